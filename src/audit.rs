@@ -5,12 +5,28 @@ use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::Path;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Operation {
     Set,
     Get,
     Delete,
     Rotate,
+    Shred,
+    /// A version removed by a retention policy, logged as `key@version`.
+    Prune,
+    /// A deleted secret restored.
+    Undelete,
+    /// A deleted secret removed for good.
+    Purge,
+    /// Several changes applied and saved together; see [`AuditEntry::changes`].
+    Transaction,
+}
+
+/// One change inside a grouped audit record.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditChange {
+    pub operation: Operation,
+    pub key: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,6 +34,9 @@ pub struct AuditEntry {
     pub timestamp: DateTime<Utc>,
     pub operation: Operation,
     pub key: String,
+    /// The individual changes of an [`Operation::Transaction`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<AuditChange>,
 }
 
 pub struct AuditLogger {
@@ -44,12 +63,32 @@ impl AuditLogger {
     /// # Errors
     /// Returns an error if writing to the log file fails.
     pub fn log(&self, operation: Operation, key: &str) -> Result<()> {
-        let entry = AuditEntry {
+        self.write(AuditEntry {
             timestamp: Utc::now(),
             operation,
             key: key.to_string(),
-        };
+            changes: Vec::new(),
+        })
+    }
+
+    /// Logs the changes of a transaction as a single record whose key lists
+    /// the affected secrets.
+    ///
+    /// # Errors
+    /// Returns an error if writing to the log file fails.
+    pub fn log_transaction(&self, changes: &[AuditChange]) -> Result<()> {
+        let mut keys: Vec<&str> = changes.iter().map(|change| change.key.as_str()).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        self.write(AuditEntry {
+            timestamp: Utc::now(),
+            operation: Operation::Transaction,
+            key: keys.join(","),
+            changes: changes.to_vec(),
+        })
+    }
 
+    fn write(&self, entry: AuditEntry) -> Result<()> {
         if let Some(path) = &self.log_path {
             let log_line = serde_json::to_string(&entry)?;
             let mut file = OpenOptions::new().create(true).append(true).open(path)?;
@@ -1,49 +1,256 @@
 use base64::{engine::general_purpose, Engine as _};
 use clap::Parser;
 use rand::{rngs::OsRng, RngCore};
-use rust_mobile_secrets_vault::cli::{Cli, Commands};
-use rust_mobile_secrets_vault::{KeySource, Result, SecretVault};
+use rust_mobile_secrets_vault::cli::{Cli, Commands, KeyCommands};
+use rust_mobile_secrets_vault::vault::format_age;
+use rust_mobile_secrets_vault::{
+    merge_vaults, KeySource, Keyring, Result, RetentionPolicy, RotationOptions, SecretVault,
+    StorageFormat, VaultData, VaultError, FORMAT_VERSION,
+};
 use std::fs;
+use std::path::Path;
+
+/// Reads a vault file in whatever format it is in. Empty files, which git
+/// passes as the base when both sides added the vault, read as `None`.
+/// Sealed and log files are opened with `keyring`; without one only their
+/// header is read.
+fn load_vault_file(path: &Path, keyring: Option<&Keyring>) -> Result<Option<VaultData>> {
+    if fs::metadata(path)?.len() == 0 {
+        return Ok(None);
+    }
+    let storage = StorageFormat::detect(path)?.open(path)?;
+    if let Some(keyring) = keyring {
+        storage.unlock(keyring)?;
+    }
+    storage.load()
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it into
+/// place, so that `path` never holds a partial file.
+fn write_new_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let temp = path.with_file_name(name);
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })?;
+    Ok(())
+}
+
+/// Reads a passphrase from `env_var` if given, otherwise prompts without echo.
+fn read_passphrase(prompt: &str, env_var: Option<&str>, confirm: bool) -> Result<String> {
+    if let Some(var) = env_var {
+        return std::env::var(var).map_err(|_| {
+            VaultError::KeyLoadError(format!("Environment variable {} not found", var))
+        });
+    }
+
+    let passphrase = rpassword::prompt_password(prompt)?;
+    if passphrase.is_empty() {
+        return Err(VaultError::KeyLoadError(
+            "Passphrase cannot be empty".to_string(),
+        ));
+    }
+    if confirm && rpassword::prompt_password("Confirm passphrase: ")? != passphrase {
+        return Err(VaultError::KeyLoadError(
+            "Passphrases do not match".to_string(),
+        ));
+    }
+    Ok(passphrase)
+}
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    let use_passphrase = cli.passphrase || cli.passphrase_env.is_some();
 
     match cli.command {
-        Commands::Init { key_out } => {
-            let mut key = [0u8; 32];
-            OsRng.fill_bytes(&mut key);
-            let key_base64 = general_purpose::STANDARD.encode(key);
-
-            if let Some(path) = key_out {
-                fs::write(&path, &key_base64)
-                    .map_err(|e| rust_mobile_secrets_vault::VaultError::Io(e))?;
-                println!("✓ Master key written to {:?}", path);
+        Commands::Init {
+            key_out,
+            cipher,
+            format,
+            kdf_profile,
+            kdf_memory_kib,
+            kdf_iterations,
+            kdf_parallelism,
+            force,
+        } => {
+            if !force {
+                if cli.vault_path.exists() {
+                    return Err(VaultError::VaultExists(
+                        cli.vault_path.display().to_string(),
+                    ));
+                }
+                if let Some(path) = key_out.as_ref().filter(|path| path.exists()) {
+                    return Err(VaultError::Io(std::io::Error::new(
+                        std::io::ErrorKind::AlreadyExists,
+                        format!("{} already exists", path.display()),
+                    )));
+                }
+            }
+            let mut builder = SecretVault::builder()
+                .vault_path(&cli.vault_path)
+                .cipher(cipher)
+                .format(format)
+                .overwrite(force)
+                .manifest(cli.manifest);
+            if let Some(path) = &cli.pin_path {
+                builder = builder.pin_path(path);
+            }
+
+            let mut key_base64 = None;
+            if use_passphrase {
+                let passphrase = read_passphrase(
+                    "New vault passphrase: ",
+                    cli.passphrase_env.as_deref(),
+                    true,
+                )?;
+                let params = kdf_profile.params(kdf_memory_kib, kdf_iterations, kdf_parallelism)?;
+                builder = builder
+                    .master_key(KeySource::Passphrase(passphrase))
+                    .kdf_params(params);
             } else {
-                println!("Master Key (SAVE THIS SECURELY!):");
-                println!("{}", key_base64);
-                println!("\nStore this key in a secure location.");
+                let mut key = [0u8; 32];
+                OsRng.fill_bytes(&mut key);
+                key_base64 = Some(general_purpose::STANDARD.encode(key));
+                builder = builder.master_key(KeySource::Bytes(key.to_vec()));
             }
 
             // Initialize empty vault
-            let key_source = KeySource::Bytes(key.to_vec());
-            let vault = SecretVault::new(key_source, &cli.vault_path, cli.audit_path.as_deref())?;
-            vault.save()?;
-            println!("✓ Initialized empty vault at {:?}", cli.vault_path);
+            if let Some(audit) = &cli.audit_path {
+                builder = builder.audit_path(audit);
+            }
+            let vault = builder.create()?;
+
+            // The key is only handed out once the vault it opens exists.
+            if let Some(key_base64) = key_base64 {
+                if let Some(path) = key_out {
+                    write_new_file(&path, key_base64.as_bytes())?;
+                    println!("✓ Master key written to {:?}", path);
+                } else {
+                    println!("Master Key (SAVE THIS SECURELY!):");
+                    println!("{}", key_base64);
+                    println!("\nStore this key in a secure location.");
+                }
+            }
+            println!(
+                "✓ Initialized empty vault at {:?} ({})",
+                cli.vault_path,
+                vault.cipher()
+            );
+        }
+        Commands::Merge { base, ours, theirs } => {
+            let missing = |path: &Path| {
+                VaultError::InvalidDataFormat(format!("{} holds no vault", path.display()))
+            };
+            let ours_header = load_vault_file(&ours, None)?.ok_or_else(|| missing(&ours))?;
+
+            // The key is optional here: most merges only move ciphertexts.
+            let key_source = if use_passphrase {
+                Some(KeySource::Passphrase(read_passphrase(
+                    "Vault passphrase: ",
+                    cli.passphrase_env.as_deref(),
+                    false,
+                )?))
+            } else if let Some(path) = cli.key_path {
+                Some(KeySource::File(path))
+            } else {
+                cli.key_env.map(KeySource::Env)
+            };
+            let keyring = match key_source {
+                Some(source) => {
+                    let mut keyring = Keyring::new(source.load_with_kdf(ours_header.kdf.as_ref())?);
+                    for path in cli.extra_key_path {
+                        keyring.insert(KeySource::File(path).load()?);
+                    }
+                    Some(keyring)
+                }
+                None => None,
+            };
+            for path in [&base, &ours, &theirs] {
+                let encrypted = matches!(
+                    StorageFormat::detect(path)?,
+                    StorageFormat::Sealed | StorageFormat::Log
+                );
+                if keyring.is_none() && encrypted {
+                    return Err(VaultError::KeyLoadError(
+                        "Merging sealed or log vaults needs the master key".to_string(),
+                    ));
+                }
+            }
+            let base_data = load_vault_file(&base, keyring.as_ref())?;
+            let ours_data =
+                load_vault_file(&ours, keyring.as_ref())?.ok_or_else(|| missing(&ours))?;
+            let theirs_data =
+                load_vault_file(&theirs, keyring.as_ref())?.ok_or_else(|| missing(&theirs))?;
+
+            let outcome = merge_vaults(
+                base_data.as_ref(),
+                &ours_data,
+                &theirs_data,
+                keyring.as_ref(),
+            )?;
+            for (name, from, to) in &outcome.renumbered {
+                println!("Renumbered their {} v{} to v{}", name, from, to);
+            }
+            if !outcome.is_clean() {
+                for conflict in &outcome.conflicts {
+                    eprintln!("✗ {}", conflict);
+                }
+                eprintln!(
+                    "{} conflict(s); {} left unchanged",
+                    outcome.conflicts.len(),
+                    ours.display()
+                );
+                std::process::exit(1);
+            }
+            let target = StorageFormat::detect(&ours)?.open(&ours)?;
+            if let Some(keyring) = &keyring {
+                target.unlock(keyring)?;
+            }
+            target.store(&outcome.data)?;
+            println!("✓ Merged into {}", ours.display());
         }
         _ => {
             // For other commands, we need to load the key
-            let key_source = if let Some(path) = cli.key_path {
+            let key_source = if use_passphrase {
+                KeySource::Passphrase(read_passphrase(
+                    "Vault passphrase: ",
+                    cli.passphrase_env.as_deref(),
+                    false,
+                )?)
+            } else if let Some(path) = cli.key_path {
                 KeySource::File(path)
             } else if let Some(env_var) = cli.key_env {
                 KeySource::Env(env_var)
             } else {
-                return Err(rust_mobile_secrets_vault::VaultError::KeyLoadError(
-                    "Master key must be provided via --key-path or --key-env".to_string(),
+                return Err(VaultError::KeyLoadError(
+                    "Master key must be provided via --key-path, --key-env or --passphrase"
+                        .to_string(),
                 ));
             };
 
-            let mut vault =
-                SecretVault::new(key_source, &cli.vault_path, cli.audit_path.as_deref())?;
+            let mut builder = SecretVault::builder()
+                .master_key(key_source)
+                .vault_path(&cli.vault_path)
+                .backup(cli.backup)
+                .max_retries(cli.retries)
+                .create_if_missing(false)
+                // The vault to sign has no manifest to require yet.
+                .manifest(cli.manifest && !matches!(cli.command, Commands::SignManifest));
+            if cli.read_only {
+                builder = builder.read_only();
+            }
+            if let Some(path) = &cli.pin_path {
+                builder = builder.pin_path(path);
+            }
+            for path in cli.extra_key_path {
+                builder = builder.additional_key(KeySource::File(path));
+            }
+            if let Some(audit) = &cli.audit_path {
+                builder = builder.audit_path(audit);
+            }
+            let mut vault = builder.build()?;
 
             match cli.command {
                 Commands::Set { key, value } => {
@@ -61,38 +268,124 @@ fn main() -> Result<()> {
                 }
                 Commands::Delete { key } => {
                     vault.delete(&key)?;
-                    println!("✓ Secret '{}' deleted", key);
+                    println!(
+                        "✓ Secret '{}' deleted; restore it with `vault undelete {}`",
+                        key, key
+                    );
+                }
+                Commands::Undelete { key } => {
+                    if vault.undelete(&key)? {
+                        println!("✓ Secret '{}' restored", key);
+                    } else {
+                        eprintln!("No deleted secret '{}'", key);
+                        std::process::exit(1);
+                    }
+                }
+                Commands::Purge { expired: true, .. } => {
+                    let purged = vault.purge_expired()?;
+                    for key in &purged {
+                        println!("Purged '{}'", key);
+                    }
+                    println!("✓ Purged {} deleted secret(s)", purged.len());
+                }
+                Commands::Purge { key, .. } => {
+                    let key = key.expect("clap requires a key without --expired");
+                    if vault.purge(&key)? {
+                        println!("✓ Secret '{}' purged", key);
+                    } else {
+                        eprintln!("No deleted secret '{}'", key);
+                        std::process::exit(1);
+                    }
+                }
+                Commands::Deleted => {
+                    let deleted = vault.deleted_keys();
+                    if deleted.is_empty() {
+                        println!("No deleted secrets");
+                    }
+                    for (key, deleted_at) in deleted {
+                        println!("{} (deleted {})", key, deleted_at.to_rfc3339());
+                    }
+                }
+                Commands::GracePeriod { period: None } => {
+                    let period = vault.delete_grace_period();
+                    println!("Deleted secrets are kept for {}", format_age(period));
+                }
+                Commands::GracePeriod {
+                    period: Some(period),
+                } => {
+                    vault.set_delete_grace_period(period)?;
+                    println!("✓ Deleted secrets are now kept for {}", format_age(period));
+                }
+                Commands::Rotate { abort: true, .. } => {
+                    if vault.abort_rotation()? {
+                        println!("✓ Staged rotation discarded");
+                    } else {
+                        println!("No rotation in progress");
+                    }
                 }
                 Commands::Rotate {
                     new_key_path,
                     new_key_out,
+                    new_passphrase,
+                    new_passphrase_env,
+                    dry_run,
+                    checkpoint_every,
+                    ..
                 } => {
-                    let (new_key, new_key_source) = if let Some(path) = new_key_path {
-                        let content = fs::read_to_string(&path)
-                            .map_err(|e| rust_mobile_secrets_vault::VaultError::Io(e))?;
+                    let new_key_source = if new_passphrase || new_passphrase_env.is_some() {
+                        let passphrase = read_passphrase(
+                            "New vault passphrase: ",
+                            new_passphrase_env.as_deref(),
+                            !dry_run,
+                        )?;
+                        KeySource::Passphrase(passphrase)
+                    } else if let Some(path) = new_key_path {
+                        let content = fs::read_to_string(&path).map_err(VaultError::Io)?;
                         let decoded = general_purpose::STANDARD.decode(content.trim())?;
-                        (None, KeySource::Bytes(decoded))
+                        KeySource::Bytes(decoded)
                     } else {
                         let mut key = [0u8; 32];
                         OsRng.fill_bytes(&mut key);
-                        (Some(key), KeySource::Bytes(key.to_vec()))
-                    };
 
-                    vault.rotate(new_key_source)?;
-
-                    if let Some(key_bytes) = new_key {
-                        let new_key_base64 = general_purpose::STANDARD.encode(key_bytes);
-                        if let Some(path) = new_key_out {
-                            fs::write(&path, &new_key_base64)
-                                .map_err(|e| rust_mobile_secrets_vault::VaultError::Io(e))?;
-                            println!("✓ New master key written to {:?}", path);
-                        } else {
-                            println!("New Master Key (SAVE THIS SECURELY!):");
-                            println!("{}", new_key_base64);
+                        // Persist the key before touching the vault, so an
+                        // interrupted rotation can be resumed with it.
+                        if !dry_run {
+                            let new_key_base64 = general_purpose::STANDARD.encode(key);
+                            if let Some(path) = new_key_out {
+                                fs::write(&path, &new_key_base64).map_err(VaultError::Io)?;
+                                println!("✓ New master key written to {:?}", path);
+                            } else {
+                                println!("New Master Key (SAVE THIS SECURELY!):");
+                                println!("{}", new_key_base64);
+                            }
                         }
-                    }
+                        KeySource::Bytes(key.to_vec())
+                    };
 
-                    println!("✓ Vault rotated successfully");
+                    let report = vault.rotate_with(
+                        new_key_source,
+                        RotationOptions {
+                            dry_run,
+                            checkpoint_every,
+                            limit: None,
+                        },
+                    )?;
+
+                    if report.resumed > 0 {
+                        println!("Resumed staged rotation ({} secrets done)", report.resumed);
+                    }
+                    println!(
+                        "{} {} data keys, {} direct entries converted, {} shredded entries skipped",
+                        if dry_run { "Would rewrap" } else { "Rewrapped" },
+                        report.rewrapped,
+                        report.converted,
+                        report.skipped
+                    );
+                    if dry_run {
+                        println!("✓ Dry run passed; vault unchanged");
+                    } else {
+                        println!("✓ Vault rotated successfully");
+                    }
                 }
                 Commands::ListVersions { key } => {
                     let versions = vault.list_versions(&key)?;
@@ -102,6 +395,125 @@ fn main() -> Result<()> {
                         println!("Versions for '{}': {:?}", key, versions);
                     }
                 }
+                Commands::Shred { key, version } => {
+                    if vault.shred_version(&key, version)? {
+                        println!("✓ Secret '{}' version {} shredded", key, version);
+                    } else {
+                        eprintln!("Secret '{}' version {} not found", key, version);
+                        std::process::exit(1);
+                    }
+                }
+                Commands::BindLegacy => {
+                    let migrated = vault.bind_legacy_entries()?;
+                    println!("✓ Bound {} legacy entries", migrated);
+                }
+                Commands::Upgrade => match vault.upgrade()? {
+                    Some(from) => println!(
+                        "✓ Upgraded vault from format version {} to {}",
+                        from, FORMAT_VERSION
+                    ),
+                    None => println!("Vault is already at format version {}", FORMAT_VERSION),
+                },
+                Commands::SignManifest => {
+                    vault.sign_manifest()?;
+                    println!("✓ Signed a manifest of the vault");
+                }
+                Commands::Compact => {
+                    if vault.compact()? {
+                        println!("✓ Compacted vault");
+                    } else {
+                        println!("Nothing to compact");
+                    }
+                }
+                Commands::Fsck { repair } => {
+                    let report = if repair {
+                        vault.repair()?
+                    } else {
+                        vault.verify()?
+                    };
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                    if !report.is_healthy() {
+                        std::process::exit(1);
+                    }
+                }
+                Commands::Retention {
+                    key,
+                    keep_last,
+                    max_age,
+                    clear,
+                } => {
+                    let policy =
+                        (keep_last.is_some() || max_age.is_some()).then(|| RetentionPolicy {
+                            keep_last,
+                            max_age_secs: max_age.map(|age| age.as_secs()),
+                        });
+                    let scope = match &key {
+                        Some(key) => format!("'{}'", key),
+                        None => "the vault".to_string(),
+                    };
+                    if policy.is_none() && !clear {
+                        let current = match &key {
+                            Some(key) => vault.key_retention(key).or(vault.retention()),
+                            None => vault.retention(),
+                        };
+                        match current {
+                            Some(policy) => println!("Retention for {}: {}", scope, policy),
+                            None => println!("No retention policy for {}", scope),
+                        }
+                    } else {
+                        match &key {
+                            Some(key) => vault.set_key_retention(key, policy)?,
+                            None => vault.set_retention(policy)?,
+                        }
+                        match policy {
+                            Some(policy) => println!("✓ Retention for {}: {}", scope, policy),
+                            None => println!("✓ Retention policy for {} removed", scope),
+                        }
+                    }
+                }
+                Commands::Prune => {
+                    let pruned = vault.prune()?;
+                    for (key, versions) in &pruned {
+                        println!("Pruned '{}' versions {:?}", key, versions);
+                    }
+                    let count: usize = pruned.values().map(Vec::len).sum();
+                    println!("✓ Pruned {} version(s)", count);
+                }
+                Commands::Migrate { to, out } => {
+                    let out = out.unwrap_or_else(|| cli.vault_path.with_extension(to.extension()));
+                    if out.exists() {
+                        return Err(VaultError::Io(std::io::Error::new(
+                            std::io::ErrorKind::AlreadyExists,
+                            format!("{} already exists", out.display()),
+                        )));
+                    }
+                    vault.export_to(to.open(&out)?.as_ref())?;
+                    println!("✓ Vault copied to {} ({})", out.display(), to);
+                    println!(
+                        "Use --vault-path {} from now on; the original is unchanged",
+                        out.display()
+                    );
+                }
+                Commands::Key { action } => match action {
+                    KeyCommands::Usage => {
+                        for (key_id, entries) in vault.key_usage()? {
+                            let marker = if key_id == vault.active_key_id() {
+                                " (active)"
+                            } else {
+                                ""
+                            };
+                            println!("{}{}: {} entries", key_id, marker, entries);
+                        }
+                    }
+                    KeyCommands::Rewrap { limit } => {
+                        let rewrapped = vault.rewrap_to_active(limit)?;
+                        println!(
+                            "✓ Rewrapped {} entries under key {}",
+                            rewrapped,
+                            vault.active_key_id()
+                        );
+                    }
+                },
                 _ => unreachable!(),
             }
         }
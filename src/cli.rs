@@ -1,5 +1,11 @@
-use clap::{Parser, Subcommand};
+use crate::encryption::CipherKind;
+use crate::error::Result;
+use crate::kdf::KdfParams;
+use crate::storage::StorageFormat;
+use crate::vault::parse_age;
+use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "vault")]
@@ -9,20 +15,81 @@ pub struct Cli {
     pub command: Commands,
 
     /// Path to the vault file
-    #[arg(short, long, default_value = "vault.yaml")]
+    #[arg(short, long, global = true, default_value = "vault.yaml")]
     pub vault_path: PathBuf,
 
     /// Path to the master key file
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub key_path: Option<PathBuf>,
 
     /// Environment variable containing the master key
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub key_env: Option<String>,
 
+    /// Additional master key files for entries not yet rewrapped (repeatable)
+    #[arg(long, global = true)]
+    pub extra_key_path: Vec<PathBuf>,
+
+    /// Prompt for the vault passphrase instead of reading a key
+    #[arg(long, global = true)]
+    pub passphrase: bool,
+
+    /// Environment variable containing the vault passphrase
+    #[arg(long, global = true)]
+    pub passphrase_env: Option<String>,
+
     /// Path to the audit log file
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub audit_path: Option<PathBuf>,
+
+    /// Keep the previous vault file as `<vault>.bak` on every save
+    #[arg(long, global = true)]
+    pub backup: bool,
+
+    /// How many times to reapply a change when another process saved first
+    #[arg(long, global = true, default_value_t = 3)]
+    pub retries: u32,
+
+    /// Open the vault read-only; commands that change it fail
+    #[arg(long, global = true)]
+    pub read_only: bool,
+
+    /// Sign a manifest of all secret names, versions and settings on every save, and refuse a vault without one
+    #[arg(long, global = true)]
+    pub manifest: bool,
+
+    /// Local state file pinning the last vault generation seen, to detect rollbacks
+    #[arg(long, global = true)]
+    pub pin_path: Option<PathBuf>,
+}
+
+/// Argon2id cost presets for passphrase-protected vaults.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum KdfProfile {
+    /// 19 MiB, 2 passes, 1 lane
+    Mobile,
+    /// 256 MiB, 3 passes, 4 lanes
+    Server,
+}
+
+impl KdfProfile {
+    /// Builds KDF parameters from the preset, applying any overrides.
+    pub fn params(
+        self,
+        memory_kib: Option<u32>,
+        iterations: Option<u32>,
+        parallelism: Option<u32>,
+    ) -> Result<KdfParams> {
+        let preset = match self {
+            KdfProfile::Mobile => KdfParams::mobile(),
+            KdfProfile::Server => KdfParams::server(),
+        };
+        KdfParams::new(
+            memory_kib.unwrap_or(preset.memory_kib),
+            iterations.unwrap_or(preset.iterations),
+            parallelism.unwrap_or(preset.parallelism),
+        )
+    }
 }
 
 #[derive(Subcommand)]
@@ -32,13 +99,53 @@ pub enum Commands {
         /// Output path for the generated master key
         #[arg(long)]
         key_out: Option<PathBuf>,
+        /// Cipher used to encrypt secrets in the new vault
+        #[arg(long, default_value_t = CipherKind::Aes256Gcm)]
+        cipher: CipherKind,
+        /// On-disk format of the new vault file
+        #[arg(long, default_value_t = StorageFormat::Yaml)]
+        format: StorageFormat,
+        /// Argon2id cost preset when the vault is protected by a passphrase
+        #[arg(long, value_enum, default_value_t = KdfProfile::Mobile)]
+        kdf_profile: KdfProfile,
+        /// Override the Argon2id memory cost in KiB
+        #[arg(long)]
+        kdf_memory_kib: Option<u32>,
+        /// Override the number of Argon2id passes
+        #[arg(long)]
+        kdf_iterations: Option<u32>,
+        /// Override the Argon2id degree of parallelism
+        #[arg(long)]
+        kdf_parallelism: Option<u32>,
+        /// Replace an existing vault and key file, losing the old secrets
+        #[arg(long)]
+        force: bool,
     },
     /// Set a secret
     Set { key: String, value: String },
     /// Get a secret
     Get { key: String },
-    /// Delete a secret
+    /// Delete a secret; it can be restored with `undelete` until purged
     Delete { key: String },
+    /// Restore a deleted secret with all of its versions
+    Undelete { key: String },
+    /// Remove a deleted secret for good
+    Purge {
+        /// The deleted secret to remove
+        #[arg(required_unless_present = "expired")]
+        key: Option<String>,
+        /// Remove every secret deleted longer ago than the grace period
+        #[arg(long, conflicts_with = "key")]
+        expired: bool,
+    },
+    /// List deleted secrets that can still be restored
+    Deleted,
+    /// Show or set how long deleted secrets are kept before saves purge them
+    GracePeriod {
+        /// The new grace period, e.g. 30d, 12h or 2w
+        #[arg(value_parser = parse_age)]
+        period: Option<Duration>,
+    },
     /// Rotate the master key
     Rotate {
         /// Path to the new master key file (optional, otherwise generates new)
@@ -47,7 +154,101 @@ pub enum Commands {
         /// Output path for the new master key if generated
         #[arg(long)]
         new_key_out: Option<PathBuf>,
+        /// Prompt for a new passphrase instead of using a key
+        #[arg(long)]
+        new_passphrase: bool,
+        /// Environment variable containing the new passphrase
+        #[arg(long)]
+        new_passphrase_env: Option<String>,
+        /// Check that every entry can be rewrapped, without writing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Number of secrets rewrapped between checkpoints
+        #[arg(long, default_value_t = 100)]
+        checkpoint_every: usize,
+        /// Discard an interrupted rotation instead of rotating
+        #[arg(long, conflicts_with_all = ["new_key_path", "new_passphrase", "new_passphrase_env", "dry_run"])]
+        abort: bool,
     },
     /// List versions of a secret
     ListVersions { key: String },
+    /// Destroy the data key of one version of a secret
+    Shred { key: String, version: u32 },
+    /// Bind entries written by older releases to their vault, name and version
+    BindLegacy,
+    /// Three-way merge of vault files, for use as a git merge driver
+    ///
+    /// The result is written over OURS. Exits with status 1, leaving OURS
+    /// unchanged, when conflicts remain. No key is needed unless versions added
+    /// on both sides must be renumbered or the sides use different keys.
+    Merge {
+        /// Common ancestor (git's %O); may be empty
+        base: PathBuf,
+        /// Our version (git's %A), replaced by the merge result
+        ours: PathBuf,
+        /// Their version (git's %B)
+        theirs: PathBuf,
+    },
+    /// Rewrite the vault file in the current format version
+    Upgrade,
+    /// Sign a manifest for a vault that has none, e.g. after a merge without the key
+    SignManifest,
+    /// Fold a log-structured vault's records into a single snapshot
+    Compact,
+    /// Decrypt and check every entry, printing a JSON report
+    Fsck {
+        /// Move undecryptable entries to `<vault>.quarantine` and save the rest
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Show or set how many old versions of secrets are kept
+    ///
+    /// Without a limit, prints the policy. Limits are enforced on every set
+    /// and by `vault prune`; the latest version is always kept.
+    Retention {
+        /// Set the policy of this secret instead of the whole vault
+        #[arg(long)]
+        key: Option<String>,
+        /// Keep only the newest N versions
+        #[arg(long)]
+        keep_last: Option<u32>,
+        /// Prune versions older than this age, e.g. 90d, 12h or 2w
+        #[arg(long, value_parser = parse_age)]
+        max_age: Option<Duration>,
+        /// Remove the policy
+        #[arg(long, conflicts_with_all = ["keep_last", "max_age"])]
+        clear: bool,
+    },
+    /// Remove the versions the retention policies do not keep
+    Prune,
+    /// Copy the vault into another storage format (yaml, binary, log, sqlite or sealed)
+    Migrate {
+        /// Storage format of the copy
+        #[arg(long)]
+        to: StorageFormat,
+        /// Path of the copy (default: the vault path with the format's extension)
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Inspect or migrate the master keyring
+    ///
+    /// The keyring is not stored in the vault: it is the `--key-path` key,
+    /// which is active for new writes, plus every `--extra-key-path` key given
+    /// on each run.
+    Key {
+        #[command(subcommand)]
+        action: KeyCommands,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum KeyCommands {
+    /// Show how many entries each master key protects
+    Usage,
+    /// Rewrap entries under the active key
+    Rewrap {
+        /// Maximum number of entries to rewrap in this run
+        #[arg(long)]
+        limit: Option<usize>,
+    },
 }
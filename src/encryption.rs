@@ -1,106 +1,535 @@
 use crate::error::{Result, VaultError};
 use aes_gcm::{
-    aead::{Aead, KeyInit},
-    Aes256Gcm, Key, Nonce,
+    aead::{generic_array::GenericArray, Aead, AeadCore, KeyInit, Payload},
+    Aes256Gcm,
 };
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
 use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
 
 pub const KEY_SIZE: usize = 32;
 pub const NONCE_SIZE: usize = 12;
+pub const XNONCE_SIZE: usize = 24;
 
-/// Encrypts data using AES-256-GCM.
-///
-/// # Arguments
-/// * `key` - A 32-byte encryption key
-/// * `plaintext` - The data to encrypt
-///
-/// # Returns
-/// A vector containing the nonce (12 bytes) followed by the ciphertext.
+/// An authenticated cipher used to protect secret values.
 ///
-/// # Errors
-/// Returns `VaultError::InvalidKeySize` if the key is not 32 bytes.
-/// Returns `VaultError::EncryptionFailed` if encryption fails.
-pub fn encrypt(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+/// Implementations produce blobs laid out as `nonce || ciphertext`, where the
+/// nonce length is given by [`Cipher::nonce_size`]. The associated data `aad`
+/// is authenticated but not stored; decryption fails unless the exact same
+/// bytes are supplied again.
+pub trait Cipher: Send + Sync {
+    /// The identifier recorded in the vault file for this cipher.
+    fn kind(&self) -> CipherKind;
+
+    /// Length of the random nonce prefixed to every ciphertext.
+    fn nonce_size(&self) -> usize;
+
+    /// Encrypts `plaintext` under a 32-byte `key`, binding it to `aad`.
+    fn encrypt(&self, key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
+
+    /// Decrypts a blob previously produced by [`Cipher::encrypt`] with the same `aad`.
+    fn decrypt(&self, key: &[u8], encrypted_data: &[u8], aad: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// The AEAD algorithms a vault can be configured with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CipherKind {
+    /// AES-256-GCM with a 96-bit random nonce. Fastest on hardware with AES-NI.
+    #[default]
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    /// ChaCha20-Poly1305 with a 96-bit random nonce. Constant-time in software.
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+    /// XChaCha20-Poly1305 with a 192-bit random nonce, safe for very high volumes.
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl CipherKind {
+    /// Returns the cipher implementation for this algorithm.
+    pub fn cipher(self) -> &'static dyn Cipher {
+        match self {
+            CipherKind::Aes256Gcm => &Aes256GcmCipher,
+            CipherKind::ChaCha20Poly1305 => &ChaCha20Poly1305Cipher,
+            CipherKind::XChaCha20Poly1305 => &XChaCha20Poly1305Cipher,
+        }
+    }
+
+    /// The one-byte identifier written into ciphertext envelopes.
+    pub fn id(self) -> u8 {
+        match self {
+            CipherKind::Aes256Gcm => 1,
+            CipherKind::ChaCha20Poly1305 => 2,
+            CipherKind::XChaCha20Poly1305 => 3,
+        }
+    }
+
+    /// Looks up a cipher by its envelope identifier.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(CipherKind::Aes256Gcm),
+            2 => Some(CipherKind::ChaCha20Poly1305),
+            3 => Some(CipherKind::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    /// The canonical name of the algorithm, as written to the vault file.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CipherKind::Aes256Gcm => "aes-256-gcm",
+            CipherKind::ChaCha20Poly1305 => "chacha20-poly1305",
+            CipherKind::XChaCha20Poly1305 => "xchacha20-poly1305",
+        }
+    }
+}
+
+impl fmt::Display for CipherKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for CipherKind {
+    type Err = VaultError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "aes-256-gcm" => Ok(CipherKind::Aes256Gcm),
+            "chacha20-poly1305" => Ok(CipherKind::ChaCha20Poly1305),
+            "xchacha20-poly1305" => Ok(CipherKind::XChaCha20Poly1305),
+            other => Err(VaultError::UnsupportedCipher(other.to_string())),
+        }
+    }
+}
+
+/// AES-256-GCM backend.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Aes256GcmCipher;
+
+/// ChaCha20-Poly1305 backend.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChaCha20Poly1305Cipher;
+
+/// XChaCha20-Poly1305 backend.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XChaCha20Poly1305Cipher;
+
+impl Cipher for Aes256GcmCipher {
+    fn kind(&self) -> CipherKind {
+        CipherKind::Aes256Gcm
+    }
+
+    fn nonce_size(&self) -> usize {
+        NONCE_SIZE
+    }
+
+    fn encrypt(&self, key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        aead_seal::<Aes256Gcm>(key, plaintext, aad)
+    }
+
+    fn decrypt(&self, key: &[u8], encrypted_data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        aead_open::<Aes256Gcm>(key, encrypted_data, aad)
+    }
+}
+
+impl Cipher for ChaCha20Poly1305Cipher {
+    fn kind(&self) -> CipherKind {
+        CipherKind::ChaCha20Poly1305
+    }
+
+    fn nonce_size(&self) -> usize {
+        NONCE_SIZE
+    }
+
+    fn encrypt(&self, key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        aead_seal::<ChaCha20Poly1305>(key, plaintext, aad)
+    }
+
+    fn decrypt(&self, key: &[u8], encrypted_data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        aead_open::<ChaCha20Poly1305>(key, encrypted_data, aad)
+    }
+}
+
+impl Cipher for XChaCha20Poly1305Cipher {
+    fn kind(&self) -> CipherKind {
+        CipherKind::XChaCha20Poly1305
+    }
+
+    fn nonce_size(&self) -> usize {
+        XNONCE_SIZE
+    }
+
+    fn encrypt(&self, key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        aead_seal::<XChaCha20Poly1305>(key, plaintext, aad)
+    }
+
+    fn decrypt(&self, key: &[u8], encrypted_data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        aead_open::<XChaCha20Poly1305>(key, encrypted_data, aad)
+    }
+}
+
+fn check_key_size(key: &[u8]) -> Result<()> {
     if key.len() != KEY_SIZE {
         return Err(VaultError::InvalidKeySize {
             expected: KEY_SIZE,
             found: key.len(),
         });
     }
+    Ok(())
+}
+
+fn aead_seal<A: Aead + AeadCore + KeyInit>(
+    key: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    check_key_size(key)?;
 
-    let key = Key::<Aes256Gcm>::from_slice(key);
-    let cipher = Aes256Gcm::new(key);
+    let cipher = A::new(GenericArray::from_slice(key));
 
-    let mut nonce_bytes = [0u8; NONCE_SIZE];
-    OsRng.fill_bytes(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
+    let mut nonce = GenericArray::<u8, A::NonceSize>::default();
+    OsRng.fill_bytes(&mut nonce);
 
     let ciphertext = cipher
-        .encrypt(nonce, plaintext)
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
         .map_err(|e| VaultError::EncryptionFailed(e.to_string()))?;
 
-    let mut result = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
-    result.extend_from_slice(&nonce_bytes);
+    let mut result = Vec::with_capacity(nonce.len() + ciphertext.len());
+    result.extend_from_slice(&nonce);
     result.extend_from_slice(&ciphertext);
 
     Ok(result)
 }
 
-/// Decrypts data using AES-256-GCM.
-///
-/// # Arguments
-/// * `key` - A 32-byte encryption key
-/// * `encrypted_data` - Data containing the nonce (12 bytes) followed by the ciphertext
-///
-/// # Returns
-/// The decrypted plaintext.
-///
-/// # Errors
-/// Returns `VaultError::InvalidKeySize` if the key is not 32 bytes.
-/// Returns `VaultError::InvalidDataFormat` if the data is too short.
-/// Returns `VaultError::DecryptionFailed` if decryption fails.
-pub fn decrypt(key: &[u8], encrypted_data: &[u8]) -> Result<Vec<u8>> {
-    if key.len() != KEY_SIZE {
-        return Err(VaultError::InvalidKeySize {
-            expected: KEY_SIZE,
-            found: key.len(),
-        });
-    }
+fn aead_open<A: Aead + AeadCore + KeyInit>(
+    key: &[u8],
+    encrypted_data: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    check_key_size(key)?;
 
-    if encrypted_data.len() < NONCE_SIZE {
+    let nonce_size = GenericArray::<u8, A::NonceSize>::default().len();
+    if encrypted_data.len() < nonce_size {
         return Err(VaultError::InvalidDataFormat(format!(
             "Data too short: {} bytes (minimum {} bytes required)",
             encrypted_data.len(),
-            NONCE_SIZE
+            nonce_size
         )));
     }
 
-    let key = Key::<Aes256Gcm>::from_slice(key);
-    let cipher = Aes256Gcm::new(key);
-
-    let nonce = Nonce::from_slice(&encrypted_data[..NONCE_SIZE]);
-    let ciphertext = &encrypted_data[NONCE_SIZE..];
+    let cipher = A::new(GenericArray::from_slice(key));
 
+    let (nonce, ciphertext) = encrypted_data.split_at(nonce_size);
     let plaintext = cipher
-        .decrypt(nonce, ciphertext)
+        .decrypt(
+            GenericArray::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
         .map_err(|e| VaultError::DecryptionFailed(e.to_string()))?;
 
     Ok(plaintext)
 }
 
+/// Magic bytes that open every self-describing ciphertext.
+pub const ENVELOPE_MAGIC: &[u8; 4] = b"RMSV";
+/// The envelope layout version written by this release.
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// The plaintext header prefixed to every ciphertext.
+///
+/// On disk the header is laid out as:
+///
+/// ```text
+/// magic (4) | format version (1) | cipher ID (1) | key ID length (1) | key ID | nonce length (1)
+/// ```
+///
+/// followed by the cipher output (`nonce || ciphertext`). The encoded header is
+/// authenticated as part of the associated data, so it cannot be altered
+/// without decryption failing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvelopeHeader {
+    pub version: u8,
+    pub cipher: CipherKind,
+    /// Identifier of the key the payload was sealed with. Empty for per-entry
+    /// data keys, which are identified by the entry that holds them.
+    pub key_id: String,
+    pub nonce_len: u8,
+}
+
+impl EnvelopeHeader {
+    fn new(cipher: CipherKind, key_id: &str) -> Result<Self> {
+        if key_id.len() > u8::MAX as usize {
+            return Err(VaultError::InvalidDataFormat(format!(
+                "Key ID too long: {} bytes (maximum {})",
+                key_id.len(),
+                u8::MAX
+            )));
+        }
+        Ok(Self {
+            version: ENVELOPE_VERSION,
+            cipher,
+            key_id: key_id.to_string(),
+            nonce_len: cipher.cipher().nonce_size() as u8,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + self.key_id.len());
+        out.extend_from_slice(ENVELOPE_MAGIC);
+        out.push(self.version);
+        out.push(self.cipher.id());
+        out.push(self.key_id.len() as u8);
+        out.extend_from_slice(self.key_id.as_bytes());
+        out.push(self.nonce_len);
+        out
+    }
+
+    /// Parses the header at the start of `data`.
+    ///
+    /// # Returns
+    /// `None` if `data` does not start with [`ENVELOPE_MAGIC`] (a legacy
+    /// headerless blob), otherwise the header and its encoded length.
+    ///
+    /// # Errors
+    /// Returns `VaultError::InvalidDataFormat` describing the first malformed field.
+    pub fn parse(data: &[u8]) -> Result<Option<(Self, usize)>> {
+        if !data.starts_with(ENVELOPE_MAGIC) {
+            return Ok(None);
+        }
+
+        let mut pos = ENVELOPE_MAGIC.len();
+        let mut next = |field: &str| -> Result<u8> {
+            let byte = *data.get(pos).ok_or_else(|| {
+                VaultError::InvalidDataFormat(format!(
+                    "Truncated envelope header: missing {} at offset {}",
+                    field, pos
+                ))
+            })?;
+            pos += 1;
+            Ok(byte)
+        };
+
+        let version = next("format version")?;
+        if version != ENVELOPE_VERSION {
+            return Err(VaultError::InvalidDataFormat(format!(
+                "Unsupported envelope format version {} (this release reads version {})",
+                version, ENVELOPE_VERSION
+            )));
+        }
+
+        let cipher_id = next("cipher ID")?;
+        let cipher = CipherKind::from_id(cipher_id).ok_or_else(|| {
+            VaultError::InvalidDataFormat(format!("Unknown cipher ID {} in envelope", cipher_id))
+        })?;
+
+        let key_id_len = next("key ID length")? as usize;
+        let key_id_end = pos + key_id_len;
+        let key_id_bytes = data.get(pos..key_id_end).ok_or_else(|| {
+            VaultError::InvalidDataFormat(format!(
+                "Truncated envelope header: key ID declares {} bytes but only {} remain",
+                key_id_len,
+                data.len() - pos
+            ))
+        })?;
+        let key_id = std::str::from_utf8(key_id_bytes)
+            .map_err(|_| VaultError::InvalidDataFormat("Key ID is not valid UTF-8".to_string()))?
+            .to_string();
+        pos = key_id_end;
+
+        let nonce_len = *data.get(pos).ok_or_else(|| {
+            VaultError::InvalidDataFormat(format!(
+                "Truncated envelope header: missing nonce length at offset {}",
+                pos
+            ))
+        })?;
+        pos += 1;
+        let expected = cipher.cipher().nonce_size();
+        if nonce_len as usize != expected {
+            return Err(VaultError::InvalidDataFormat(format!(
+                "Nonce length {} does not match {} (expected {})",
+                nonce_len, cipher, expected
+            )));
+        }
+
+        Ok(Some((
+            Self {
+                version,
+                cipher,
+                key_id,
+                nonce_len,
+            },
+            pos,
+        )))
+    }
+}
+
+/// Derives the public identifier of a key: the first 8 bytes of a
+/// domain-separated SHA-256 digest, hex encoded.
+pub fn key_id(key: &[u8]) -> String {
+    let digest = Sha256::new()
+        .chain_update(b"rmsv-key-id-v1")
+        .chain_update(key)
+        .finalize();
+    digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Encrypts data with the given cipher and wraps it in a self-describing envelope.
+///
+/// # Arguments
+/// * `cipher` - The AEAD algorithm to use
+/// * `key_id` - Identifier recorded in the header (may be empty)
+/// * `key` - A 32-byte encryption key
+/// * `plaintext` - The data to encrypt
+/// * `aad` - Associated data to authenticate alongside the ciphertext (may be empty)
+///
+/// # Errors
+/// Returns `VaultError::InvalidKeySize` if the key is not 32 bytes.
+/// Returns `VaultError::EncryptionFailed` if encryption fails.
+pub fn encrypt_with(
+    cipher: CipherKind,
+    key_id: &str,
+    key: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let mut header = EnvelopeHeader::new(cipher, key_id)?.encode();
+    let mut full_aad = header.clone();
+    full_aad.extend_from_slice(aad);
+
+    let sealed = cipher.cipher().encrypt(key, plaintext, &full_aad)?;
+    header.extend_from_slice(&sealed);
+    Ok(header)
+}
+
+/// Decrypts an envelope produced by [`encrypt_with`].
+///
+/// Headerless blobs written before envelopes existed are decrypted with
+/// `legacy_cipher`. Their random nonce can look like a header, so a blob
+/// that fails to open as an envelope is also tried that way.
+///
+/// # Errors
+/// Returns `VaultError::InvalidDataFormat` if the header is malformed.
+/// Returns `VaultError::KeyIdMismatch` if the header names a different key.
+/// Returns `VaultError::DecryptionFailed` if decryption fails.
+pub fn decrypt_with(
+    legacy_cipher: CipherKind,
+    key: &[u8],
+    encrypted_data: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let legacy = || legacy_cipher.cipher().decrypt(key, encrypted_data, aad);
+    let (header, header_len) = match EnvelopeHeader::parse(encrypted_data) {
+        Ok(Some(parsed)) => parsed,
+        Ok(None) => return legacy(),
+        // A legacy nonce can start with the magic bytes by chance.
+        Err(err) => return legacy().map_err(|_| err),
+    };
+
+    // It can even start with a whole valid header, so a blob that fails to
+    // open as an envelope is tried as a legacy blob before giving up.
+    decrypt_envelope(header, header_len, key, encrypted_data, aad).or_else(|err| match err {
+        VaultError::DecryptionFailed(_) | VaultError::KeyIdMismatch { .. } => {
+            legacy().map_err(|_| err)
+        }
+        other => Err(other),
+    })
+}
+
+fn decrypt_envelope(
+    header: EnvelopeHeader,
+    header_len: usize,
+    key: &[u8],
+    encrypted_data: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    if !header.key_id.is_empty() {
+        let found = key_id(key);
+        if found != header.key_id {
+            return Err(VaultError::KeyIdMismatch {
+                expected: header.key_id,
+                found,
+            });
+        }
+    }
+
+    let mut full_aad = encrypted_data[..header_len].to_vec();
+    full_aad.extend_from_slice(aad);
+    header
+        .cipher
+        .cipher()
+        .decrypt(key, &encrypted_data[header_len..], &full_aad)
+}
+
+/// Encrypts data using AES-256-GCM.
+///
+/// # Arguments
+/// * `key` - A 32-byte encryption key
+/// * `plaintext` - The data to encrypt
+/// * `aad` - Associated data to authenticate alongside the ciphertext (may be empty)
+///
+/// # Returns
+/// An envelope header naming AES-256-GCM with no key ID, followed by the
+/// nonce (12 bytes) and the ciphertext. Use [`encrypt_with`] to record a key ID.
+///
+/// # Errors
+/// Returns `VaultError::InvalidKeySize` if the key is not 32 bytes.
+/// Returns `VaultError::EncryptionFailed` if encryption fails.
+pub fn encrypt(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    encrypt_with(CipherKind::Aes256Gcm, "", key, plaintext, aad)
+}
+
+/// Decrypts data produced by [`encrypt`] or [`encrypt_with`].
+///
+/// # Arguments
+/// * `key` - A 32-byte encryption key
+/// * `encrypted_data` - An envelope, or a legacy AES-256-GCM `nonce || ciphertext` blob
+/// * `aad` - The associated data supplied at encryption time
+///
+/// # Returns
+/// The decrypted plaintext.
+///
+/// # Errors
+/// Returns `VaultError::InvalidKeySize` if the key is not 32 bytes.
+/// Returns `VaultError::InvalidDataFormat` if the data is too short or the header is malformed.
+/// Returns `VaultError::DecryptionFailed` if decryption fails, including when
+/// `aad` does not match.
+pub fn decrypt(key: &[u8], encrypted_data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    decrypt_with(CipherKind::Aes256Gcm, key, encrypted_data, aad)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const ALL_CIPHERS: [CipherKind; 3] = [
+        CipherKind::Aes256Gcm,
+        CipherKind::ChaCha20Poly1305,
+        CipherKind::XChaCha20Poly1305,
+    ];
+
     #[test]
     fn test_encrypt_decrypt() {
         let key = [42u8; 32];
         let plaintext = b"Hello, world!";
 
-        let encrypted = encrypt(&key, plaintext).unwrap();
+        let encrypted = encrypt(&key, plaintext, b"").unwrap();
         assert_ne!(&encrypted[NONCE_SIZE..], plaintext);
 
-        let decrypted = decrypt(&key, &encrypted).unwrap();
+        let decrypted = decrypt(&key, &encrypted, b"").unwrap();
         assert_eq!(decrypted, plaintext);
     }
 
@@ -108,7 +537,7 @@ mod tests {
     fn test_invalid_key_size() {
         let key = [42u8; 31];
         let plaintext = b"Hello";
-        let result = encrypt(&key, plaintext);
+        let result = encrypt(&key, plaintext, b"");
         assert!(matches!(result, Err(VaultError::InvalidKeySize { .. })));
     }
 
@@ -116,7 +545,7 @@ mod tests {
     fn test_invalid_data_format() {
         let key = [42u8; 32];
         let short_data = vec![0u8; 5];
-        let result = decrypt(&key, &short_data);
+        let result = decrypt(&key, &short_data, b"");
         assert!(matches!(result, Err(VaultError::InvalidDataFormat(_))));
     }
 
@@ -126,8 +555,182 @@ mod tests {
         let key2 = [43u8; 32];
         let plaintext = b"Secret message";
 
-        let encrypted = encrypt(&key1, plaintext).unwrap();
-        let result = decrypt(&key2, &encrypted);
+        let encrypted = encrypt(&key1, plaintext, b"").unwrap();
+        let result = decrypt(&key2, &encrypted, b"");
         assert!(matches!(result, Err(VaultError::DecryptionFailed(_))));
     }
+
+    #[test]
+    fn test_all_ciphers_round_trip() {
+        let key = [7u8; 32];
+        for kind in ALL_CIPHERS {
+            let cipher = kind.cipher();
+            assert_eq!(cipher.kind(), kind);
+
+            let encrypted = cipher.encrypt(&key, b"payload", b"").unwrap();
+            assert_eq!(encrypted.len(), cipher.nonce_size() + b"payload".len() + 16);
+            assert_eq!(cipher.decrypt(&key, &encrypted, b"").unwrap(), b"payload");
+        }
+    }
+
+    #[test]
+    fn test_ciphers_are_not_interchangeable() {
+        let key = [7u8; 32];
+        let encrypted = CipherKind::ChaCha20Poly1305
+            .cipher()
+            .encrypt(&key, b"payload", b"")
+            .unwrap();
+        let result = CipherKind::Aes256Gcm
+            .cipher()
+            .decrypt(&key, &encrypted, b"");
+        assert!(matches!(result, Err(VaultError::DecryptionFailed(_))));
+    }
+
+    #[test]
+    fn test_cipher_kind_parse() {
+        for kind in ALL_CIPHERS {
+            assert_eq!(kind.as_str().parse::<CipherKind>().unwrap(), kind);
+        }
+        assert!(matches!(
+            "des".parse::<CipherKind>(),
+            Err(VaultError::UnsupportedCipher(_))
+        ));
+    }
+
+    #[test]
+    fn test_associated_data_is_authenticated() {
+        let key = [7u8; 32];
+        for kind in ALL_CIPHERS {
+            let cipher = kind.cipher();
+            let encrypted = cipher.encrypt(&key, b"payload", b"db_password").unwrap();
+
+            assert_eq!(
+                cipher.decrypt(&key, &encrypted, b"db_password").unwrap(),
+                b"payload"
+            );
+            assert!(matches!(
+                cipher.decrypt(&key, &encrypted, b"api_key"),
+                Err(VaultError::DecryptionFailed(_))
+            ));
+            assert!(matches!(
+                cipher.decrypt(&key, &encrypted, b""),
+                Err(VaultError::DecryptionFailed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_envelope_header_round_trip() {
+        let key = [7u8; 32];
+        let id = key_id(&key);
+        for kind in ALL_CIPHERS {
+            let encrypted = encrypt_with(kind, &id, &key, b"payload", b"aad").unwrap();
+            assert!(encrypted.starts_with(ENVELOPE_MAGIC));
+
+            let (header, len) = EnvelopeHeader::parse(&encrypted).unwrap().unwrap();
+            assert_eq!(header.version, ENVELOPE_VERSION);
+            assert_eq!(header.cipher, kind);
+            assert_eq!(header.key_id, id);
+            assert_eq!(header.nonce_len as usize, kind.cipher().nonce_size());
+            assert_eq!(len, 8 + id.len());
+
+            // The header, not the caller, decides which cipher is used.
+            let decrypted = decrypt_with(CipherKind::Aes256Gcm, &key, &encrypted, b"aad").unwrap();
+            assert_eq!(decrypted, b"payload");
+        }
+    }
+
+    #[test]
+    fn test_legacy_headerless_blob() {
+        let key = [7u8; 32];
+        let legacy = Aes256GcmCipher.encrypt(&key, b"old", b"").unwrap();
+        assert!(EnvelopeHeader::parse(&legacy).unwrap().is_none());
+        assert_eq!(decrypt(&key, &legacy, b"").unwrap(), b"old");
+
+        let legacy = ChaCha20Poly1305Cipher.encrypt(&key, b"old", b"").unwrap();
+        assert_eq!(
+            decrypt_with(CipherKind::ChaCha20Poly1305, &key, &legacy, b"").unwrap(),
+            b"old"
+        );
+    }
+
+    #[test]
+    fn test_legacy_blob_that_looks_like_an_envelope() {
+        let key = [7u8; 32];
+        // A legacy nonce that happens to spell out an AES-256-GCM header.
+        let nonce = *b"RMSV\x01\x01\x00\x0cabcd";
+        let ciphertext = Aes256Gcm::new(GenericArray::from_slice(&key))
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: b"old",
+                    aad: b"",
+                },
+            )
+            .unwrap();
+        let legacy = [&nonce[..], &ciphertext].concat();
+        assert!(EnvelopeHeader::parse(&legacy).unwrap().is_some());
+        assert_eq!(decrypt(&key, &legacy, b"").unwrap(), b"old");
+        assert!(matches!(
+            decrypt(&[8u8; 32], &legacy, b""),
+            Err(VaultError::DecryptionFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_header_is_authenticated() {
+        let key = [7u8; 32];
+        let mut encrypted = encrypt(&key, b"payload", b"").unwrap();
+        // Rewriting the cipher ID is detected even though the nonce size matches.
+        encrypted[5] = CipherKind::ChaCha20Poly1305.id();
+        assert!(decrypt(&key, &encrypted, b"").is_err());
+    }
+
+    #[test]
+    fn test_malformed_headers() {
+        let key = [7u8; 32];
+        let cases: Vec<(Vec<u8>, &str)> = vec![
+            (b"RMSV".to_vec(), "missing format version"),
+            (
+                b"RMSV\x09".to_vec(),
+                "Unsupported envelope format version 9",
+            ),
+            (b"RMSV\x01\x07".to_vec(), "Unknown cipher ID 7"),
+            (b"RMSV\x01\x01\x05ab".to_vec(), "key ID declares 5 bytes"),
+            (b"RMSV\x01\x01\x00".to_vec(), "missing nonce length"),
+            (
+                b"RMSV\x01\x03\x00\x0c".to_vec(),
+                "Nonce length 12 does not match",
+            ),
+        ];
+        for (data, expected) in cases {
+            match decrypt(&key, &data, b"") {
+                Err(VaultError::InvalidDataFormat(msg)) => {
+                    assert!(msg.contains(expected), "{:?} -> {}", expected, msg)
+                }
+                other => panic!(
+                    "expected InvalidDataFormat for {:?}, got {:?}",
+                    expected, other
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_id_mismatch() {
+        let key1 = [1u8; 32];
+        let key2 = [2u8; 32];
+        let encrypted = encrypt_with(
+            CipherKind::Aes256Gcm,
+            &key_id(&key1),
+            &key1,
+            b"payload",
+            b"",
+        )
+        .unwrap();
+        assert!(matches!(
+            decrypt(&key2, &encrypted, b""),
+            Err(VaultError::KeyIdMismatch { .. })
+        ));
+    }
 }
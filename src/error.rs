@@ -19,8 +19,44 @@ pub enum VaultError {
     InvalidSecretKey(String),
     /// Secret not found
     SecretNotFound(String),
+    /// The secret is deleted; undelete or purge it before setting it
+    SecretDeleted(String),
     /// Key loading error
     KeyLoadError(String),
+    /// Unknown or unsupported cipher algorithm
+    UnsupportedCipher(String),
+    /// A ciphertext did not authenticate against the slot it was read from
+    IntegrityCheckFailed { key: String, version: u32 },
+    /// The data key of this version was destroyed
+    SecretShredded { key: String, version: u32 },
+    /// The ciphertext names a different key than the one supplied
+    KeyIdMismatch { expected: String, found: String },
+    /// No key with this ID is loaded
+    UnknownKeyId(String),
+    /// The key is active or still protects entries
+    KeyInUse {
+        key_id: String,
+        entries: usize,
+        active: bool,
+    },
+    /// An interrupted rotation is staged and blocks this operation
+    RotationInProgress(String),
+    /// Another writer saved the vault since it was loaded
+    ConcurrentModification { expected: u64, found: u64 },
+    /// A storage backend failed
+    Storage(String),
+    /// The vault was written by a newer release in a format this one cannot read
+    UnsupportedFormatVersion { found: u32, supported: u32 },
+    /// The vault does not exist and may not be created
+    VaultNotFound(String),
+    /// A vault already exists where a new one was to be created
+    VaultExists(String),
+    /// A change was attempted on a vault opened read-only
+    ReadOnly,
+    /// The vault does not match its manifest
+    ManifestMismatch(String),
+    /// The vault is older than the generation pinned in the local state file
+    RollbackDetected { pinned: u64, found: u64 },
 }
 
 impl fmt::Display for VaultError {
@@ -40,7 +76,68 @@ impl fmt::Display for VaultError {
             VaultError::Serialization(msg) => write!(f, "Serialization error: {}", msg),
             VaultError::InvalidSecretKey(key) => write!(f, "Invalid secret key: {}", key),
             VaultError::SecretNotFound(key) => write!(f, "Secret not found: {}", key),
+            VaultError::SecretDeleted(key) => write!(
+                f,
+                "Secret {} is deleted; undelete it to keep its history, or purge it to start over",
+                key
+            ),
             VaultError::KeyLoadError(msg) => write!(f, "Failed to load key: {}", msg),
+            VaultError::UnsupportedCipher(name) => write!(f, "Unsupported cipher: {}", name),
+            VaultError::IntegrityCheckFailed { key, version } => write!(
+                f,
+                "Integrity check failed for secret '{}' version {}: the entry was modified or moved, or the master key is wrong",
+                key, version
+            ),
+            VaultError::SecretShredded { key, version } => write!(
+                f,
+                "Secret '{}' version {} has been shredded and cannot be decrypted",
+                key, version
+            ),
+            VaultError::KeyIdMismatch { expected, found } => write!(
+                f,
+                "Key mismatch: data was encrypted with key {} but key {} was supplied",
+                expected, found
+            ),
+            VaultError::UnknownKeyId(key_id) => write!(f, "No key with ID {} is loaded", key_id),
+            VaultError::RotationInProgress(msg) => write!(f, "Rotation in progress: {}", msg),
+            VaultError::ConcurrentModification { expected, found } => write!(
+                f,
+                "Vault was modified concurrently: expected generation {}, found {}",
+                expected, found
+            ),
+            VaultError::Storage(msg) => write!(f, "Storage error: {}", msg),
+            VaultError::UnsupportedFormatVersion { found, supported } => write!(
+                f,
+                "Vault format version {} is newer than the latest supported version {}; upgrade this tool to open it",
+                found, supported
+            ),
+            VaultError::KeyInUse {
+                key_id,
+                active: true,
+                ..
+            } => write!(
+                f,
+                "Key {} is the active key; activate another key first",
+                key_id
+            ),
+            VaultError::KeyInUse {
+                key_id, entries, ..
+            } => write!(
+                f,
+                "Key {} still protects {} entries; rewrap them first",
+                key_id, entries
+            ),
+            VaultError::VaultNotFound(path) => write!(f, "Vault not found: {}", path),
+            VaultError::VaultExists(path) => write!(f, "Vault already exists: {}", path),
+            VaultError::ReadOnly => write!(f, "Vault is open read-only"),
+            VaultError::ManifestMismatch(msg) => {
+                write!(f, "Vault manifest check failed: {}", msg)
+            }
+            VaultError::RollbackDetected { pinned, found } => write!(
+                f,
+                "Vault generation {} is older than the last one seen, {}; it may have been rolled back",
+                found, pinned
+            ),
         }
     }
 }
@@ -65,6 +162,13 @@ impl From<serde_json::Error> for VaultError {
     }
 }
 
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for VaultError {
+    fn from(err: rusqlite::Error) -> Self {
+        VaultError::Storage(err.to_string())
+    }
+}
+
 impl From<base64::DecodeError> for VaultError {
     fn from(err: base64::DecodeError) -> Self {
         VaultError::KeyLoadError(format!("Base64 decode error: {}", err))
@@ -0,0 +1,144 @@
+use crate::encryption::KEY_SIZE;
+use crate::error::{Result, VaultError};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+pub const SALT_SIZE: usize = 16;
+
+/// Password-hashing algorithms supported for deriving master keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum KdfAlgorithm {
+    #[default]
+    #[serde(rename = "argon2id")]
+    Argon2id,
+}
+
+/// Salt and cost parameters used to derive a master key from a passphrase.
+///
+/// These are stored in the vault file; they are not secret, but the same
+/// values are required to derive the same key again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    #[serde(with = "crate::encoding::bytes")]
+    pub salt: Vec<u8>,
+    /// Memory cost in KiB.
+    pub memory_kib: u32,
+    /// Number of passes over memory.
+    pub iterations: u32,
+    /// Degree of parallelism (lanes).
+    pub parallelism: u32,
+    /// Key ID of the derived key, used to reject a wrong passphrase up front.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub key_id: String,
+}
+
+impl KdfParams {
+    /// Creates parameters with a fresh random salt.
+    ///
+    /// # Errors
+    /// Returns `VaultError::KeyLoadError` if Argon2 rejects the cost parameters.
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Self> {
+        let mut salt = vec![0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+
+        let params = Self {
+            algorithm: KdfAlgorithm::Argon2id,
+            salt,
+            memory_kib,
+            iterations,
+            parallelism,
+            key_id: String::new(),
+        };
+        params.argon2()?;
+        Ok(params)
+    }
+
+    /// Parameters sized for phones and embedded devices: 19 MiB, 2 passes, 1 lane.
+    pub fn mobile() -> Self {
+        Self::new(19 * 1024, 2, 1).expect("mobile KDF preset is valid")
+    }
+
+    /// Parameters sized for servers: 256 MiB, 3 passes, 4 lanes.
+    pub fn server() -> Self {
+        Self::new(256 * 1024, 3, 4).expect("server KDF preset is valid")
+    }
+
+    /// Returns a copy with the same cost parameters and a fresh salt.
+    pub fn with_fresh_salt(&self) -> Self {
+        let mut salt = vec![0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            salt,
+            key_id: String::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Derives a 32-byte key from `passphrase`.
+    ///
+    /// # Errors
+    /// Returns `VaultError::KeyLoadError` if the parameters are invalid or
+    /// derivation fails.
+    pub fn derive(&self, passphrase: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let mut key = Zeroizing::new(vec![0u8; KEY_SIZE]);
+        self.argon2()?
+            .hash_password_into(passphrase, &self.salt, &mut key)
+            .map_err(|e| VaultError::KeyLoadError(format!("Key derivation failed: {}", e)))?;
+        Ok(key)
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>> {
+        let params = Params::new(
+            self.memory_kib,
+            self.iterations,
+            self.parallelism,
+            Some(KEY_SIZE),
+        )
+        .map_err(|e| VaultError::KeyLoadError(format!("Invalid KDF parameters: {}", e)))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self::mobile()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cheap() -> KdfParams {
+        KdfParams::new(64, 1, 1).unwrap()
+    }
+
+    #[test]
+    fn test_derive_is_deterministic_per_salt() {
+        let params = cheap();
+        let a = params.derive(b"correct horse").unwrap();
+        let b = params.derive(b"correct horse").unwrap();
+        assert_eq!(*a, *b);
+        assert_eq!(a.len(), KEY_SIZE);
+
+        let other = params.with_fresh_salt();
+        assert_ne!(other.salt, params.salt);
+        assert_ne!(*other.derive(b"correct horse").unwrap(), *a);
+        assert_ne!(*params.derive(b"battery staple").unwrap(), *a);
+    }
+
+    #[test]
+    fn test_invalid_cost_parameters() {
+        assert!(matches!(
+            KdfParams::new(1, 1, 1),
+            Err(VaultError::KeyLoadError(_))
+        ));
+        assert!(matches!(
+            KdfParams::new(64, 0, 1),
+            Err(VaultError::KeyLoadError(_))
+        ));
+    }
+}
@@ -0,0 +1,125 @@
+use crate::error::{Result, VaultError};
+use crate::vault::MasterKey;
+use std::collections::BTreeMap;
+
+/// A set of master keys identified by key ID, one of which is active.
+///
+/// The active key wraps the data keys of new entries. The other keys are kept
+/// so entries written under them can still be decrypted until they have been
+/// rewrapped and the key is retired.
+#[derive(Clone)]
+pub struct Keyring {
+    keys: BTreeMap<String, MasterKey>,
+    active: String,
+}
+
+impl Keyring {
+    /// Creates a keyring holding a single, active key.
+    pub fn new(active: MasterKey) -> Self {
+        let id = active.key_id();
+        let mut keys = BTreeMap::new();
+        keys.insert(id.clone(), active);
+        Self { keys, active: id }
+    }
+
+    /// Adds a key without activating it.
+    ///
+    /// # Returns
+    /// The ID of the added key.
+    pub fn insert(&mut self, key: MasterKey) -> String {
+        let id = key.key_id();
+        self.keys.insert(id.clone(), key);
+        id
+    }
+
+    /// Marks a key already in the ring as active.
+    ///
+    /// # Errors
+    /// Returns `VaultError::UnknownKeyId` if the ring does not hold the key.
+    pub fn set_active(&mut self, key_id: &str) -> Result<()> {
+        if !self.keys.contains_key(key_id) {
+            return Err(VaultError::UnknownKeyId(key_id.to_string()));
+        }
+        self.active = key_id.to_string();
+        Ok(())
+    }
+
+    /// Removes a key from the ring. The active key cannot be removed.
+    pub(crate) fn remove(&mut self, key_id: &str) -> Option<MasterKey> {
+        if key_id == self.active {
+            return None;
+        }
+        self.keys.remove(key_id)
+    }
+
+    /// Drops every key except the active one.
+    pub(crate) fn retain_active(&mut self) {
+        let active = self.active.clone();
+        self.keys.retain(|id, _| *id == active);
+    }
+
+    /// Returns the active key.
+    pub fn active(&self) -> &MasterKey {
+        &self.keys[&self.active]
+    }
+
+    /// Returns the ID of the active key.
+    pub fn active_id(&self) -> &str {
+        &self.active
+    }
+
+    /// Looks up a key by ID.
+    ///
+    /// # Errors
+    /// Returns `VaultError::UnknownKeyId` if the ring does not hold the key.
+    pub fn get(&self, key_id: &str) -> Result<&MasterKey> {
+        self.keys
+            .get(key_id)
+            .ok_or_else(|| VaultError::UnknownKeyId(key_id.to_string()))
+    }
+
+    /// Returns whether the ring holds a key with this ID.
+    pub fn contains(&self, key_id: &str) -> bool {
+        self.keys.contains_key(key_id)
+    }
+
+    /// Returns the IDs of every key in the ring, sorted.
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.keys.keys().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> MasterKey {
+        MasterKey::new(vec![byte; 32]).unwrap()
+    }
+
+    #[test]
+    fn test_keyring_active_and_lookup() {
+        let mut ring = Keyring::new(key(1));
+        let first = ring.active_id().to_string();
+        let second = ring.insert(key(2));
+
+        assert_eq!(ring.active_id(), first);
+        assert_eq!(ring.get(&second).unwrap().as_bytes(), &[2u8; 32]);
+        assert!(matches!(ring.get("nope"), Err(VaultError::UnknownKeyId(_))));
+
+        ring.set_active(&second).unwrap();
+        assert_eq!(ring.active().as_bytes(), &[2u8; 32]);
+        assert!(ring.set_active("nope").is_err());
+    }
+
+    #[test]
+    fn test_keyring_remove() {
+        let mut ring = Keyring::new(key(1));
+        let active = ring.active_id().to_string();
+        let other = ring.insert(key(2));
+
+        assert!(ring.remove(&active).is_none());
+        assert!(ring.remove(&other).is_some());
+        assert_eq!(ring.ids().collect::<Vec<_>>(), vec![active.as_str()]);
+    }
+}
@@ -1,10 +1,28 @@
 pub mod audit;
 pub mod cli;
+mod encoding;
 pub mod encryption;
 pub mod error;
+pub mod kdf;
+pub mod keyring;
+pub mod storage;
 pub mod vault;
 
-pub use audit::{AuditLogger, Operation};
-pub use encryption::{decrypt, encrypt};
+pub use audit::{AuditChange, AuditLogger, Operation};
+pub use encryption::{
+    decrypt, decrypt_with, encrypt, encrypt_with, Cipher, CipherKind, EnvelopeHeader,
+};
 pub use error::{Result, VaultError};
-pub use vault::{KeySource, MasterKey, SecretVault};
+pub use kdf::KdfParams;
+pub use keyring::Keyring;
+#[cfg(feature = "sqlite")]
+pub use storage::SqliteStorage;
+pub use storage::{
+    FileStorage, LockMode, LogStorage, MemoryStorage, SealedStorage, StorageFormat, StorageLock,
+    VaultStorage,
+};
+pub use vault::{
+    merge_vaults, IssueKind, KeySource, Manifest, MasterKey, MemoryUsage, MergeConflict,
+    MergeOutcome, RetentionPolicy, RotationOptions, RotationReport, SecretEntry, SecretVault,
+    Transaction, VaultData, VerifyIssue, VerifyReport, FORMAT_VERSION,
+};
@@ -0,0 +1,231 @@
+use super::{LockMode, StorageLock};
+use crate::error::Result;
+use rand::{rngs::OsRng, RngCore};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Returns `path` with `suffix` appended to its file name, e.g. `vault.yaml.rotation`.
+pub(crate) fn sidecar_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Replaces `path` with `bytes` so that readers, and the file after a crash,
+/// see either the old contents or the new ones, never a mix.
+///
+/// The bytes go to a temporary file in the same directory, which is synced,
+/// given the permissions of the file it replaces and renamed over it. The
+/// directory is synced afterwards so the rename itself survives a power loss.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    write_atomic_with(path, |file| Ok(file.write_all(bytes)?))
+}
+
+/// Like [`write_atomic`], with the contents written by `write` straight into
+/// the temporary file, so that they need not be held in memory.
+pub(super) fn write_atomic_with(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> Result<()>,
+) -> Result<()> {
+    let permissions = match fs::metadata(path) {
+        Ok(metadata) => Some(metadata.permissions()),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    replace_with(path, permissions, write)
+}
+
+/// Atomically replaces `path` with the contents written by `write`, giving
+/// the new file `permissions` if set.
+fn replace_with(
+    path: &Path,
+    permissions: Option<fs::Permissions>,
+    write: impl FnOnce(&mut File) -> Result<()>,
+) -> Result<()> {
+    let tmp_path = sidecar_path(path, &format!("tmp.{:016x}", OsRng.next_u64()));
+    let result =
+        write_synced(&tmp_path, permissions, write).and_then(|()| rename_durable(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Renames `from` over `to`, syncing the directory so that the rename
+/// survives a power loss.
+pub(crate) fn rename_durable(from: &Path, to: &Path) -> Result<()> {
+    fs::rename(from, to)?;
+    sync_parent(to)
+}
+
+/// Blocks until an advisory lock on `<path>.lock` is held.
+///
+/// Readers that cannot create or write the lock file, as on a read-only
+/// mount, lock an existing one through a read-only handle and otherwise go
+/// without: nobody can write the vault there either.
+pub(super) fn lock_file(path: &Path, mode: LockMode) -> Result<StorageLock> {
+    let lock_path = sidecar_path(path, "lock");
+    let opened = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path);
+    let file = match (opened, mode) {
+        (Ok(file), _) => file,
+        (Err(e), LockMode::Shared) if is_read_only(&e) => match File::open(&lock_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(StorageLock::none()),
+            Err(e) => return Err(e.into()),
+        },
+        (Err(e), _) => return Err(e.into()),
+    };
+    match mode {
+        LockMode::Shared => file.lock_shared()?,
+        LockMode::Exclusive => file.lock()?,
+    }
+    Ok(StorageLock::new(file))
+}
+
+/// Returns whether `err` means the file system refuses writes here.
+fn is_read_only(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem
+    )
+}
+
+/// Reads `path`, or returns `None` if it does not exist.
+pub(crate) fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces `path` atomically with `bytes`, or with `None` removes it.
+pub(super) fn write_or_remove(path: &Path, bytes: Option<&[u8]>) -> Result<()> {
+    match bytes {
+        Some(bytes) => write_atomic(path, bytes),
+        None => match fs::remove_file(path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        },
+    }
+}
+
+/// Copies `path` to `<path>.bak` durably, if `path` exists.
+///
+/// The backup holds everything the vault does, so it always gets the vault's
+/// permissions rather than those of an earlier backup or the umask default.
+pub(super) fn write_backup(path: &Path) -> Result<()> {
+    let mut previous = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let permissions = previous.metadata()?.permissions();
+    replace_with(&sidecar_path(path, "bak"), Some(permissions), |file| {
+        io::copy(&mut previous, file)?;
+        Ok(())
+    })
+}
+
+fn write_synced(
+    tmp_path: &Path,
+    permissions: Option<fs::Permissions>,
+    write: impl FnOnce(&mut File) -> Result<()>,
+) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(tmp_path)?;
+    if let Some(permissions) = permissions {
+        file.set_permissions(permissions)?;
+    }
+    write(&mut file)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+// Directories cannot be opened for syncing on other platforms; the rename is
+// as durable as the filesystem makes it.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_atomic_replaces_and_cleans_up() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.vault");
+
+        write_atomic(&path, b"first").unwrap();
+        write_backup(&path).unwrap();
+        write_atomic(&path, b"second").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read(sidecar_path(&path, "bak")).unwrap(), b"first");
+        let names: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names.len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_preserves_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.vault");
+        fs::write(&path, b"first").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        write_atomic(&path, b"second").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backup_gets_vault_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.vault");
+        fs::write(&path, b"first").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        write_backup(&path).unwrap();
+        let bak_path = sidecar_path(&path, "bak");
+        let mode = fs::metadata(&bak_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // A backup left readable by an earlier release is tightened too.
+        fs::set_permissions(&bak_path, fs::Permissions::from_mode(0o644)).unwrap();
+        write_backup(&path).unwrap();
+        let mode = fs::metadata(&bak_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,576 @@
+use super::atomic::{
+    lock_file, read_if_exists, sidecar_path, write_atomic, write_atomic_with, write_backup,
+    write_or_remove,
+};
+use super::index::{
+    index_vault, write_vault, CborReader, Index, Output, SecretIndex, Source, Stamp,
+};
+use super::{entry_versions, select_entry, LockMode, StorageLock, VaultStorage};
+use crate::error::{Result, VaultError};
+use crate::vault::{check_version, SecretEntry, VaultData};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Magic bytes opening a binary vault file, followed by the CBOR document.
+pub(super) const BINARY_MAGIC: &[u8] = b"RMSVCBOR";
+
+/// A vault file, guarded by an advisory lock on `<vault>.lock`.
+///
+/// The file is YAML by default, or compact CBOR with [`FileStorage::binary`].
+/// Saves replace the file atomically and durably. With [`FileStorage::backup`]
+/// the previous generation is kept as `<vault>.bak`. Sidecar records live next
+/// to the vault as `<vault>.<name>`.
+///
+/// Binary files can be opened [index-only](FileStorage::index_only), keeping
+/// just the location of each entry in memory.
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    path: PathBuf,
+    backup: bool,
+    encoding: Encoding,
+    index_only: bool,
+    /// The index of the file in index-only mode, shared between clones.
+    index: Arc<Mutex<Option<Index>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Yaml,
+    Binary,
+}
+
+impl Encoding {
+    fn encode(self, data: &VaultData) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Yaml => Ok(serde_yaml::to_string(data)?.into_bytes()),
+            Encoding::Binary => {
+                let mut bytes = BINARY_MAGIC.to_vec();
+                ciborium::into_writer(data, &mut bytes)
+                    .map_err(|e| VaultError::Serialization(e.to_string()))?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        match self {
+            Encoding::Yaml => Ok(serde_yaml::from_slice(bytes)?),
+            Encoding::Binary => {
+                let body = bytes.strip_prefix(BINARY_MAGIC).ok_or_else(|| {
+                    VaultError::InvalidDataFormat("Not a binary vault file".to_string())
+                })?;
+                ciborium::from_reader(body).map_err(|e| VaultError::Serialization(e.to_string()))
+            }
+        }
+    }
+}
+
+/// The parts of the file read back without decoding the whole vault.
+#[derive(Deserialize)]
+struct Header {
+    #[serde(default)]
+    generation: u64,
+    #[serde(default)]
+    format_version: u32,
+}
+
+impl FileStorage {
+    /// Creates a backend for the YAML vault file at `path`.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            backup: false,
+            encoding: Encoding::Yaml,
+            index_only: false,
+            index: Arc::default(),
+        }
+    }
+
+    /// Creates a backend for the binary vault file at `path`.
+    pub fn binary(path: impl AsRef<Path>) -> Self {
+        Self {
+            encoding: Encoding::Binary,
+            ..Self::new(path)
+        }
+    }
+
+    /// Keeps a copy of the previous vault file as `<vault>.bak` on every save.
+    pub fn backup(mut self, enabled: bool) -> Self {
+        self.backup = enabled;
+        self
+    }
+
+    /// Loads only an index of where each entry is stored, reading entries
+    /// from the file when they are asked for. Saves then rewrite the file
+    /// by copying unchanged entries from the previous one, so the vault
+    /// never has to fit in memory.
+    ///
+    /// Only binary files can be opened this way; loading a YAML file fails.
+    pub fn index_only(mut self, enabled: bool) -> Self {
+        self.index_only = enabled;
+        self
+    }
+
+    /// Returns the path of the vault file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether the file is written in the binary format.
+    pub fn is_binary(&self) -> bool {
+        self.encoding == Encoding::Binary
+    }
+
+    /// Returns the index of the file, rebuilding it if the file changed.
+    fn index(&self) -> Result<MutexGuard<'_, Option<Index>>> {
+        let mut index = self
+            .index
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let stamp = Stamp::of_path(&self.path)?;
+        if index.as_ref().map(|index| index.stamp) != stamp {
+            *index = None;
+            if stamp.is_some() {
+                *index = Some(self.build_index()?);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Returns the index built when the vault was loaded, for reading the
+    /// entries that belong to that load.
+    ///
+    /// # Errors
+    /// Returns `VaultError::ConcurrentModification` if the file was saved
+    /// since, because its entries would not match the loaded metadata.
+    fn loaded_index(&self) -> Result<MutexGuard<'_, Option<Index>>> {
+        let index = self
+            .index
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Some((stamp, expected)) = index
+            .as_ref()
+            .map(|index| (index.stamp, index.metadata.generation))
+        else {
+            drop(index);
+            return self.index();
+        };
+        let current = Stamp::of_path(&self.path)?;
+        if current != Some(stamp) {
+            let found = match current {
+                Some(_) => self.build_index()?.metadata.generation,
+                None => 0,
+            };
+            return Err(VaultError::ConcurrentModification { expected, found });
+        }
+        Ok(index)
+    }
+
+    fn build_index(&self) -> Result<Index> {
+        if self.encoding != Encoding::Binary {
+            return Err(VaultError::InvalidDataFormat(
+                "Only binary vault files can be loaded index-only".to_string(),
+            ));
+        }
+        let mut file = File::open(&self.path)?;
+        let stamp = Stamp::of(&file.metadata()?);
+        let mut magic = [0u8; BINARY_MAGIC.len()];
+        file.read_exact(&mut magic)?;
+        if magic != BINARY_MAGIC {
+            return Err(VaultError::InvalidDataFormat(
+                "Not a binary vault file".to_string(),
+            ));
+        }
+        let mut reader = CborReader::new(BufReader::new(&file), magic.len() as u64);
+        let (metadata, secrets) = index_vault(&mut reader)?;
+        Ok(Index {
+            file,
+            stamp,
+            metadata,
+            secrets,
+        })
+    }
+
+    /// Writes the next generation with the changed secrets, copying the
+    /// others from the current file.
+    fn save_indexed(
+        &self,
+        data: &mut VaultData,
+        changes: &[(&str, Option<&[SecretEntry]>)],
+    ) -> Result<()> {
+        let _lock = self.lock(LockMode::Exclusive)?;
+        let mut index = self.index()?;
+        let found = index.as_ref().map_or(0, |index| index.metadata.generation);
+        if found != data.generation {
+            return Err(VaultError::ConcurrentModification {
+                expected: data.generation,
+                found,
+            });
+        }
+
+        let metadata = VaultData {
+            generation: data.generation + 1,
+            ..data.metadata()
+        };
+        let mut secrets: BTreeMap<&str, Source<'_>> = index
+            .iter()
+            .flat_map(|index| &index.secrets)
+            .map(|(name, entries)| (name.as_str(), Source::Stored(entries)))
+            .collect();
+        for (name, entries) in changes {
+            match entries {
+                Some(entries) => secrets.insert(name, Source::Entries(entries)),
+                None => secrets.remove(name),
+            };
+        }
+
+        if self.backup {
+            write_backup(&self.path)?;
+        }
+        let mut written = SecretIndex::new();
+        write_atomic_with(&self.path, |file| {
+            let mut out = Output::new(BufWriter::new(file), 0);
+            out.write_all(BINARY_MAGIC)?;
+            let stored = index.as_ref().map(|index| &index.file);
+            written = write_vault(&mut out, &metadata, &secrets, stored)?;
+            out.flush()?;
+            Ok(())
+        })?;
+
+        let file = File::open(&self.path)?;
+        *index = Some(Index {
+            stamp: Stamp::of(&file.metadata()?),
+            file,
+            metadata,
+            secrets: written,
+        });
+        data.generation += 1;
+        Ok(())
+    }
+
+    /// Returns the generation of the vault file, if there is one.
+    fn generation(&self) -> Result<Option<u64>> {
+        if self.index_only {
+            return Ok(self
+                .index()?
+                .as_ref()
+                .map(|index| index.metadata.generation));
+        }
+        self.read_generation(&self.path)
+    }
+
+    fn read_generation(&self, path: &Path) -> Result<Option<u64>> {
+        read_if_exists(path)?
+            .map(|bytes| Ok(self.encoding.decode::<Header>(&bytes)?.generation))
+            .transpose()
+    }
+}
+
+impl VaultStorage for FileStorage {
+    fn load(&self) -> Result<Option<VaultData>> {
+        if self.index_only {
+            return Ok(self.index()?.as_ref().map(|index| index.metadata.clone()));
+        }
+        let Some(bytes) = read_if_exists(&self.path)? else {
+            return Ok(None);
+        };
+        match self.encoding.decode(&bytes) {
+            Ok(data) => Ok(Some(data)),
+            // A newer layout may not decode at all; say so rather than
+            // reporting whatever field failed to parse.
+            Err(e) => match self.encoding.decode::<Header>(&bytes) {
+                Ok(header) => check_version(header.format_version).and(Err(e)),
+                Err(_) => Err(e),
+            },
+        }
+    }
+
+    fn store(&self, data: &VaultData) -> Result<()> {
+        let bytes = self.encoding.encode(data)?;
+        if self.backup {
+            write_backup(&self.path)?;
+        }
+        write_atomic(&self.path, &bytes)
+    }
+
+    fn lock(&self, mode: LockMode) -> Result<StorageLock> {
+        lock_file(&self.path, mode)
+    }
+
+    fn generations(&self) -> Result<Vec<u64>> {
+        let mut generations = Vec::new();
+        if self.backup {
+            generations.extend(self.read_generation(&sidecar_path(&self.path, "bak"))?);
+        }
+        generations.extend(self.generation()?);
+        Ok(generations)
+    }
+
+    fn current_generation(&self) -> Result<u64> {
+        Ok(self.generation()?.unwrap_or(0))
+    }
+
+    fn load_sidecar(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        read_if_exists(&sidecar_path(&self.path, name))
+    }
+
+    fn store_sidecar(&self, name: &str, bytes: Option<&[u8]>) -> Result<()> {
+        write_or_remove(&sidecar_path(&self.path, name), bytes)
+    }
+
+    fn is_lazy(&self) -> bool {
+        self.index_only
+    }
+
+    fn secret_names(&self) -> Result<Vec<String>> {
+        if !self.index_only {
+            return Ok(self
+                .load()?
+                .map(|data| data.secrets.into_keys().collect())
+                .unwrap_or_default());
+        }
+        Ok(self
+            .index()?
+            .iter()
+            .flat_map(|index| index.secrets.keys().cloned())
+            .collect())
+    }
+
+    fn load_secret(&self, name: &str) -> Result<Option<Vec<SecretEntry>>> {
+        if !self.index_only {
+            return Ok(self.load()?.and_then(|mut data| data.secrets.remove(name)));
+        }
+        match self.loaded_index()?.as_ref() {
+            Some(index) => index.read_secret(name),
+            None => Ok(None),
+        }
+    }
+
+    fn load_entry(&self, name: &str, version: Option<u32>) -> Result<Option<SecretEntry>> {
+        if !self.index_only {
+            return Ok(select_entry(self.load_secret(name)?, version));
+        }
+        match self.loaded_index()?.as_ref() {
+            Some(index) => index.read_entry(name, version),
+            None => Ok(None),
+        }
+    }
+
+    fn entry_versions(&self, name: &str) -> Result<Vec<u32>> {
+        if !self.index_only {
+            return Ok(entry_versions(self.load_secret(name)?));
+        }
+        Ok(self
+            .loaded_index()?
+            .iter()
+            .flat_map(|index| index.secrets.get(name))
+            .flatten()
+            .map(|entry| entry.version)
+            .collect())
+    }
+
+    fn index_size(&self) -> usize {
+        self.index
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .as_ref()
+            .map_or(0, Index::size)
+    }
+
+    fn save_changes(
+        &self,
+        data: &mut VaultData,
+        changes: &[(&str, Option<&[SecretEntry]>)],
+    ) -> Result<()> {
+        if self.index_only {
+            self.save_indexed(data, changes)
+        } else {
+            self.save(data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::VaultError;
+    use crate::storage::StorageFormat;
+    use crate::vault::{KeySource, SecretVault};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_save_bumps_generation_and_detects_conflicts() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::new(temp_dir.path().join("test.vault")).backup(true);
+
+        let mut ours = VaultData::default();
+        storage.save(&mut ours).unwrap();
+        assert_eq!(ours.generation, 1);
+
+        let mut theirs = storage.load().unwrap().unwrap();
+        storage.save(&mut theirs).unwrap();
+        assert_eq!(theirs.generation, 2);
+        assert_eq!(storage.generations().unwrap(), vec![1, 2]);
+
+        assert!(matches!(
+            storage.save(&mut ours),
+            Err(VaultError::ConcurrentModification {
+                expected: 1,
+                found: 2
+            })
+        ));
+        assert_eq!(ours.generation, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_first_backup_keeps_vault_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test.vault");
+        let storage = FileStorage::new(&path).backup(true);
+        let mut data = VaultData::default();
+        storage.save(&mut data).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        storage.save(&mut data).unwrap();
+        let bak_path = temp_dir.path().join("test.vault.bak");
+        let mode = fs::metadata(bak_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_binary_file_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let yaml = FileStorage::new(temp_dir.path().join("vault.yaml"));
+        let binary = FileStorage::binary(temp_dir.path().join("vault.rmsv"));
+
+        for storage in [&yaml, &binary] {
+            let mut vault = SecretVault::builder()
+                .master_key(KeySource::Bytes(vec![1u8; 32]))
+                .storage(storage.clone())
+                .build()
+                .unwrap();
+            vault.set("api_key", &[7u8; 64]).unwrap();
+        }
+
+        let loaded = binary.load().unwrap().unwrap();
+        assert_eq!(loaded.generation, 1);
+        assert_eq!(loaded.secrets["api_key"].len(), 1);
+        assert_eq!(binary.current_generation().unwrap(), 1);
+
+        let binary_len = fs::metadata(binary.path()).unwrap().len();
+        let yaml_len = fs::metadata(yaml.path()).unwrap().len();
+        assert!(binary_len < yaml_len);
+
+        // The builder recognises the binary file by its header.
+        assert_eq!(
+            StorageFormat::detect(binary.path()).unwrap(),
+            StorageFormat::Binary
+        );
+        let reopened = SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .vault_path(binary.path())
+            .build()
+            .unwrap();
+        assert_eq!(reopened.generation(), 1);
+        assert_eq!(reopened.get("api_key").unwrap().unwrap(), [7u8; 64]);
+
+        // Each encoding refuses the other's files.
+        assert!(FileStorage::binary(yaml.path()).load().is_err());
+        assert!(FileStorage::new(binary.path()).load().is_err());
+    }
+
+    #[test]
+    fn test_index_only_binary_vault() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("vault.rmsv");
+        let open = |index_only| {
+            SecretVault::builder()
+                .master_key(KeySource::Bytes(vec![1u8; 32]))
+                .vault_path(&path)
+                .format(StorageFormat::Binary)
+                .index_only(index_only)
+                .build()
+                .unwrap()
+        };
+
+        let mut vault = open(false);
+        vault
+            .transaction(|tx| {
+                for version in 1..=200 {
+                    tx.set("history", format!("value {}", version).as_bytes())?;
+                }
+                tx.set("other", b"other")
+            })
+            .unwrap();
+        let full = vault.memory_usage();
+        assert_eq!(full.loaded_secrets, 2);
+        assert_eq!(full.indexed_secrets, 0);
+
+        let mut vault = open(true);
+        let usage = vault.memory_usage();
+        assert_eq!(usage.loaded_secrets, 0);
+        assert_eq!(usage.indexed_secrets, 2);
+        assert!(usage.total_bytes() * 4 < full.total_bytes());
+        assert_eq!(vault.get("history").unwrap().unwrap(), b"value 200");
+        assert_eq!(
+            vault.get_version("history", 17).unwrap().unwrap(),
+            b"value 17"
+        );
+        assert_eq!(vault.list_versions("history").unwrap().len(), 200);
+        assert_eq!(vault.memory_usage().loaded_secrets, 0);
+
+        // Saves copy unchanged entries and drop the changed ones afterwards.
+        vault.set("other", b"changed").unwrap();
+        vault.delete("missing").unwrap();
+        assert_eq!(vault.memory_usage().loaded_secrets, 0);
+        assert_eq!(vault.get("other").unwrap().unwrap(), b"changed");
+
+        let reopened = open(false);
+        assert_eq!(reopened.generation(), 2);
+        assert_eq!(
+            reopened.get_version("history", 1).unwrap().unwrap(),
+            b"value 1"
+        );
+        assert_eq!(reopened.get_version("other", 1).unwrap().unwrap(), b"other");
+
+        // Entries are not read from another writer's save until the vault
+        // reloads, which a change does by itself.
+        let mut writer = open(false);
+        writer.set("history", b"value 201").unwrap();
+        assert!(matches!(
+            vault.get("history"),
+            Err(VaultError::ConcurrentModification {
+                expected: 2,
+                found: 3
+            })
+        ));
+        vault.set("third", b"3").unwrap();
+        assert_eq!(vault.get("history").unwrap().unwrap(), b"value 201");
+        assert_eq!(open(false).list_keys().len(), 3);
+
+        writer.set("history", b"value 202").unwrap();
+        assert!(matches!(
+            vault.list_versions("history"),
+            Err(VaultError::ConcurrentModification { .. })
+        ));
+        vault.reload().unwrap();
+        assert_eq!(vault.get("history").unwrap().unwrap(), b"value 202");
+
+        // YAML files have no entries to point at.
+        let yaml = SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .vault_path(temp_dir.path().join("vault.yaml"))
+            .index_only(true)
+            .build();
+        assert!(matches!(yaml, Err(VaultError::InvalidDataFormat(_))));
+    }
+}
@@ -0,0 +1,492 @@
+//! Index-only loading of the CBOR vault files.
+//!
+//! The binary and log-structured formats store every entry as a CBOR item of
+//! its own. Instead of decoding the whole vault, [`index_vault`] walks it once
+//! and keeps only the vault metadata and where each entry starts and ends.
+//! Entries are then read back one at a time, and [`write_vault`] writes a new
+//! vault whose unchanged entries are copied from the old file byte for byte.
+
+use crate::error::{Result, VaultError};
+use crate::vault::{SecretEntry, VaultData};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::time::SystemTime;
+
+const UNSIGNED: u8 = 0;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const SIMPLE: u8 = 7;
+/// Additional information of `null` under [`SIMPLE`].
+const NULL: u64 = 22;
+
+/// Where one stored entry is.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct EntryRef {
+    pub version: u32,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Entry locations by secret name, oldest version first.
+pub(super) type SecretIndex = BTreeMap<String, Vec<EntryRef>>;
+
+/// Identifies one state of a file, to tell whether an index still matches it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    inode: u64,
+}
+
+impl Stamp {
+    pub fn of(metadata: &fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            inode,
+        }
+    }
+
+    /// Returns the stamp of the file at `path`, or `None` if there is none.
+    pub fn of_path(path: &Path) -> Result<Option<Self>> {
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(Some(Self::of(&metadata))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+/// The index of a vault file, and the file it was built from.
+///
+/// Entries are read through `file`, so they stay consistent with the index
+/// even if the file is replaced in the meantime.
+#[derive(Debug)]
+pub(super) struct Index {
+    pub file: File,
+    pub stamp: Stamp,
+    pub metadata: VaultData,
+    pub secrets: SecretIndex,
+}
+
+impl Index {
+    /// Returns the approximate heap bytes the index occupies.
+    pub fn size(&self) -> usize {
+        self.secrets
+            .iter()
+            .map(|(name, entries)| {
+                // Map nodes cost roughly a name, a vector and two pointers.
+                name.len() + 4 * size_of::<usize>() + entries.len() * size_of::<EntryRef>()
+            })
+            .sum()
+    }
+
+    /// Reads every stored entry of `name`.
+    pub fn read_secret(&self, name: &str) -> Result<Option<Vec<SecretEntry>>> {
+        self.secrets
+            .get(name)
+            .map(|entries| entries.iter().map(|entry| self.read(entry)).collect())
+            .transpose()
+    }
+
+    /// Reads one stored entry of `name`: `version`, or the latest with `None`.
+    pub fn read_entry(&self, name: &str, version: Option<u32>) -> Result<Option<SecretEntry>> {
+        let Some(entries) = self.secrets.get(name) else {
+            return Ok(None);
+        };
+        let entry = match version {
+            Some(version) => entries.iter().find(|entry| entry.version == version),
+            None => entries.last(),
+        };
+        entry.map(|entry| self.read(entry)).transpose()
+    }
+
+    fn read(&self, entry: &EntryRef) -> Result<SecretEntry> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        ciborium::from_reader(io::BufReader::new(file.take(entry.len)))
+            .map_err(|e| VaultError::Serialization(e.to_string()))
+    }
+}
+
+pub(super) fn layout_error() -> VaultError {
+    VaultError::InvalidDataFormat("Unexpected layout in vault file".to_string())
+}
+
+/// Reads CBOR item by item, keeping track of the position in the file.
+pub(super) struct CborReader<R> {
+    inner: R,
+    position: u64,
+    /// Copy of the bytes read, while set.
+    capture: Option<Vec<u8>>,
+}
+
+impl<R: Read> CborReader<R> {
+    /// Reads from `inner`, which starts at `position` in the file.
+    pub fn new(inner: R, position: u64) -> Self {
+        Self {
+            inner,
+            position,
+            capture: None,
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.inner.read_exact(buf)?;
+        self.position += buf.len() as u64;
+        if let Some(capture) = self.capture.as_mut() {
+            capture.extend_from_slice(buf);
+        }
+        Ok(())
+    }
+
+    /// Reads an item head: its major type and argument, `None` for
+    /// indefinite lengths and the break code.
+    fn head(&mut self) -> Result<(u8, Option<u64>)> {
+        let mut initial = [0u8; 1];
+        self.read_exact(&mut initial)?;
+        let (major, info) = (initial[0] >> 5, initial[0] & 0x1f);
+        let width = match info {
+            0..=23 => return Ok((major, Some(info.into()))),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            31 => return Ok((major, None)),
+            _ => return Err(layout_error()),
+        };
+        let mut bytes = [0u8; 8];
+        self.read_exact(&mut bytes[8 - width..])?;
+        Ok((major, Some(u64::from_be_bytes(bytes))))
+    }
+
+    fn expect(&mut self, major: u8) -> Result<u64> {
+        match self.head()? {
+            (found, Some(arg)) if found == major => Ok(arg),
+            _ => Err(layout_error()),
+        }
+    }
+
+    fn skip_bytes(&mut self, mut len: u64) -> Result<()> {
+        let mut buf = [0u8; 4096];
+        while len > 0 {
+            let chunk = len.min(buf.len() as u64) as usize;
+            self.read_exact(&mut buf[..chunk])?;
+            len -= chunk as u64;
+        }
+        Ok(())
+    }
+
+    /// Skips one item, returning `false` at a break code instead.
+    fn skip_or_break(&mut self) -> Result<bool> {
+        match self.head()? {
+            (SIMPLE, None) => return Ok(false),
+            (UNSIGNED | 1 | SIMPLE, Some(_)) => {}
+            (2 | TEXT, Some(len)) => self.skip_bytes(len)?,
+            (ARRAY, Some(len)) => (0..len).try_for_each(|_| self.skip())?,
+            (MAP, Some(len)) => (0..len * 2).try_for_each(|_| self.skip())?,
+            (6, Some(_)) => self.skip()?,
+            (2..=MAP, None) => while self.skip_or_break()? {},
+            _ => return Err(layout_error()),
+        }
+        Ok(true)
+    }
+
+    /// Skips one item.
+    pub fn skip(&mut self) -> Result<()> {
+        if self.skip_or_break()? {
+            Ok(())
+        } else {
+            Err(layout_error())
+        }
+    }
+
+    pub fn text(&mut self) -> Result<String> {
+        let len = self.expect(TEXT)?;
+        let mut bytes = Vec::new();
+        (&mut self.inner).take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        self.position += len;
+        if let Some(capture) = self.capture.as_mut() {
+            capture.extend_from_slice(&bytes);
+        }
+        String::from_utf8(bytes).map_err(|_| layout_error())
+    }
+
+    pub fn map_len(&mut self) -> Result<u64> {
+        self.expect(MAP)
+    }
+
+    pub fn array_len(&mut self) -> Result<u64> {
+        self.expect(ARRAY)
+    }
+
+    /// Reads an array of entries, or `None` for `null`.
+    pub fn optional_entries(&mut self) -> Result<Option<Vec<EntryRef>>> {
+        match self.head()? {
+            (SIMPLE, Some(NULL)) => Ok(None),
+            (ARRAY, Some(len)) => (0..len)
+                .map(|_| self.entry())
+                .collect::<Result<_>>()
+                .map(Some),
+            _ => Err(layout_error()),
+        }
+    }
+
+    fn entries(&mut self) -> Result<Vec<EntryRef>> {
+        let len = self.array_len()?;
+        (0..len).map(|_| self.entry()).collect()
+    }
+
+    fn entry(&mut self) -> Result<EntryRef> {
+        let offset = self.position;
+        let mut version = None;
+        for _ in 0..self.map_len()? {
+            if self.text()? == "version" {
+                version = Some(self.expect(UNSIGNED)?);
+            } else {
+                self.skip()?;
+            }
+        }
+        let version = version
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(layout_error)?;
+        Ok(EntryRef {
+            version,
+            offset,
+            len: self.position - offset,
+        })
+    }
+}
+
+/// Walks a serialized [`VaultData`], returning its metadata with `secrets`
+/// left empty, and the index of its secrets.
+pub(super) fn index_vault<R: Read>(reader: &mut CborReader<R>) -> Result<(VaultData, SecretIndex)> {
+    let mut metadata = Vec::new();
+    let mut fields = 0;
+    let mut secrets = SecretIndex::new();
+    for _ in 0..reader.map_len()? {
+        reader.capture = Some(Vec::new());
+        if reader.text()? == "secrets" {
+            reader.capture = None;
+            for _ in 0..reader.map_len()? {
+                let name = reader.text()?;
+                secrets.insert(name, reader.entries()?);
+            }
+        } else {
+            reader.skip()?;
+            metadata.extend(reader.capture.take().unwrap_or_default());
+            fields += 1;
+        }
+    }
+
+    let mut bytes = Vec::with_capacity(metadata.len() + 9);
+    write_head(&mut bytes, MAP, fields)?;
+    bytes.extend(metadata);
+    let metadata =
+        ciborium::from_reader(&bytes[..]).map_err(|e| VaultError::Serialization(e.to_string()))?;
+    Ok((metadata, secrets))
+}
+
+/// The entries of one secret to write.
+pub(super) enum Source<'a> {
+    /// Entries in memory, to encode.
+    Entries(&'a [SecretEntry]),
+    /// Entries already stored in the indexed file, to copy.
+    Stored(&'a [EntryRef]),
+}
+
+/// A writer that tracks its position.
+pub(super) struct Output<W> {
+    inner: W,
+    position: u64,
+}
+
+impl<W: Write> Output<W> {
+    /// Writes to `inner`, which starts at `position` in the file.
+    pub fn new(inner: W, position: u64) -> Self {
+        Self { inner, position }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_head(out: &mut impl Write, major: u8, arg: u64) -> Result<()> {
+    let major = major << 5;
+    match arg {
+        0..=23 => out.write_all(&[major | arg as u8])?,
+        24..=0xff => out.write_all(&[major | 24, arg as u8])?,
+        0x100..=0xffff => {
+            out.write_all(&[major | 25])?;
+            out.write_all(&(arg as u16).to_be_bytes())?;
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.write_all(&[major | 26])?;
+            out.write_all(&(arg as u32).to_be_bytes())?;
+        }
+        _ => {
+            out.write_all(&[major | 27])?;
+            out.write_all(&arg.to_be_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn write_text(out: &mut impl Write, text: &str) -> Result<()> {
+    write_head(out, TEXT, text.len() as u64)?;
+    out.write_all(text.as_bytes())?;
+    Ok(())
+}
+
+/// Writes `metadata` with `secrets` as a serialized [`VaultData`], reading
+/// stored entries from `stored`. Returns the index of what was written.
+pub(super) fn write_vault<'a, W: Write>(
+    out: &mut Output<W>,
+    metadata: &VaultData,
+    secrets: &BTreeMap<&'a str, Source<'a>>,
+    stored: Option<&File>,
+) -> Result<SecretIndex> {
+    // Serialize the metadata as usual and copy it without its empty secrets.
+    let mut encoded = Vec::new();
+    ciborium::into_writer(metadata, &mut encoded)
+        .map_err(|e| VaultError::Serialization(e.to_string()))?;
+    let mut reader = CborReader::new(&encoded[..], 0);
+    let fields = reader.map_len()?;
+    write_head(out, MAP, fields)?;
+    for _ in 0..fields {
+        reader.capture = Some(Vec::new());
+        if reader.text()? == "secrets" {
+            reader.skip()?;
+        } else {
+            reader.skip()?;
+            out.write_all(&reader.capture.take().unwrap_or_default())?;
+        }
+    }
+
+    write_text(out, "secrets")?;
+    write_head(out, MAP, secrets.len() as u64)?;
+    let mut index = SecretIndex::new();
+    for (name, source) in secrets {
+        write_text(out, name)?;
+        let written = match source {
+            Source::Entries(entries) => {
+                write_head(out, ARRAY, entries.len() as u64)?;
+                let mut written = Vec::with_capacity(entries.len());
+                for entry in *entries {
+                    let offset = out.len();
+                    ciborium::into_writer(entry, &mut *out)
+                        .map_err(|e| VaultError::Serialization(e.to_string()))?;
+                    written.push(EntryRef {
+                        version: entry.version,
+                        offset,
+                        len: out.len() - offset,
+                    });
+                }
+                written
+            }
+            Source::Stored(entries) => {
+                let mut file = stored.ok_or_else(layout_error)?;
+                write_head(out, ARRAY, entries.len() as u64)?;
+                let mut written = Vec::with_capacity(entries.len());
+                for entry in *entries {
+                    let offset = out.len();
+                    file.seek(SeekFrom::Start(entry.offset))?;
+                    let copied = io::copy(&mut file.take(entry.len), out)?;
+                    if copied != entry.len {
+                        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+                    }
+                    written.push(EntryRef { offset, ..*entry });
+                }
+                written
+            }
+        };
+        index.insert(name.to_string(), written);
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::CipherKind;
+
+    fn entry(version: u32) -> SecretEntry {
+        SecretEntry {
+            encrypted_value: vec![version as u8; 300],
+            version,
+            created_at: chrono::Utc::now(),
+            aad_bound: true,
+            wrapped_dek: Some(vec![1; 60]),
+            shredded: false,
+            key_id: "key".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_index_matches_serde_layout() {
+        let mut data = VaultData {
+            generation: 7,
+            cipher: CipherKind::XChaCha20Poly1305,
+            vault_id: "vault".to_string(),
+            ..VaultData::default()
+        };
+        data.secrets
+            .insert("a".to_string(), (1..=30).map(entry).collect());
+        data.secrets.insert("b".to_string(), vec![entry(1)]);
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&data, &mut bytes).unwrap();
+
+        let (metadata, secrets) = index_vault(&mut CborReader::new(&bytes[..], 0)).unwrap();
+        assert_eq!(metadata.generation, 7);
+        assert_eq!(metadata.cipher, CipherKind::XChaCha20Poly1305);
+        assert!(metadata.secrets.is_empty());
+        assert_eq!(secrets["a"].len(), 30);
+        let located = secrets["a"][24];
+        assert_eq!(located.version, 25);
+        let range = located.offset as usize..(located.offset + located.len) as usize;
+        let decoded: SecretEntry = ciborium::from_reader(&bytes[range]).unwrap();
+        assert_eq!(decoded, data.secrets["a"][24]);
+
+        // Rewriting from the index gives the same bytes serde writes.
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("vault");
+        fs::write(&path, &bytes).unwrap();
+        let file = File::open(&path).unwrap();
+        let sources = secrets
+            .iter()
+            .map(|(name, entries)| (name.as_str(), Source::Stored(entries)))
+            .collect();
+        let mut rewritten = Vec::new();
+        let mut out = Output::new(&mut rewritten, 0);
+        write_vault(&mut out, &data.metadata(), &sources, Some(&file)).unwrap();
+        assert_eq!(rewritten, bytes);
+    }
+}
@@ -0,0 +1,1397 @@
+use super::atomic::{lock_file, read_if_exists, sidecar_path, write_atomic_with, write_or_remove};
+use super::index::{index_vault, layout_error, CborReader, EntryRef, Stamp};
+use super::sealed::{open_container, parse_header, seal_container, SealedHeader};
+use super::{entry_versions, select_entry, LockMode, StorageLock, VaultStorage};
+use crate::error::{Result, VaultError};
+use crate::keyring::Keyring;
+use crate::vault::{SecretEntry, VaultData};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use zeroize::Zeroizing;
+
+/// Magic bytes opening a log-structured vault file.
+pub(super) const LOG_MAGIC: &[u8] = b"RMSVLOG1";
+
+/// Each record is framed by its payload length, the payload's CRC-32 and the
+/// CRC-32 of those two, all little-endian `u32`s. The last one tells a
+/// damaged length from a record cut short by a crash.
+const FRAME_HEADER_LEN: usize = 12;
+
+/// Snapshots store their secrets in records of about this many bytes, so
+/// that reading one entry in index-only mode decrypts little else.
+const SNAPSHOT_CHUNK: usize = 64 * 1024;
+
+/// A changed secret's name and its new entries, `None` once deleted.
+type Change<'a> = (Cow<'a, str>, Option<Cow<'a, [SecretEntry]>>);
+
+/// One record of the log, encoded as CBOR and sealed.
+#[derive(Serialize, Deserialize)]
+enum Record<'a> {
+    /// The vault metadata, as written by the first save and by compaction.
+    /// The secrets follow in [`Record::Secrets`] records.
+    Snapshot(Cow<'a, VaultData>),
+    /// One save: the vault metadata after it, and the new entries of every
+    /// secret it changed, or `None` for deleted secrets.
+    Commit {
+        metadata: VaultData,
+        changes: Vec<Change<'a>>,
+    },
+    /// Some of the secrets of the preceding snapshot.
+    Secrets(Vec<Change<'a>>),
+}
+
+/// Where one stored entry is: the record holding it, and the entry's place
+/// in the record's plaintext.
+#[derive(Debug, Clone, Copy)]
+struct Located {
+    record: u64,
+    entry: EntryRef,
+}
+
+/// Entry locations by secret name, oldest version first.
+type LogIndexSecrets = BTreeMap<String, Vec<Located>>;
+
+/// A [`Record`] walked for the index rather than decoded.
+struct IndexedRecord {
+    /// Whether the record starts a snapshot, replacing everything before it.
+    snapshot: bool,
+    metadata: Option<VaultData>,
+    changes: Vec<(String, Option<Vec<EntryRef>>)>,
+}
+
+impl IndexedRecord {
+    /// Applies the record at `offset` to `metadata` and `secrets`.
+    fn apply(
+        self,
+        offset: u64,
+        metadata: &mut Option<VaultData>,
+        secrets: &mut LogIndexSecrets,
+    ) -> Result<()> {
+        if self.snapshot {
+            secrets.clear();
+        }
+        match self.metadata {
+            Some(next) => *metadata = Some(next),
+            None if metadata.is_none() => return Err(layout_error()),
+            None => {}
+        }
+        for (name, entries) in self.changes {
+            match entries {
+                Some(entries) => secrets.insert(
+                    name,
+                    entries
+                        .into_iter()
+                        .map(|entry| Located {
+                            record: offset,
+                            entry,
+                        })
+                        .collect(),
+                ),
+                None => secrets.remove(&name),
+            };
+        }
+        Ok(())
+    }
+}
+
+/// The index of a log, and the file it was built from.
+///
+/// Entries are read through `file`, so they stay consistent with the index
+/// even if the file is replaced in the meantime.
+struct LogIndex {
+    file: File,
+    stamp: Stamp,
+    metadata: VaultData,
+    secrets: LogIndexSecrets,
+}
+
+impl LogIndex {
+    /// Returns the approximate heap bytes the index occupies.
+    fn size(&self) -> usize {
+        self.secrets
+            .iter()
+            .map(|(name, entries)| {
+                // Map nodes cost roughly a name, a vector and two pointers.
+                name.len() + 4 * size_of::<usize>() + entries.len() * size_of::<Located>()
+            })
+            .sum()
+    }
+
+    /// Reads every stored entry of `name`.
+    fn read_secret(&self, keyring: &Keyring, name: &str) -> Result<Option<Vec<SecretEntry>>> {
+        self.secrets
+            .get(name)
+            .map(|entries| self.read(keyring, entries.iter()))
+            .transpose()
+    }
+
+    /// Reads one stored entry of `name`: `version`, or the latest with `None`.
+    fn read_entry(
+        &self,
+        keyring: &Keyring,
+        name: &str,
+        version: Option<u32>,
+    ) -> Result<Option<SecretEntry>> {
+        let Some(entries) = self.secrets.get(name) else {
+            return Ok(None);
+        };
+        let entry = match version {
+            Some(version) => entries.iter().find(|entry| entry.entry.version == version),
+            None => entries.last(),
+        };
+        Ok(self.read(keyring, entry)?.pop())
+    }
+
+    /// Reads `entries`, opening each record they are in once.
+    fn read<'a>(
+        &self,
+        keyring: &Keyring,
+        entries: impl IntoIterator<Item = &'a Located>,
+    ) -> Result<Vec<SecretEntry>> {
+        let mut record: Option<(u64, Zeroizing<Vec<u8>>)> = None;
+        let mut read = Vec::new();
+        for located in entries {
+            let plaintext = match &record {
+                Some((offset, plaintext)) if *offset == located.record => plaintext,
+                _ => {
+                    let payload = read_record(&self.file, located.record, self.stamp.len())?
+                        .ok_or_else(|| corrupt_record(located.record))?;
+                    let (_, plaintext) = open_record(keyring, &payload, located.record)?;
+                    &record.insert((located.record, plaintext)).1
+                }
+            };
+            let bytes = usize::try_from(located.entry.offset)
+                .ok()
+                .zip(usize::try_from(located.entry.offset + located.entry.len).ok())
+                .and_then(|(start, end)| plaintext.get(start..end))
+                .ok_or_else(layout_error)?;
+            read.push(
+                ciborium::from_reader(bytes)
+                    .map_err(|e| VaultError::Serialization(e.to_string()))?,
+            );
+        }
+        Ok(read)
+    }
+}
+
+/// An append-only vault file whose records are sealed under the master key.
+///
+/// The first save writes a snapshot of the vault; every later save appends a
+/// single record holding only the secrets it changed, so a write costs the
+/// size of the change rather than the size of the vault. Loading replays the
+/// records into the vault, and [`VaultStorage::compact`] folds them back
+/// into one snapshot.
+///
+/// Each record is sealed like a [`SealedStorage`](super::SealedStorage)
+/// container, under a key derived from the active master key and bound to
+/// the record's position, and framed by its length and a CRC-32. Secret
+/// names and metadata are only readable once the backend is
+/// [unlocked](VaultStorage::unlock); until then [`VaultStorage::load`]
+/// returns just the cipher and KDF parameters. Sidecar records are sealed
+/// the same way. A save that finds records sealed under a retired key
+/// compacts the log, so that old keys are not needed to read it.
+///
+/// A record cut short by a crash is detected by its length or checksum and
+/// discarded, together with the save it belonged to; the next save
+/// overwrites it. A damaged record followed by further records is reported
+/// as corruption instead.
+///
+/// With [`LogStorage::index_only`] the log is replayed into an index of
+/// where each secret's current entries are, rather than into memory.
+pub struct LogStorage {
+    path: PathBuf,
+    index_only: bool,
+    /// The keys records are opened with.
+    keyring: Mutex<Option<Keyring>>,
+    /// Where the last read or write left the file, so that a save does not
+    /// have to replay the log to learn the current generation.
+    tail: Mutex<Option<Tail>>,
+    /// The index of the log in index-only mode.
+    index: Mutex<Option<LogIndex>>,
+}
+
+#[derive(Debug, Clone)]
+struct Tail {
+    /// The state of the file when this was recorded.
+    stamp: Stamp,
+    /// Length of the intact records; anything beyond is a torn record.
+    valid_len: u64,
+    generation: u64,
+    /// IDs of the master keys the intact records are sealed under.
+    key_ids: BTreeSet<String>,
+}
+
+/// The result of replaying a log.
+struct Replay {
+    data: Option<VaultData>,
+    valid_len: u64,
+    key_ids: BTreeSet<String>,
+}
+
+/// The result of indexing a log.
+struct Scan {
+    index: Option<LogIndex>,
+    valid_len: u64,
+    commits: usize,
+    key_ids: BTreeSet<String>,
+}
+
+impl LogStorage {
+    /// Creates a backend for the log-structured vault file at `path`.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            index_only: false,
+            keyring: Mutex::new(None),
+            tail: Mutex::new(None),
+            index: Mutex::new(None),
+        }
+    }
+
+    /// Replays the log into an index of where each entry is stored, reading
+    /// entries from the file when they are asked for. Since saves only
+    /// append, the vault never has to fit in memory.
+    pub fn index_only(mut self, enabled: bool) -> Self {
+        self.index_only = enabled;
+        self
+    }
+
+    /// Returns the path of the vault file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether the backend holds the keys to open the records.
+    pub fn is_unlocked(&self) -> bool {
+        self.keyring().is_some()
+    }
+
+    fn keyring(&self) -> Option<Keyring> {
+        self.keyring
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    fn unlocked(&self) -> Result<Keyring> {
+        self.keyring().ok_or_else(|| {
+            VaultError::Storage("Log vault is locked; unlock it with its master key".to_string())
+        })
+    }
+
+    fn cached_tail(&self) -> MutexGuard<'_, Option<Tail>> {
+        self.tail
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn cached_index(&self) -> MutexGuard<'_, Option<LogIndex>> {
+        self.index
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Opens the log file and checks its magic bytes.
+    fn open_file(&self) -> Result<Option<File>> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut magic = [0u8; LOG_MAGIC.len()];
+        if file.read_exact(&mut magic).is_err() || magic != LOG_MAGIC {
+            return Err(VaultError::InvalidDataFormat(
+                "Not a log-structured vault file".to_string(),
+            ));
+        }
+        Ok(Some(file))
+    }
+
+    /// Reads the plaintext header of the last intact record, which is all a
+    /// locked log reveals.
+    fn read_header(&self) -> Result<Option<VaultData>> {
+        let Some(file) = self.open_file()? else {
+            return Ok(None);
+        };
+        let len = file.metadata()?.len();
+        let mut offset = LOG_MAGIC.len() as u64;
+        let mut last = None;
+        while let Some(payload) = read_record(&file, offset, len)? {
+            offset += (FRAME_HEADER_LEN + payload.len()) as u64;
+            last = Some(payload);
+        }
+        let Some(last) = last else {
+            return Ok(None);
+        };
+        let (header, _) = parse_header(b"", &last)?;
+        Ok(Some(VaultData {
+            cipher: header.cipher,
+            kdf: header.kdf,
+            ..VaultData::default()
+        }))
+    }
+
+    /// Reads and replays the whole log.
+    fn replay(&self, keyring: &Keyring) -> Result<Option<Replay>> {
+        let Some(mut file) = self.open_file()? else {
+            return Ok(None);
+        };
+        let stamp = Stamp::of(&file.metadata()?);
+        let mut bytes = LOG_MAGIC.to_vec();
+        file.read_to_end(&mut bytes)?;
+        let replay = replay(&bytes, keyring)?;
+        *self.cached_tail() = Some(Tail {
+            stamp,
+            valid_len: replay.valid_len,
+            generation: replay.data.as_ref().map_or(0, |data| data.generation),
+            key_ids: replay.key_ids.clone(),
+        });
+        Ok(Some(replay))
+    }
+
+    /// Walks the whole log, indexing the current entries of every secret.
+    fn scan(&self) -> Result<Option<Scan>> {
+        let keyring = self.unlocked()?;
+        let Some(file) = self.open_file()? else {
+            return Ok(None);
+        };
+        let stamp = Stamp::of(&file.metadata()?);
+
+        let mut metadata = None;
+        let mut secrets = LogIndexSecrets::new();
+        let mut key_ids = BTreeSet::new();
+        let mut offset = LOG_MAGIC.len() as u64;
+        let mut commits = 0;
+        while let Some(payload) = read_record(&file, offset, stamp.len())? {
+            let (header, plaintext) = open_record(&keyring, &payload, offset)?;
+            key_ids.insert(header.key_id);
+            let record = index_record(&mut CborReader::new(&plaintext[..], 0))?;
+            commits += usize::from(!record.snapshot && record.metadata.is_some());
+            record.apply(offset, &mut metadata, &mut secrets)?;
+            offset += (FRAME_HEADER_LEN + payload.len()) as u64;
+        }
+
+        *self.cached_tail() = Some(Tail {
+            stamp,
+            valid_len: offset,
+            generation: metadata.as_ref().map_or(0, |data| data.generation),
+            key_ids: key_ids.clone(),
+        });
+        Ok(Some(Scan {
+            index: metadata.map(|metadata| LogIndex {
+                file,
+                stamp,
+                metadata,
+                secrets,
+            }),
+            valid_len: offset,
+            commits,
+            key_ids,
+        }))
+    }
+
+    /// Returns the index of the log, rebuilding it if another writer changed
+    /// the file.
+    fn index(&self) -> Result<MutexGuard<'_, Option<LogIndex>>> {
+        let mut index = self.cached_index();
+        let stamp = Stamp::of_path(&self.path)?;
+        if index.as_ref().map(|index| index.stamp) != stamp {
+            *index = None;
+            if stamp.is_some() {
+                *index = self.scan()?.and_then(|scan| scan.index);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Returns the index built when the vault was loaded, for reading the
+    /// entries that belong to that load.
+    ///
+    /// # Errors
+    /// Returns `VaultError::ConcurrentModification` if another writer
+    /// appended since, because its entries would not match the loaded
+    /// metadata.
+    fn loaded_index(&self) -> Result<MutexGuard<'_, Option<LogIndex>>> {
+        let index = self.cached_index();
+        let Some((stamp, expected)) = index
+            .as_ref()
+            .map(|index| (index.stamp, index.metadata.generation))
+        else {
+            drop(index);
+            return self.index();
+        };
+        let current = Stamp::of_path(&self.path)?;
+        if current != Some(stamp) {
+            let found = match current {
+                Some(_) => self
+                    .scan()?
+                    .and_then(|scan| scan.index)
+                    .map_or(0, |index| index.metadata.generation),
+                None => 0,
+            };
+            return Err(VaultError::ConcurrentModification { expected, found });
+        }
+        Ok(index)
+    }
+
+    /// Returns the end of the log, reading it again only if another writer
+    /// has changed the file since this backend last looked.
+    fn tail(&self) -> Result<Option<Tail>> {
+        let Some(stamp) = Stamp::of_path(&self.path)? else {
+            return Ok(None);
+        };
+        if let Some(tail) = self.cached_tail().as_ref() {
+            if tail.stamp == stamp {
+                return Ok(Some(tail.clone()));
+            }
+        }
+        if self.index_only {
+            drop(self.index()?);
+        } else {
+            self.replay(&self.unlocked()?)?;
+        }
+        Ok(self.cached_tail().clone())
+    }
+
+    /// Appends `frame`, the sealed `plaintext`, after the intact records.
+    ///
+    /// # Errors
+    /// Returns `VaultError::InvalidDataFormat` if the log ends in a torn
+    /// record, which only [`VaultStorage::repair`] cuts off.
+    fn append(
+        &self,
+        tail: &Tail,
+        frame: &[u8],
+        plaintext: &[u8],
+        generation: u64,
+        key_id: &str,
+    ) -> Result<()> {
+        if tail.stamp.len() != tail.valid_len {
+            return Err(VaultError::InvalidDataFormat(format!(
+                "Log vault ends in a torn record at byte {}; run `vault fsck --repair` to cut it off",
+                tail.valid_len
+            )));
+        }
+        let mut file = OpenOptions::new().write(true).open(&self.path)?;
+        file.seek(SeekFrom::Start(tail.valid_len))?;
+        file.write_all(frame)?;
+        file.sync_data()?;
+
+        let stamp = Stamp::of(&file.metadata()?);
+        let mut key_ids = tail.key_ids.clone();
+        key_ids.insert(key_id.to_string());
+        *self.cached_tail() = Some(Tail {
+            stamp,
+            valid_len: stamp.len(),
+            generation,
+            key_ids,
+        });
+
+        // Bring the index up to date with the record just written.
+        let mut index = self.cached_index();
+        if let Some(current) = index.as_mut().filter(|index| index.stamp == tail.stamp) {
+            let record = index_record(&mut CborReader::new(plaintext, 0))?;
+            let mut metadata = Some(current.metadata.clone());
+            record.apply(tail.valid_len, &mut metadata, &mut current.secrets)?;
+            current.metadata = metadata.unwrap_or_default();
+            current.stamp = stamp;
+        } else {
+            *index = None;
+        }
+        Ok(())
+    }
+
+    /// Compacts the log under a lock the caller holds, rewriting it as one
+    /// snapshot sealed under the active key. The current entries are read
+    /// one record at a time.
+    fn compact_locked(&self, keyring: &Keyring) -> Result<bool> {
+        let Some(Scan {
+            index: Some(index),
+            valid_len,
+            commits,
+            key_ids,
+        }) = self.scan()?
+        else {
+            return Ok(false);
+        };
+        let compacted = commits == 0
+            && valid_len == index.stamp.len()
+            && key_ids.iter().all(|id| id == keyring.active_id());
+        if compacted {
+            return Ok(false);
+        }
+        write_atomic_with(&self.path, |file| {
+            let mut writer = SnapshotWriter::new(BufWriter::new(file), keyring, &index.metadata)?;
+            for (name, entries) in &index.secrets {
+                writer.push(name, index.read(keyring, entries)?)?;
+            }
+            writer.finish()
+        })?;
+        self.invalidate();
+        Ok(true)
+    }
+
+    fn invalidate(&self) {
+        *self.cached_tail() = None;
+        *self.cached_index() = None;
+    }
+}
+
+impl fmt::Debug for LogStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogStorage")
+            .field("path", &self.path)
+            .field("index_only", &self.index_only)
+            .field("unlocked", &self.is_unlocked())
+            .finish()
+    }
+}
+
+fn frame_header(len: u64, crc: u32) -> Result<[u8; FRAME_HEADER_LEN]> {
+    let len =
+        u32::try_from(len).map_err(|_| VaultError::Storage("Log record too large".to_string()))?;
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    header[..4].copy_from_slice(&len.to_le_bytes());
+    header[4..8].copy_from_slice(&crc.to_le_bytes());
+    let header_crc = crc32fast::hash(&header[..8]);
+    header[8..].copy_from_slice(&header_crc.to_le_bytes());
+    Ok(header)
+}
+
+/// Checks the frame header at `offset`, with `remaining` bytes from there
+/// to the end of the file, returning the payload's length and CRC-32, or
+/// `None` at the end of the log or at a record cut short at the end of the
+/// file.
+///
+/// A damaged header is an error wherever it is, so that it cannot pass for
+/// a torn record and hide the records after it.
+fn parse_frame_header(header: &[u8], offset: u64, remaining: u64) -> Result<Option<(u64, u32)>> {
+    if remaining < FRAME_HEADER_LEN as u64 {
+        return Ok(None);
+    }
+    let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().expect("4 bytes"));
+    if crc32fast::hash(&header[..8]) != field(8) {
+        return Err(corrupt_record(offset));
+    }
+    let len = u64::from(field(0));
+    if FRAME_HEADER_LEN as u64 + len > remaining {
+        return Ok(None);
+    }
+    Ok(Some((len, field(4))))
+}
+
+/// Binds a record to its position, so that records cannot be reordered or
+/// moved between places in the log.
+fn record_label(offset: u64) -> String {
+    format!("log record at {}", offset)
+}
+
+fn encode(record: &Record<'_>) -> Result<Zeroizing<Vec<u8>>> {
+    let mut plaintext = Zeroizing::new(Vec::new());
+    ciborium::into_writer(record, &mut *plaintext)
+        .map_err(|e| VaultError::Serialization(e.to_string()))?;
+    Ok(plaintext)
+}
+
+/// Seals `plaintext` as the record at `offset` under the active key, with
+/// the cipher and KDF parameters of `data`, and frames it.
+fn seal_frame(
+    keyring: &Keyring,
+    data: &VaultData,
+    plaintext: &[u8],
+    offset: u64,
+) -> Result<Vec<u8>> {
+    let payload = seal_container(
+        b"",
+        keyring,
+        data.cipher,
+        data.kdf.clone(),
+        plaintext,
+        &record_label(offset),
+    )?;
+    let mut frame = frame_header(payload.len() as u64, crc32fast::hash(&payload))?.to_vec();
+    frame.extend(payload);
+    Ok(frame)
+}
+
+fn open_record(
+    keyring: &Keyring,
+    payload: &[u8],
+    offset: u64,
+) -> Result<(SealedHeader, Zeroizing<Vec<u8>>)> {
+    let (header, plaintext) = open_container(b"", keyring, payload, &record_label(offset))?;
+    Ok((header, Zeroizing::new(plaintext)))
+}
+
+/// Writes a log holding one snapshot: a [`Record::Snapshot`] with the
+/// metadata, then the secrets in [`Record::Secrets`] records of about
+/// [`SNAPSHOT_CHUNK`] bytes.
+struct SnapshotWriter<'a, W> {
+    out: W,
+    offset: u64,
+    keyring: &'a Keyring,
+    metadata: &'a VaultData,
+    chunk: Vec<(String, Vec<SecretEntry>)>,
+    chunk_len: usize,
+}
+
+impl<'a, W: Write> SnapshotWriter<'a, W> {
+    fn new(mut out: W, keyring: &'a Keyring, metadata: &'a VaultData) -> Result<Self> {
+        out.write_all(LOG_MAGIC)?;
+        let mut writer = Self {
+            out,
+            offset: LOG_MAGIC.len() as u64,
+            keyring,
+            metadata,
+            chunk: Vec::new(),
+            chunk_len: 0,
+        };
+        let snapshot = Record::Snapshot(Cow::Owned(metadata.metadata()));
+        writer.write(&encode(&snapshot)?)?;
+        Ok(writer)
+    }
+
+    fn write(&mut self, plaintext: &[u8]) -> Result<()> {
+        let frame = seal_frame(self.keyring, self.metadata, plaintext, self.offset)?;
+        self.out.write_all(&frame)?;
+        self.offset += frame.len() as u64;
+        Ok(())
+    }
+
+    fn push(&mut self, name: &str, entries: Vec<SecretEntry>) -> Result<()> {
+        self.chunk_len += name.len()
+            + entries
+                .iter()
+                .map(|entry| {
+                    // Roughly the ciphertexts plus the fixed fields.
+                    entry.encrypted_value.len()
+                        + entry.wrapped_dek.as_ref().map_or(0, Vec::len)
+                        + entry.key_id.len()
+                        + 64
+                })
+                .sum::<usize>();
+        self.chunk.push((name.to_string(), entries));
+        if self.chunk_len >= SNAPSHOT_CHUNK {
+            self.flush_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn flush_chunk(&mut self) -> Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let record = Record::Secrets(
+            self.chunk
+                .iter()
+                .map(|(name, entries)| {
+                    (
+                        Cow::Borrowed(name.as_str()),
+                        Some(Cow::Borrowed(entries.as_slice())),
+                    )
+                })
+                .collect(),
+        );
+        let plaintext = encode(&record)?;
+        self.write(&plaintext)?;
+        self.chunk.clear();
+        self.chunk_len = 0;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.flush_chunk()?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+fn replay(bytes: &[u8], keyring: &Keyring) -> Result<Replay> {
+    if !bytes.starts_with(LOG_MAGIC) {
+        return Err(VaultError::InvalidDataFormat(
+            "Not a log-structured vault file".to_string(),
+        ));
+    }
+
+    let mut data: Option<VaultData> = None;
+    let mut key_ids = BTreeSet::new();
+    let mut offset = LOG_MAGIC.len();
+    while let Some(payload) = next_record(bytes, offset)? {
+        let (header, plaintext) = open_record(keyring, payload, offset as u64)?;
+        key_ids.insert(header.key_id);
+        let record: Record<'_> = ciborium::from_reader(&plaintext[..])
+            .map_err(|e| VaultError::Serialization(e.to_string()))?;
+        let (mut next, changes) = match record {
+            Record::Snapshot(snapshot) => (snapshot.into_owned(), Vec::new()),
+            Record::Commit { metadata, changes } => (
+                VaultData {
+                    secrets: data.map(|data| data.secrets).unwrap_or_default(),
+                    ..metadata
+                },
+                changes,
+            ),
+            Record::Secrets(changes) => (data.ok_or_else(layout_error)?, changes),
+        };
+        for (name, entries) in changes {
+            match entries {
+                Some(entries) => next.secrets.insert(name.into_owned(), entries.into_owned()),
+                None => next.secrets.remove(name.as_ref()),
+            };
+        }
+        data = Some(next);
+        offset += FRAME_HEADER_LEN + payload.len();
+    }
+
+    Ok(Replay {
+        data,
+        valid_len: offset as u64,
+        key_ids,
+    })
+}
+
+/// Returns the payload of the record at `offset`, or `None` at the end of
+/// the log or at a torn final record.
+fn next_record(bytes: &[u8], offset: usize) -> Result<Option<&[u8]>> {
+    let rest = &bytes[offset..];
+    let Some((len, crc)) = parse_frame_header(rest, offset as u64, rest.len() as u64)? else {
+        return Ok(None);
+    };
+    let payload = &rest[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len as usize];
+    if crc32fast::hash(payload) == crc {
+        return Ok(Some(payload));
+    }
+    if FRAME_HEADER_LEN + payload.len() == rest.len() {
+        return Ok(None);
+    }
+    Err(corrupt_record(offset as u64))
+}
+
+/// Like [`next_record`], reading the record at `offset` from the file.
+fn read_record(mut file: &File, offset: u64, file_len: u64) -> Result<Option<Vec<u8>>> {
+    let remaining = file_len.saturating_sub(offset);
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    if remaining >= FRAME_HEADER_LEN as u64 {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+    }
+    let Some((len, crc)) = parse_frame_header(&header, offset, remaining)? else {
+        return Ok(None);
+    };
+    let start = offset + FRAME_HEADER_LEN as u64;
+
+    let mut payload = vec![0u8; len as usize];
+    file.read_exact(&mut payload)?;
+    if crc32fast::hash(&payload) != crc {
+        if start + len == file_len {
+            return Ok(None);
+        }
+        return Err(corrupt_record(offset));
+    }
+    Ok(Some(payload))
+}
+
+fn corrupt_record(offset: u64) -> VaultError {
+    VaultError::InvalidDataFormat(format!("Log record at byte {} is corrupt", offset))
+}
+
+/// Walks the changes of a [`Record::Commit`] or [`Record::Secrets`].
+fn index_changes<R: Read>(
+    reader: &mut CborReader<R>,
+) -> Result<Vec<(String, Option<Vec<EntryRef>>)>> {
+    let mut changes = Vec::new();
+    for _ in 0..reader.array_len()? {
+        if reader.array_len()? != 2 {
+            return Err(layout_error());
+        }
+        let name = reader.text()?;
+        changes.push((name, reader.optional_entries()?));
+    }
+    Ok(changes)
+}
+
+/// Walks one serialized [`Record`].
+fn index_record<R: Read>(reader: &mut CborReader<R>) -> Result<IndexedRecord> {
+    if reader.map_len()? != 1 {
+        return Err(layout_error());
+    }
+    match reader.text()?.as_str() {
+        "Snapshot" => {
+            let (metadata, secrets) = index_vault(reader)?;
+            Ok(IndexedRecord {
+                snapshot: true,
+                metadata: Some(metadata),
+                changes: secrets
+                    .into_iter()
+                    .map(|(name, entries)| (name, Some(entries)))
+                    .collect(),
+            })
+        }
+        "Commit" => {
+            let mut metadata = None;
+            let mut changes = Vec::new();
+            for _ in 0..reader.map_len()? {
+                match reader.text()?.as_str() {
+                    "metadata" => metadata = Some(index_vault(reader)?.0),
+                    "changes" => changes = index_changes(reader)?,
+                    _ => reader.skip()?,
+                }
+            }
+            let metadata = metadata.ok_or_else(layout_error)?;
+            Ok(IndexedRecord {
+                snapshot: false,
+                metadata: Some(metadata),
+                changes,
+            })
+        }
+        "Secrets" => Ok(IndexedRecord {
+            snapshot: false,
+            metadata: None,
+            changes: index_changes(reader)?,
+        }),
+        _ => Err(layout_error()),
+    }
+}
+
+impl VaultStorage for LogStorage {
+    fn load(&self) -> Result<Option<VaultData>> {
+        let Some(keyring) = self.keyring() else {
+            return self.read_header();
+        };
+        if self.index_only {
+            return Ok(self.index()?.as_ref().map(|index| index.metadata.clone()));
+        }
+        Ok(self.replay(&keyring)?.and_then(|replay| replay.data))
+    }
+
+    /// Writes `data` as a single snapshot, replacing the log.
+    fn store(&self, data: &VaultData) -> Result<()> {
+        let keyring = self.unlocked()?;
+        write_atomic_with(&self.path, |file| {
+            let mut writer = SnapshotWriter::new(BufWriter::new(file), &keyring, data)?;
+            for (name, entries) in &data.secrets {
+                writer.push(name, entries.clone())?;
+            }
+            writer.finish()
+        })?;
+        self.invalidate();
+        Ok(())
+    }
+
+    fn lock(&self, mode: LockMode) -> Result<StorageLock> {
+        lock_file(&self.path, mode)
+    }
+
+    fn generations(&self) -> Result<Vec<u64>> {
+        Ok(self
+            .tail()?
+            .map(|tail| tail.generation)
+            .into_iter()
+            .collect())
+    }
+
+    fn current_generation(&self) -> Result<u64> {
+        Ok(self.tail()?.map_or(0, |tail| tail.generation))
+    }
+
+    fn load_sidecar(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let Some(bytes) = read_if_exists(&sidecar_path(&self.path, name))? else {
+            return Ok(None);
+        };
+        let (_, plaintext) = open_container(b"", &self.unlocked()?, &bytes, name)?;
+        Ok(Some(plaintext))
+    }
+
+    fn store_sidecar(&self, name: &str, bytes: Option<&[u8]>) -> Result<()> {
+        let sealed = match bytes {
+            Some(bytes) => Some(seal_container(
+                b"",
+                &self.unlocked()?,
+                Default::default(),
+                None,
+                bytes,
+                name,
+            )?),
+            None => None,
+        };
+        write_or_remove(&sidecar_path(&self.path, name), sealed.as_deref())
+    }
+
+    fn is_sealed(&self) -> bool {
+        true
+    }
+
+    fn unlock(&self, keyring: &Keyring) -> Result<()> {
+        *self
+            .keyring
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(keyring.clone());
+        Ok(())
+    }
+
+    fn is_lazy(&self) -> bool {
+        self.index_only
+    }
+
+    fn secret_names(&self) -> Result<Vec<String>> {
+        if !self.index_only || !self.is_unlocked() {
+            return Ok(self
+                .load()?
+                .map(|data| data.secrets.into_keys().collect())
+                .unwrap_or_default());
+        }
+        Ok(self
+            .index()?
+            .iter()
+            .flat_map(|index| index.secrets.keys().cloned())
+            .collect())
+    }
+
+    fn load_secret(&self, name: &str) -> Result<Option<Vec<SecretEntry>>> {
+        if !self.index_only {
+            return Ok(self.load()?.and_then(|mut data| data.secrets.remove(name)));
+        }
+        let keyring = self.unlocked()?;
+        match self.loaded_index()?.as_ref() {
+            Some(index) => index.read_secret(&keyring, name),
+            None => Ok(None),
+        }
+    }
+
+    fn load_entry(&self, name: &str, version: Option<u32>) -> Result<Option<SecretEntry>> {
+        if !self.index_only {
+            return Ok(select_entry(self.load_secret(name)?, version));
+        }
+        let keyring = self.unlocked()?;
+        match self.loaded_index()?.as_ref() {
+            Some(index) => index.read_entry(&keyring, name, version),
+            None => Ok(None),
+        }
+    }
+
+    fn entry_versions(&self, name: &str) -> Result<Vec<u32>> {
+        if !self.index_only {
+            return Ok(entry_versions(self.load_secret(name)?));
+        }
+        Ok(self
+            .loaded_index()?
+            .iter()
+            .flat_map(|index| index.secrets.get(name))
+            .flatten()
+            .map(|located| located.entry.version)
+            .collect())
+    }
+
+    fn index_size(&self) -> usize {
+        self.cached_index().as_ref().map_or(0, LogIndex::size)
+    }
+
+    /// Appends one record with the changed secrets, then compacts the log
+    /// if older records are sealed under another key.
+    fn save_changes(
+        &self,
+        data: &mut VaultData,
+        changes: &[(&str, Option<&[SecretEntry]>)],
+    ) -> Result<()> {
+        let keyring = self.unlocked()?;
+        let _lock = self.lock(LockMode::Exclusive)?;
+        let tail = self.tail()?;
+        let found = tail.as_ref().map_or(0, |tail| tail.generation);
+        if found != data.generation {
+            return Err(VaultError::ConcurrentModification {
+                expected: data.generation,
+                found,
+            });
+        }
+
+        let next = data.generation + 1;
+        match tail {
+            Some(tail) => {
+                let metadata = VaultData {
+                    generation: next,
+                    ..data.metadata()
+                };
+                let plaintext = encode(&Record::Commit {
+                    metadata: metadata.clone(),
+                    changes: changes
+                        .iter()
+                        .map(|(name, entries)| (Cow::Borrowed(*name), entries.map(Cow::Borrowed)))
+                        .collect(),
+                })?;
+                let frame = seal_frame(&keyring, &metadata, &plaintext, tail.valid_len)?;
+                self.append(&tail, &frame, &plaintext, next, keyring.active_id())?;
+                if tail.key_ids.iter().any(|id| id != keyring.active_id()) {
+                    self.compact_locked(&keyring)?;
+                }
+            }
+            None => {
+                let snapshot = VaultData {
+                    generation: next,
+                    ..data.clone()
+                };
+                self.store(&snapshot)?;
+            }
+        }
+        data.generation = next;
+        Ok(())
+    }
+
+    /// Folds the log into a single snapshot sealed under the active key.
+    fn compact(&self) -> Result<bool> {
+        let keyring = self.unlocked()?;
+        let _lock = self.lock(LockMode::Exclusive)?;
+        self.compact_locked(&keyring)
+    }
+
+    /// Cuts off a torn final record, left by a crash in the middle of an
+    /// append. Damage anywhere else is an error, not something to cut off.
+    fn repair(&self) -> Result<u64> {
+        let _lock = self.lock(LockMode::Exclusive)?;
+        self.invalidate();
+        let Some(tail) = self.tail()? else {
+            return Ok(0);
+        };
+        let torn = tail.stamp.len() - tail.valid_len;
+        if torn > 0 {
+            let file = OpenOptions::new().write(true).open(&self.path)?;
+            file.set_len(tail.valid_len)?;
+            file.sync_data()?;
+            self.invalidate();
+        }
+        Ok(torn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageFormat;
+    use crate::vault::{KeySource, MasterKey, SecretVault};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn open(path: &Path) -> SecretVault {
+        SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .storage(LogStorage::new(path))
+            .build()
+            .unwrap()
+    }
+
+    fn open_indexed(path: &Path) -> SecretVault {
+        SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .storage(LogStorage::new(path).index_only(true))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_log_appends_and_replays() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("vault.rmsl");
+
+        let mut vault = open(&path);
+        for i in 0..20 {
+            vault.set(&format!("key_{}", i), b"value").unwrap();
+        }
+        let before = fs::read(&path).unwrap();
+        vault.set("key_3", b"updated").unwrap();
+        vault.delete("key_4").unwrap();
+        let after = fs::read(&path).unwrap();
+
+        // Saves only ever append.
+        assert!(after.starts_with(&before));
+        assert!(after.len() - before.len() < 2048);
+        assert_eq!(StorageFormat::detect(&path).unwrap(), StorageFormat::Log);
+
+        let reopened = open(&path);
+        assert_eq!(reopened.generation(), 22);
+        assert_eq!(reopened.list_keys().len(), 19);
+        assert_eq!(reopened.get("key_3").unwrap().unwrap(), b"updated");
+        assert_eq!(reopened.get_version("key_3", 1).unwrap().unwrap(), b"value");
+
+        // Another handle's writes are picked up by the generation check.
+        let mut other = open(&path);
+        vault.set("a", b"1").unwrap();
+        other.set("b", b"2").unwrap();
+        assert_eq!(open(&path).list_keys().len(), 21);
+    }
+
+    #[test]
+    fn test_torn_record_is_discarded() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("vault.rmsl");
+
+        let mut vault = open(&path);
+        vault.set("a", b"1").unwrap();
+        vault.set("b", b"2").unwrap();
+        let intact = fs::read(&path).unwrap();
+
+        // A crash in the middle of the next append.
+        vault.set("c", b"3").unwrap();
+        let full = fs::read(&path).unwrap();
+        fs::write(&path, &full[..full.len() - 5]).unwrap();
+
+        let mut recovered = open(&path);
+        assert_eq!(recovered.list_keys(), vec!["a", "b"]);
+        assert_eq!(recovered.generation(), 2);
+
+        // Saves leave the torn record alone; repairing cuts it off.
+        assert!(matches!(
+            recovered.set("d", b"4"),
+            Err(VaultError::InvalidDataFormat(_))
+        ));
+        assert_eq!(fs::read(&path).unwrap(), &full[..full.len() - 5]);
+        let report = recovered.repair().unwrap();
+        assert_eq!(
+            report.discarded_bytes,
+            (full.len() - 5 - intact.len()) as u64
+        );
+        assert_eq!(fs::read(&path).unwrap(), intact);
+        recovered.set("d", b"4").unwrap();
+        assert!(fs::read(&path).unwrap().starts_with(&intact));
+        assert_eq!(open(&path).list_keys(), vec!["a", "b", "d"]);
+
+        // Damage before the last record is not silently dropped.
+        let mut damaged = fs::read(&path).unwrap();
+        damaged[LOG_MAGIC.len() + FRAME_HEADER_LEN + 4] ^= 0xff;
+        fs::write(&path, damaged).unwrap();
+        assert!(matches!(
+            LogStorage::new(&path).load(),
+            Err(VaultError::InvalidDataFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_damaged_length_is_not_taken_for_a_torn_record() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("vault.rmsl");
+
+        let mut vault = open(&path);
+        for key in ["a", "b", "c"] {
+            vault.set(key, b"1").unwrap();
+        }
+        let bytes = fs::read(&path).unwrap();
+        let lengths = record_lengths(&bytes);
+        assert_eq!(lengths.len(), 4);
+
+        // A length pointing past the end of the file, in a middle record.
+        let mut damaged = bytes.clone();
+        let middle = LOG_MAGIC.len() + lengths[0] + lengths[1];
+        damaged[middle + 2] = 0x7f;
+        fs::write(&path, &damaged).unwrap();
+
+        for storage in [
+            LogStorage::new(&path),
+            LogStorage::new(&path).index_only(true),
+        ] {
+            storage
+                .unlock(&Keyring::new(MasterKey::new(vec![1u8; 32]).unwrap()))
+                .unwrap();
+            assert!(matches!(
+                storage.load(),
+                Err(VaultError::InvalidDataFormat(_))
+            ));
+            assert!(matches!(
+                storage.repair(),
+                Err(VaultError::InvalidDataFormat(_))
+            ));
+        }
+        assert!(matches!(
+            LogStorage::new(&path).load(),
+            Err(VaultError::InvalidDataFormat(_))
+        ));
+        assert_eq!(fs::read(&path).unwrap(), damaged);
+    }
+
+    #[test]
+    fn test_compact_folds_log_into_snapshot() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("vault.rmsl");
+
+        let mut vault = open(&path);
+        for i in 0..10 {
+            vault.set("key", format!("value {}", i).as_bytes()).unwrap();
+            vault.set(&format!("temp_{}", i), b"x").unwrap();
+            vault.delete(&format!("temp_{}", i)).unwrap();
+        }
+        let storage = LogStorage::new(&path);
+        storage
+            .unlock(&Keyring::new(MasterKey::new(vec![1u8; 32]).unwrap()))
+            .unwrap();
+        let before = fs::metadata(&path).unwrap().len();
+        assert!(storage.compact().unwrap());
+        assert!(fs::metadata(&path).unwrap().len() < before);
+        assert!(!storage.compact().unwrap());
+
+        // Handles opened before compaction keep working.
+        vault.set("key", b"after").unwrap();
+        let reopened = open(&path);
+        assert_eq!(reopened.list_keys(), vec!["key"]);
+        assert_eq!(reopened.list_versions("key").unwrap().len(), 11);
+        assert_eq!(reopened.get("key").unwrap().unwrap(), b"after");
+    }
+
+    #[test]
+    fn test_index_only_log() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("vault.rmsl");
+
+        let mut vault = open(&path);
+        for version in 1..=50 {
+            vault
+                .set("history", format!("value {}", version).as_bytes())
+                .unwrap();
+        }
+        vault.set("gone", b"x").unwrap();
+
+        let mut indexed = open_indexed(&path);
+        assert_eq!(indexed.memory_usage().loaded_secrets, 0);
+        assert_eq!(indexed.memory_usage().indexed_secrets, 2);
+        assert_eq!(indexed.get("history").unwrap().unwrap(), b"value 50");
+        assert_eq!(
+            indexed.get_version("history", 9).unwrap().unwrap(),
+            b"value 9"
+        );
+
+        // Entries are not read from another writer's append until the vault
+        // reloads.
+        vault.set("gone", b"y").unwrap();
+        assert!(matches!(
+            indexed.get("gone"),
+            Err(VaultError::ConcurrentModification {
+                expected: 51,
+                found: 52
+            })
+        ));
+        indexed.reload().unwrap();
+        assert_eq!(indexed.get("gone").unwrap().unwrap(), b"y");
+
+        indexed.set("history", b"value 51").unwrap();
+        indexed.delete("gone").unwrap();
+        assert_eq!(indexed.memory_usage().loaded_secrets, 0);
+        assert_eq!(indexed.list_keys(), vec!["history"]);
+        assert_eq!(indexed.get("history").unwrap().unwrap(), b"value 51");
+
+        // A torn append, here the delete, is skipped, and compaction copies
+        // the current entries.
+        let full = fs::read(&path).unwrap();
+        fs::write(&path, &full[..full.len() - 3]).unwrap();
+        let indexed = open_indexed(&path);
+        assert_eq!(indexed.get("history").unwrap().unwrap(), b"value 51");
+        assert_eq!(indexed.list_keys(), vec!["gone", "history"]);
+        assert!(indexed.compact().unwrap());
+
+        let reopened = open(&path);
+        assert_eq!(reopened.list_versions("history").unwrap().len(), 51);
+        assert_eq!(
+            reopened.get_version("history", 1).unwrap().unwrap(),
+            b"value 1"
+        );
+        assert_eq!(reopened.get("gone").unwrap().unwrap(), b"y");
+        assert_eq!(indexed.get("history").unwrap().unwrap(), b"value 51");
+    }
+
+    #[test]
+    fn test_log_records_are_sealed() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("vault.rmsl");
+
+        let mut vault = open(&path);
+        vault.set("database_password", b"hunter2").unwrap();
+        vault.set("api_token", b"abc").unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let text = String::from_utf8_lossy(&bytes);
+        assert!(!text.contains("database_password"));
+        assert!(!text.contains("api_token"));
+
+        let storage = LogStorage::new(&path);
+        let locked = storage.load().unwrap().unwrap();
+        assert!(locked.secrets.is_empty());
+        assert_eq!(locked.generation, 0);
+        assert!(storage.secret_names().unwrap().is_empty());
+        storage
+            .unlock(&Keyring::new(MasterKey::new(vec![2u8; 32]).unwrap()))
+            .unwrap();
+        assert!(matches!(storage.load(), Err(VaultError::UnknownKeyId(_))));
+
+        // A record whose checksum was fixed up after tampering still fails
+        // to open.
+        let mut tampered = bytes.clone();
+        let end = tampered.len() - 1;
+        tampered[end] ^= 1;
+        let last = bytes.len() - record_lengths(&bytes).last().copied().unwrap();
+        let payload = &tampered[last + FRAME_HEADER_LEN..];
+        let header = frame_header(payload.len() as u64, crc32fast::hash(payload)).unwrap();
+        tampered[last..last + FRAME_HEADER_LEN].copy_from_slice(&header);
+        fs::write(&path, &tampered).unwrap();
+        assert!(matches!(
+            SecretVault::builder()
+                .master_key(KeySource::Bytes(vec![1u8; 32]))
+                .storage(LogStorage::new(&path))
+                .build(),
+            Err(VaultError::DecryptionFailed(_))
+        ));
+    }
+
+    /// Lengths of the framed records of a log, in order.
+    fn record_lengths(bytes: &[u8]) -> Vec<usize> {
+        let mut lengths = Vec::new();
+        let mut offset = LOG_MAGIC.len();
+        while let Some(payload) = next_record(bytes, offset).unwrap() {
+            lengths.push(FRAME_HEADER_LEN + payload.len());
+            offset += FRAME_HEADER_LEN + payload.len();
+        }
+        lengths
+    }
+
+    #[test]
+    fn test_new_active_key_reseals_log() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("vault.rmsl");
+
+        let mut vault = open(&path);
+        vault.set("a", b"1").unwrap();
+        vault.set("b", b"2").unwrap();
+        vault
+            .add_key(KeySource::Bytes(vec![2u8; 32]), true)
+            .unwrap();
+        assert_eq!(record_lengths(&fs::read(&path).unwrap()).len(), 2);
+
+        // Every record now opens with the new key alone.
+        let storage = LogStorage::new(&path);
+        storage
+            .unlock(&Keyring::new(MasterKey::new(vec![2u8; 32]).unwrap()))
+            .unwrap();
+        let data = storage.load().unwrap().unwrap();
+        assert_eq!(data.secrets.len(), 2);
+        assert_eq!(data.generation, vault.generation());
+    }
+
+    #[test]
+    fn test_snapshot_is_split_into_records() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("vault.rmsl");
+
+        let mut vault = open(&path);
+        let value = vec![7u8; 4096];
+        for i in 0..40 {
+            vault.set(&format!("key_{:02}", i), &value).unwrap();
+        }
+        assert!(vault.compact().unwrap());
+        let records = record_lengths(&fs::read(&path).unwrap());
+        assert!(records.len() > 2);
+        assert!(records.iter().all(|len| *len < 2 * SNAPSHOT_CHUNK));
+
+        let indexed = open_indexed(&path);
+        assert_eq!(indexed.list_keys().len(), 40);
+        assert_eq!(indexed.get("key_00").unwrap().unwrap(), value);
+        assert_eq!(indexed.get("key_39").unwrap().unwrap(), value);
+    }
+}
@@ -0,0 +1,119 @@
+use super::{LockMode, StorageLock, VaultStorage};
+use crate::error::{Result, VaultError};
+use crate::vault::VaultData;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Keeps the vault in process memory.
+///
+/// Nothing survives the process, which suits tests and ephemeral mobile
+/// sessions. Clones share the same storage, so several vault handles opened
+/// on clones behave like processes sharing one file.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    data: Option<VaultData>,
+    sidecars: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    /// Creates an empty storage.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn inner(&self) -> MutexGuard<'_, Inner> {
+        // The state is replaced wholesale, so a panic elsewhere cannot leave
+        // it half-written.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl VaultStorage for MemoryStorage {
+    fn load(&self) -> Result<Option<VaultData>> {
+        Ok(self.inner().data.clone())
+    }
+
+    fn store(&self, data: &VaultData) -> Result<()> {
+        self.inner().data = Some(data.clone());
+        Ok(())
+    }
+
+    // Every operation is atomic under the mutex, and `save` below checks and
+    // stores under a single acquisition, so no separate lock is needed.
+    fn lock(&self, _mode: LockMode) -> Result<StorageLock> {
+        Ok(StorageLock::none())
+    }
+
+    fn generations(&self) -> Result<Vec<u64>> {
+        Ok(self
+            .inner()
+            .data
+            .iter()
+            .map(|data| data.generation)
+            .collect())
+    }
+
+    fn load_sidecar(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.inner().sidecars.get(name).cloned())
+    }
+
+    fn store_sidecar(&self, name: &str, bytes: Option<&[u8]>) -> Result<()> {
+        let mut inner = self.inner();
+        match bytes {
+            Some(bytes) => inner.sidecars.insert(name.to_string(), bytes.to_vec()),
+            None => inner.sidecars.remove(name),
+        };
+        Ok(())
+    }
+
+    fn save(&self, data: &mut VaultData) -> Result<()> {
+        let mut inner = self.inner();
+        let found = inner.data.as_ref().map_or(0, |stored| stored.generation);
+        if found != data.generation {
+            return Err(VaultError::ConcurrentModification {
+                expected: data.generation,
+                found,
+            });
+        }
+        data.generation += 1;
+        inner.data = Some(data.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::{KeySource, SecretVault};
+
+    fn open(storage: &MemoryStorage) -> SecretVault {
+        SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .storage(storage.clone())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_vault_on_memory_storage() {
+        let storage = MemoryStorage::new();
+        let mut first = open(&storage);
+        let mut second = open(&storage);
+
+        first.set("a", b"1").unwrap();
+        second.set("b", b"2").unwrap();
+        assert_eq!(storage.generations().unwrap(), vec![2]);
+
+        let reopened = open(&storage);
+        assert_eq!(reopened.get("a").unwrap().unwrap(), b"1");
+        assert_eq!(reopened.get("b").unwrap().unwrap(), b"2");
+        assert!(MemoryStorage::new().load().unwrap().is_none());
+    }
+}
@@ -0,0 +1,363 @@
+//! Persistence backends for vault data.
+//!
+//! A [`SecretVault`](crate::SecretVault) keeps its [`VaultData`] in memory and
+//! hands it to a [`VaultStorage`] to persist. [`FileStorage`] writes the
+//! classic YAML vault file or its compact binary counterpart; [`LogStorage`]
+//! appends each save to a log; [`SealedStorage`] encrypts the whole file so
+//! that not even secret names show; [`MemoryStorage`] keeps everything in
+//! process for tests and ephemeral sessions. Other backends plug in by implementing the
+//! trait and passing it to [`VaultBuilder::storage`](crate::vault::VaultBuilder::storage).
+
+use crate::error::{Result, VaultError};
+use crate::keyring::Keyring;
+use crate::vault::{SecretEntry, VaultData};
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+mod atomic;
+mod file;
+mod index;
+mod log;
+mod memory;
+mod sealed;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub(crate) use atomic::{read_if_exists, rename_durable, sidecar_path, write_atomic};
+pub use file::FileStorage;
+use file::BINARY_MAGIC;
+pub use log::LogStorage;
+use log::LOG_MAGIC;
+pub use memory::MemoryStorage;
+pub use sealed::SealedStorage;
+use sealed::SEALED_MAGIC;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+
+/// The first bytes of every SQLite database file.
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+/// On-disk vault formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageFormat {
+    /// One YAML document holding the whole vault.
+    #[default]
+    Yaml,
+    /// The same document as CBOR behind a magic header: smaller and faster
+    /// to parse, but not human-readable.
+    Binary,
+    /// An append-only log of saves, each holding only the secrets it
+    /// changed. Compact it to fold the log back into one snapshot.
+    Log,
+    /// A SQLite database with one row per secret version. Requires the
+    /// `sqlite` feature.
+    Sqlite,
+    /// The whole vault encrypted as one container behind a small plaintext
+    /// header, hiding secret names and metadata. See [`SealedStorage`].
+    Sealed,
+}
+
+impl StorageFormat {
+    /// Returns the name used on the command line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageFormat::Yaml => "yaml",
+            StorageFormat::Binary => "binary",
+            StorageFormat::Log => "log",
+            StorageFormat::Sqlite => "sqlite",
+            StorageFormat::Sealed => "sealed",
+        }
+    }
+
+    /// Returns the conventional file extension.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StorageFormat::Yaml => "yaml",
+            StorageFormat::Binary => "rmsv",
+            StorageFormat::Log => "rmsl",
+            StorageFormat::Sqlite => "db",
+            StorageFormat::Sealed => "rmse",
+        }
+    }
+
+    /// Detects the format of an existing vault file from its first bytes.
+    /// Missing files are reported as YAML, the format new vaults are created in.
+    pub fn detect(path: &Path) -> Result<Self> {
+        let mut magic = [0u8; SQLITE_MAGIC.len()];
+        let read = match File::open(path) {
+            Ok(mut file) => file.read(&mut magic)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let magic = &magic[..read];
+        if magic == SQLITE_MAGIC {
+            Ok(StorageFormat::Sqlite)
+        } else if magic.starts_with(BINARY_MAGIC) {
+            Ok(StorageFormat::Binary)
+        } else if magic.starts_with(LOG_MAGIC) {
+            Ok(StorageFormat::Log)
+        } else if magic.starts_with(SEALED_MAGIC) {
+            Ok(StorageFormat::Sealed)
+        } else {
+            Ok(StorageFormat::Yaml)
+        }
+    }
+
+    /// Opens the vault at `path` in this format.
+    ///
+    /// # Errors
+    /// Returns `VaultError::Storage` for SQLite when the crate was built
+    /// without the `sqlite` feature.
+    pub fn open(&self, path: &Path) -> Result<Box<dyn VaultStorage>> {
+        match self {
+            StorageFormat::Yaml => Ok(Box::new(FileStorage::new(path))),
+            StorageFormat::Binary => Ok(Box::new(FileStorage::binary(path))),
+            StorageFormat::Log => Ok(Box::new(LogStorage::new(path))),
+            StorageFormat::Sealed => Ok(Box::new(SealedStorage::new(path))),
+            #[cfg(feature = "sqlite")]
+            StorageFormat::Sqlite => Ok(Box::new(SqliteStorage::open(path)?)),
+            #[cfg(not(feature = "sqlite"))]
+            StorageFormat::Sqlite => Err(VaultError::Storage(
+                "SQLite vaults require the `sqlite` feature".to_string(),
+            )),
+        }
+    }
+
+    /// Opens the existing vault at `path` in this format without writing to
+    /// it. Only SQLite differs from [`StorageFormat::open`]: its database is
+    /// opened read-only and its schema is left alone.
+    pub fn open_read_only(&self, path: &Path) -> Result<Box<dyn VaultStorage>> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            StorageFormat::Sqlite => Ok(Box::new(SqliteStorage::open_read_only(path)?)),
+            format => format.open(path),
+        }
+    }
+}
+
+impl fmt::Display for StorageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for StorageFormat {
+    type Err = VaultError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "yaml" => Ok(StorageFormat::Yaml),
+            "binary" => Ok(StorageFormat::Binary),
+            "log" => Ok(StorageFormat::Log),
+            "sqlite" => Ok(StorageFormat::Sqlite),
+            "sealed" => Ok(StorageFormat::Sealed),
+            other => Err(VaultError::Storage(format!(
+                "Unknown storage format: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Lists the versions of `entries`, in stored order.
+fn entry_versions(entries: Option<Vec<SecretEntry>>) -> Vec<u32> {
+    entries
+        .unwrap_or_default()
+        .iter()
+        .map(|entry| entry.version)
+        .collect()
+}
+
+/// Picks `version` from `entries`, or the latest with `None`.
+fn select_entry(entries: Option<Vec<SecretEntry>>, version: Option<u32>) -> Option<SecretEntry> {
+    let mut entries = entries?.into_iter();
+    match version {
+        Some(version) => entries.find(|entry| entry.version == version),
+        None => entries.last(),
+    }
+}
+
+/// Whether a lock excludes other readers as well as other writers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+/// A held storage lock, released when dropped.
+pub struct StorageLock {
+    _guard: Option<Box<dyn Send>>,
+}
+
+impl StorageLock {
+    /// Wraps a guard whose drop releases the lock.
+    pub fn new(guard: impl Send + 'static) -> Self {
+        Self {
+            _guard: Some(Box::new(guard)),
+        }
+    }
+
+    /// A lock for backends that need none.
+    pub fn none() -> Self {
+        Self { _guard: None }
+    }
+}
+
+/// Where a vault keeps its data between sessions.
+///
+/// Every save stores a new generation of the vault. The provided
+/// [`VaultStorage::save`] refuses to store over a generation other than the
+/// one the data was loaded from, which is how concurrent writers are detected;
+/// backends only need to supply the primitives below.
+pub trait VaultStorage: Send + Sync {
+    /// Reads the current vault, or `None` if nothing has been stored yet.
+    ///
+    /// Callers hold at least a shared lock.
+    fn load(&self) -> Result<Option<VaultData>>;
+
+    /// Stores `data` as the current vault, replacing it atomically.
+    ///
+    /// Called with the exclusive lock held and the generation already checked.
+    fn store(&self, data: &VaultData) -> Result<()>;
+
+    /// Blocks until the lock is held.
+    fn lock(&self, mode: LockMode) -> Result<StorageLock>;
+
+    /// Lists the generations this backend can still produce, oldest first.
+    fn generations(&self) -> Result<Vec<u64>>;
+
+    /// Returns the generation of the current vault, 0 if there is none.
+    ///
+    /// Backends can override this to avoid reading the whole vault.
+    fn current_generation(&self) -> Result<u64> {
+        Ok(self.load()?.map_or(0, |data| data.generation))
+    }
+
+    /// Reads an auxiliary record kept alongside the vault, such as a staged
+    /// rotation.
+    ///
+    /// Backends that cannot keep auxiliary records may return `None`; an
+    /// interrupted rotation then starts over instead of resuming.
+    fn load_sidecar(&self, _name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Stores, or with `None` removes, an auxiliary record.
+    fn store_sidecar(&self, _name: &str, _bytes: Option<&[u8]>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether `load` leaves `secrets` empty, so that entries are read one
+    /// secret at a time through [`VaultStorage::load_secret`].
+    ///
+    /// Index-only backends fail those reads with
+    /// `VaultError::ConcurrentModification` once another writer has saved
+    /// since the last `load`.
+    fn is_lazy(&self) -> bool {
+        false
+    }
+
+    /// Lists the names of every stored secret.
+    fn secret_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .load()?
+            .map(|data| data.secrets.into_keys().collect())
+            .unwrap_or_default())
+    }
+
+    /// Reads the entries of one stored secret, oldest version first.
+    fn load_secret(&self, name: &str) -> Result<Option<Vec<SecretEntry>>> {
+        Ok(self.load()?.and_then(|mut data| data.secrets.remove(name)))
+    }
+
+    /// Reads one entry of a stored secret: `version`, or the latest with
+    /// `None`.
+    ///
+    /// Lazy backends can override this to read a single entry rather than
+    /// the whole history.
+    fn load_entry(&self, name: &str, version: Option<u32>) -> Result<Option<SecretEntry>> {
+        Ok(select_entry(self.load_secret(name)?, version))
+    }
+
+    /// Lists the versions stored for one secret, in stored order.
+    ///
+    /// Lazy backends can override this to answer without reading entries.
+    fn entry_versions(&self, name: &str) -> Result<Vec<u32>> {
+        Ok(entry_versions(self.load_secret(name)?))
+    }
+
+    /// Whether the stored vault is encrypted as a whole, so that `load`
+    /// returns only its plaintext header until the backend is given the
+    /// keys with [`VaultStorage::unlock`].
+    fn is_sealed(&self) -> bool {
+        false
+    }
+
+    /// Hands the backend the master keys of the vault. Sealed backends open
+    /// their container with the key named in its header and seal saves
+    /// under the active key; others ignore the keys.
+    fn unlock(&self, _keyring: &Keyring) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the approximate heap bytes a lazy backend keeps to find the
+    /// secrets it has not read.
+    fn index_size(&self) -> usize {
+        0
+    }
+
+    /// Stores `data` as the next generation, writing only the secrets listed
+    /// in `changes`: their new entries, or `None` for deleted secrets.
+    ///
+    /// Lazy backends must override this, since their `data` only holds the
+    /// secrets that were read. The default saves everything.
+    fn save_changes(
+        &self,
+        data: &mut VaultData,
+        _changes: &[(&str, Option<&[SecretEntry]>)],
+    ) -> Result<()> {
+        self.save(data)
+    }
+
+    /// Rewrites the stored vault in its most compact form, returning whether
+    /// there was anything to do.
+    ///
+    /// Backends that never accumulate garbage keep the default, which does
+    /// nothing.
+    fn compact(&self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Cuts off what a crash in the middle of a write left behind, returning
+    /// the number of bytes removed. Backends that only ever replace the
+    /// stored vault whole keep the default, which removes nothing.
+    fn repair(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Stores `data` as the next generation of the vault.
+    ///
+    /// # Errors
+    /// Returns `VaultError::ConcurrentModification` if the stored vault is not
+    /// the generation `data` was loaded from.
+    fn save(&self, data: &mut VaultData) -> Result<()> {
+        let _lock = self.lock(LockMode::Exclusive)?;
+        let found = self.current_generation()?;
+        if found != data.generation {
+            return Err(VaultError::ConcurrentModification {
+                expected: data.generation,
+                found,
+            });
+        }
+
+        data.generation += 1;
+        let result = self.store(data);
+        if result.is_err() {
+            data.generation -= 1;
+        }
+        result
+    }
+}
@@ -0,0 +1,380 @@
+use super::atomic::{
+    lock_file, read_if_exists, sidecar_path, write_atomic, write_backup, write_or_remove,
+};
+use super::{LockMode, StorageLock, VaultStorage};
+use crate::encryption::{decrypt_with, encrypt_with, CipherKind};
+use crate::error::{Result, VaultError};
+use crate::kdf::KdfParams;
+use crate::keyring::Keyring;
+use crate::vault::VaultData;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use zeroize::Zeroizing;
+
+/// Magic bytes opening a sealed vault file, followed by the header length,
+/// the header and the container.
+pub(super) const SEALED_MAGIC: &[u8] = b"RMSVSEAL";
+
+/// Derives the container key from a master key, so that it is never the key
+/// that wraps entry data keys.
+const CONTAINER_KEY_DOMAIN: &[u8] = b"rmsv-container-key-v1";
+
+/// Binds the container to the vault file, so that a sidecar cannot be passed
+/// off as the vault or the other way round.
+const VAULT_LABEL: &str = "vault";
+
+/// The plaintext part of a sealed file: only what is needed to find the key
+/// and decrypt the rest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct SealedHeader {
+    pub cipher: CipherKind,
+    /// Argon2id parameters for vaults unlocked by passphrase.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KdfParams>,
+    /// ID of the master key the container key is derived from.
+    pub key_id: String,
+}
+
+/// A vault file encrypted as a whole, guarded by an advisory lock on
+/// `<vault>.lock`.
+///
+/// The serialised vault is sealed as a single AEAD container behind a small
+/// plaintext header holding the cipher, the KDF parameters and the ID of the
+/// master key. Secret names, versions, timestamps and the generation are only
+/// readable once the backend is [unlocked](VaultStorage::unlock); until then
+/// [`VaultStorage::load`] returns just the header, and the file reveals
+/// nothing beyond its size. Sidecar records are sealed the same way.
+///
+/// Saves replace the file atomically and durably, and with
+/// [`SealedStorage::backup`] keep the previous generation as `<vault>.bak`.
+#[derive(Clone)]
+pub struct SealedStorage {
+    path: PathBuf,
+    backup: bool,
+    /// The keys the container is opened with, shared between clones.
+    keyring: Arc<Mutex<Option<Keyring>>>,
+}
+
+impl SealedStorage {
+    /// Creates a backend for the sealed vault file at `path`.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            backup: false,
+            keyring: Arc::default(),
+        }
+    }
+
+    /// Keeps a copy of the previous vault file as `<vault>.bak` on every save.
+    pub fn backup(mut self, enabled: bool) -> Self {
+        self.backup = enabled;
+        self
+    }
+
+    /// Returns the path of the vault file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether the backend holds the keys to open the container.
+    pub fn is_unlocked(&self) -> bool {
+        self.keyring().is_some()
+    }
+
+    fn keyring(&self) -> Option<Keyring> {
+        self.keyring
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    fn unlocked(&self) -> Result<Keyring> {
+        self.keyring().ok_or_else(|| {
+            VaultError::Storage("Sealed vault is locked; unlock it with its master key".to_string())
+        })
+    }
+
+    fn read_vault(&self, path: &Path) -> Result<Option<VaultData>> {
+        let Some(bytes) = read_if_exists(path)? else {
+            return Ok(None);
+        };
+        let Some(keyring) = self.keyring() else {
+            let (header, _) = parse_header(SEALED_MAGIC, &bytes)?;
+            return Ok(Some(VaultData {
+                cipher: header.cipher,
+                kdf: header.kdf,
+                ..VaultData::default()
+            }));
+        };
+        let (_, plaintext) = open_container(SEALED_MAGIC, &keyring, &bytes, VAULT_LABEL)?;
+        ciborium::from_reader(plaintext.as_slice())
+            .map(Some)
+            .map_err(|e| VaultError::Serialization(e.to_string()))
+    }
+}
+
+impl fmt::Debug for SealedStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SealedStorage")
+            .field("path", &self.path)
+            .field("backup", &self.backup)
+            .field("unlocked", &self.is_unlocked())
+            .finish()
+    }
+}
+
+impl VaultStorage for SealedStorage {
+    fn load(&self) -> Result<Option<VaultData>> {
+        self.read_vault(&self.path)
+    }
+
+    fn store(&self, data: &VaultData) -> Result<()> {
+        let keyring = self.unlocked()?;
+        let mut plaintext = Zeroizing::new(Vec::new());
+        ciborium::into_writer(data, &mut *plaintext)
+            .map_err(|e| VaultError::Serialization(e.to_string()))?;
+        let bytes = seal_container(
+            SEALED_MAGIC,
+            &keyring,
+            data.cipher,
+            data.kdf.clone(),
+            &plaintext,
+            VAULT_LABEL,
+        )?;
+        if self.backup {
+            write_backup(&self.path)?;
+        }
+        write_atomic(&self.path, &bytes)
+    }
+
+    fn lock(&self, mode: LockMode) -> Result<StorageLock> {
+        lock_file(&self.path, mode)
+    }
+
+    fn generations(&self) -> Result<Vec<u64>> {
+        let mut generations = Vec::new();
+        if self.backup {
+            let backup = self.read_vault(&sidecar_path(&self.path, "bak"))?;
+            generations.extend(backup.map(|data| data.generation));
+        }
+        generations.extend(self.load()?.map(|data| data.generation));
+        Ok(generations)
+    }
+
+    fn load_sidecar(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let Some(bytes) = read_if_exists(&sidecar_path(&self.path, name))? else {
+            return Ok(None);
+        };
+        let (_, plaintext) = open_container(SEALED_MAGIC, &self.unlocked()?, &bytes, name)?;
+        Ok(Some(plaintext))
+    }
+
+    fn store_sidecar(&self, name: &str, bytes: Option<&[u8]>) -> Result<()> {
+        let sealed = match bytes {
+            Some(bytes) => Some(seal_container(
+                SEALED_MAGIC,
+                &self.unlocked()?,
+                CipherKind::default(),
+                None,
+                bytes,
+                name,
+            )?),
+            None => None,
+        };
+        write_or_remove(&sidecar_path(&self.path, name), sealed.as_deref())
+    }
+
+    fn is_sealed(&self) -> bool {
+        true
+    }
+
+    fn unlock(&self, keyring: &Keyring) -> Result<()> {
+        *self
+            .keyring
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(keyring.clone());
+        Ok(())
+    }
+}
+
+/// Seals `plaintext` under the active key of `keyring`, behind `magic` and
+/// the plaintext header.
+pub(super) fn seal_container(
+    magic: &[u8],
+    keyring: &Keyring,
+    cipher: CipherKind,
+    kdf: Option<KdfParams>,
+    plaintext: &[u8],
+    label: &str,
+) -> Result<Vec<u8>> {
+    let header = SealedHeader {
+        cipher,
+        kdf,
+        key_id: keyring.active_id().to_string(),
+    };
+    let mut header_bytes = Vec::new();
+    ciborium::into_writer(&header, &mut header_bytes)
+        .map_err(|e| VaultError::Serialization(e.to_string()))?;
+
+    let mut bytes = magic.to_vec();
+    bytes.extend_from_slice(&(header_bytes.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&header_bytes);
+    let key = container_key(keyring, &header.key_id)?;
+    let sealed = encrypt_with(cipher, "", &*key, plaintext, &aad(&bytes, label))?;
+    bytes.extend_from_slice(&sealed);
+    Ok(bytes)
+}
+
+/// Opens a container written by [`seal_container`] with the same `magic`
+/// and `label`.
+pub(super) fn open_container(
+    magic: &[u8],
+    keyring: &Keyring,
+    bytes: &[u8],
+    label: &str,
+) -> Result<(SealedHeader, Vec<u8>)> {
+    let (header, header_len) = parse_header(magic, bytes)?;
+    let key = container_key(keyring, &header.key_id)?;
+    let plaintext = decrypt_with(
+        header.cipher,
+        &*key,
+        &bytes[header_len..],
+        &aad(&bytes[..header_len], label),
+    )?;
+    Ok((header, plaintext))
+}
+
+/// Splits the plaintext header off a sealed container, returning it with
+/// the length of everything up to the ciphertext.
+pub(super) fn parse_header(magic: &[u8], bytes: &[u8]) -> Result<(SealedHeader, usize)> {
+    let invalid = || VaultError::InvalidDataFormat("Missing or damaged sealed header".to_string());
+    let rest = bytes.strip_prefix(magic).ok_or_else(invalid)?;
+    let len_bytes: [u8; 4] = rest
+        .get(..4)
+        .and_then(|len| len.try_into().ok())
+        .ok_or_else(invalid)?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    let header_bytes = rest.get(4..4 + len).ok_or_else(invalid)?;
+    let header = ciborium::from_reader(header_bytes)
+        .map_err(|e| VaultError::Serialization(e.to_string()))?;
+    Ok((header, magic.len() + 4 + len))
+}
+
+fn container_key(keyring: &Keyring, key_id: &str) -> Result<Zeroizing<[u8; 32]>> {
+    let master = keyring.get(key_id)?;
+    let mac = Hmac::<Sha256>::new_from_slice(master.as_bytes())
+        .expect("HMAC takes keys of any length")
+        .chain_update(CONTAINER_KEY_DOMAIN);
+    Ok(Zeroizing::new(mac.finalize().into_bytes().into()))
+}
+
+/// The header and label the container is bound to.
+fn aad(header: &[u8], label: &str) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(label.as_bytes());
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageFormat;
+    use crate::vault::{KeySource, MasterKey, SecretVault};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn open(path: &Path, key: KeySource) -> Result<SecretVault> {
+        SecretVault::builder()
+            .master_key(key)
+            .vault_path(path)
+            .format(StorageFormat::Sealed)
+            .build()
+    }
+
+    #[test]
+    fn test_sealed_file_hides_names() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("vault.rmse");
+        let mut vault = open(&path, KeySource::Bytes(vec![1u8; 32])).unwrap();
+        vault.set("database_password", b"hunter2").unwrap();
+        vault.set("database_password", b"hunter3").unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        assert!(bytes.starts_with(SEALED_MAGIC));
+        assert_eq!(StorageFormat::detect(&path).unwrap(), StorageFormat::Sealed);
+
+        let storage = SealedStorage::new(&path);
+        let locked = storage.load().unwrap().unwrap();
+        assert!(locked.secrets.is_empty());
+        assert_eq!(locked.generation, 0);
+        storage
+            .unlock(&Keyring::new(MasterKey::new(vec![1u8; 32]).unwrap()))
+            .unwrap();
+        let unlocked = storage.load().unwrap().unwrap();
+        let text = String::from_utf8_lossy(&bytes);
+        assert!(!text.contains("database_password"));
+        assert!(!text.contains(&unlocked.vault_id));
+
+        let reopened = open(&path, KeySource::Bytes(vec![1u8; 32])).unwrap();
+        assert_eq!(reopened.list_keys(), vec!["database_password"]);
+        assert_eq!(reopened.list_versions("database_password").unwrap(), [1, 2]);
+        assert_eq!(
+            reopened.get("database_password").unwrap().unwrap(),
+            b"hunter3"
+        );
+        assert_eq!(reopened.generation(), 2);
+
+        assert!(matches!(
+            open(&path, KeySource::Bytes(vec![2u8; 32])),
+            Err(VaultError::UnknownKeyId(_))
+        ));
+    }
+
+    #[test]
+    fn test_sealed_file_detects_tampering() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("vault.rmse");
+        let mut vault = open(&path, KeySource::Bytes(vec![1u8; 32])).unwrap();
+        vault.set("a", b"1").unwrap();
+
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            open(&path, KeySource::Bytes(vec![1u8; 32])),
+            Err(VaultError::DecryptionFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_sealed_vault_with_passphrase_and_rotation() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("vault.rmse");
+        let passphrase = || KeySource::Passphrase("correct horse".to_string());
+        let mut vault = SecretVault::builder()
+            .master_key(passphrase())
+            .vault_path(&path)
+            .format(StorageFormat::Sealed)
+            .kdf_params(KdfParams::new(8, 1, 1).unwrap())
+            .create()
+            .unwrap();
+        vault.set("token", b"abc").unwrap();
+
+        let mut vault = open(&path, passphrase()).unwrap();
+        assert_eq!(vault.get("token").unwrap().unwrap(), b"abc");
+        assert!(matches!(
+            open(&path, KeySource::Passphrase("wrong".to_string())),
+            Err(VaultError::KeyLoadError(_))
+        ));
+
+        vault.rotate(KeySource::Bytes(vec![3u8; 32])).unwrap();
+        let reopened = open(&path, KeySource::Bytes(vec![3u8; 32])).unwrap();
+        assert_eq!(reopened.get("token").unwrap().unwrap(), b"abc");
+        assert!(open(&path, passphrase()).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+use super::{LockMode, StorageLock, VaultStorage};
+use crate::error::{Result, VaultError};
+use crate::vault::{SecretEntry, VaultData};
+use rusqlite::{
+    params, Connection, OpenFlags, OptionalExtension, Transaction, TransactionBehavior,
+};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS vault (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        generation INTEGER NOT NULL,
+        metadata TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS entries (
+        name TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        entry TEXT NOT NULL,
+        PRIMARY KEY (name, version)
+    );
+    CREATE TABLE IF NOT EXISTS sidecars (
+        name TEXT PRIMARY KEY,
+        bytes BLOB NOT NULL
+    );
+";
+
+/// A SQLite database holding one row per secret version.
+///
+/// Rows are keyed and indexed by secret name and version, so a write only
+/// touches the secrets it changed and a read only the secret it asks for.
+/// Every save runs in a single immediate transaction that also checks the
+/// generation, which makes SQLite's own locking the only lock needed.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    /// Opens or creates the database at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Opens the existing database at `path` read-only, without creating it or
+    /// its schema.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Creates a private database that lives as long as the storage.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Runs `write` in an immediate transaction after checking that the
+    /// stored generation is the one `data` was loaded from, then bumps it.
+    fn commit(
+        &self,
+        data: &mut VaultData,
+        write: impl FnOnce(&Transaction<'_>, &VaultData) -> Result<()>,
+    ) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let found = generation(&tx)?.unwrap_or(0);
+        if found != data.generation {
+            return Err(VaultError::ConcurrentModification {
+                expected: data.generation,
+                found,
+            });
+        }
+
+        let next = data.generation + 1;
+        write_metadata(&tx, next, data)?;
+        write(&tx, data)?;
+        tx.commit()?;
+        data.generation = next;
+        Ok(())
+    }
+}
+
+fn generation(conn: &Connection) -> Result<Option<u64>> {
+    Ok(conn
+        .query_row("SELECT generation FROM vault WHERE id = 1", [], |row| {
+            row.get::<_, i64>(0)
+        })
+        .optional()?
+        .map(|generation| generation as u64))
+}
+
+fn write_metadata(tx: &Transaction<'_>, generation: u64, data: &VaultData) -> Result<()> {
+    tx.execute(
+        "INSERT INTO vault (id, generation, metadata) VALUES (1, ?1, ?2)
+         ON CONFLICT (id) DO UPDATE SET generation = ?1, metadata = ?2",
+        params![generation as i64, serde_json::to_string(&data.metadata())?],
+    )?;
+    Ok(())
+}
+
+fn replace_all_entries(tx: &Transaction<'_>, data: &VaultData) -> Result<()> {
+    tx.execute("DELETE FROM entries", [])?;
+    for (name, entries) in &data.secrets {
+        insert_entries(tx, name, entries)?;
+    }
+    Ok(())
+}
+
+fn insert_entries(tx: &Transaction<'_>, name: &str, entries: &[SecretEntry]) -> Result<()> {
+    let mut insert =
+        tx.prepare_cached("INSERT INTO entries (name, version, entry) VALUES (?1, ?2, ?3)")?;
+    for entry in entries {
+        insert.execute(params![name, entry.version, serde_json::to_string(entry)?])?;
+    }
+    Ok(())
+}
+
+impl VaultStorage for SqliteStorage {
+    fn load(&self) -> Result<Option<VaultData>> {
+        let conn = self.conn();
+        let row = conn
+            .query_row(
+                "SELECT generation, metadata FROM vault WHERE id = 1",
+                [],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+        match row {
+            Some((generation, metadata)) => {
+                let mut data: VaultData = serde_json::from_str(&metadata)?;
+                data.generation = generation as u64;
+                Ok(Some(data))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn load_entry(&self, name: &str, version: Option<u32>) -> Result<Option<SecretEntry>> {
+        let conn = self.conn();
+        let row: Option<String> = match version {
+            Some(version) => conn
+                .prepare_cached("SELECT entry FROM entries WHERE name = ?1 AND version = ?2")?
+                .query_row(params![name, version], |row| row.get(0))
+                .optional()?,
+            None => conn
+                .prepare_cached(
+                    "SELECT entry FROM entries WHERE name = ?1 ORDER BY version DESC LIMIT 1",
+                )?
+                .query_row([name], |row| row.get(0))
+                .optional()?,
+        };
+        row.map(|row| Ok(serde_json::from_str(&row)?)).transpose()
+    }
+
+    fn entry_versions(&self, name: &str) -> Result<Vec<u32>> {
+        let conn = self.conn();
+        let mut query =
+            conn.prepare_cached("SELECT version FROM entries WHERE name = ?1 ORDER BY version")?;
+        let versions = query
+            .query_map([name], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(versions)
+    }
+
+    /// Replaces every row. `data` must hold all secrets.
+    fn store(&self, data: &VaultData) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        write_metadata(&tx, data.generation, data)?;
+        replace_all_entries(&tx, data)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn lock(&self, _mode: LockMode) -> Result<StorageLock> {
+        Ok(StorageLock::none())
+    }
+
+    fn generations(&self) -> Result<Vec<u64>> {
+        Ok(generation(&self.conn())?.into_iter().collect())
+    }
+
+    fn current_generation(&self) -> Result<u64> {
+        Ok(generation(&self.conn())?.unwrap_or(0))
+    }
+
+    fn load_sidecar(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT bytes FROM sidecars WHERE name = ?1",
+                [name],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn store_sidecar(&self, name: &str, bytes: Option<&[u8]>) -> Result<()> {
+        let conn = self.conn();
+        match bytes {
+            Some(bytes) => conn.execute(
+                "INSERT INTO sidecars (name, bytes) VALUES (?1, ?2)
+                 ON CONFLICT (name) DO UPDATE SET bytes = ?2",
+                params![name, bytes],
+            )?,
+            None => conn.execute("DELETE FROM sidecars WHERE name = ?1", [name])?,
+        };
+        Ok(())
+    }
+
+    fn is_lazy(&self) -> bool {
+        true
+    }
+
+    fn secret_names(&self) -> Result<Vec<String>> {
+        let conn = self.conn();
+        let mut query = conn.prepare_cached("SELECT DISTINCT name FROM entries ORDER BY name")?;
+        let names = query
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(names)
+    }
+
+    fn load_secret(&self, name: &str) -> Result<Option<Vec<SecretEntry>>> {
+        let conn = self.conn();
+        let mut query =
+            conn.prepare_cached("SELECT entry FROM entries WHERE name = ?1 ORDER BY version")?;
+        let rows = query
+            .query_map([name], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        rows.iter()
+            .map(|row| Ok(serde_json::from_str(row)?))
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    /// Replaces every row. `data` must hold all secrets.
+    fn save(&self, data: &mut VaultData) -> Result<()> {
+        self.commit(data, replace_all_entries)
+    }
+
+    fn save_changes(
+        &self,
+        data: &mut VaultData,
+        changes: &[(&str, Option<&[SecretEntry]>)],
+    ) -> Result<()> {
+        self.commit(data, |tx, _| {
+            for (name, entries) in changes {
+                tx.execute("DELETE FROM entries WHERE name = ?1", [name])?;
+                if let Some(entries) = entries {
+                    insert_entries(tx, name, entries)?;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::{KeySource, SecretVault};
+    use tempfile::TempDir;
+
+    fn open(path: &Path) -> SecretVault {
+        SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .storage(SqliteStorage::open(path).unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_sqlite_round_trip_and_lazy_load() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("vault.db");
+
+        let mut vault = open(&db_path);
+        vault.set("a", b"1").unwrap();
+        vault.set("a", b"2").unwrap();
+        vault.set("b", b"3").unwrap();
+        vault.delete("b").unwrap();
+
+        let reopened = open(&db_path);
+        assert_eq!(reopened.list_keys(), vec!["a".to_string()]);
+        assert_eq!(reopened.get("a").unwrap().unwrap(), b"2");
+        assert_eq!(reopened.get_version("a", 1).unwrap().unwrap(), b"1");
+        assert_eq!(reopened.generation(), 4);
+    }
+
+    #[test]
+    fn test_sqlite_concurrent_writers() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("vault.db");
+
+        let mut first = open(&db_path);
+        first.set("seed", b"0").unwrap();
+        let mut second = open(&db_path);
+        first.set("a", b"1").unwrap();
+        second.set("b", b"2").unwrap();
+
+        let reopened = open(&db_path);
+        assert_eq!(reopened.get("a").unwrap().unwrap(), b"1");
+        assert_eq!(reopened.get("b").unwrap().unwrap(), b"2");
+    }
+
+    #[test]
+    fn test_export_yaml_vault_to_sqlite() {
+        let temp_dir = TempDir::new().unwrap();
+        let yaml_path = temp_dir.path().join("vault.yaml");
+        let db_path = temp_dir.path().join("vault.db");
+
+        let mut yaml = SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &yaml_path, None).unwrap();
+        yaml.set("a", b"1").unwrap();
+        yaml.set("b", b"2").unwrap();
+        yaml.export_to(&SqliteStorage::open(&db_path).unwrap())
+            .unwrap();
+        assert!(matches!(
+            yaml.export_to(&SqliteStorage::open(&db_path).unwrap()),
+            Err(VaultError::Storage(_))
+        ));
+
+        // The builder recognises the database by its header.
+        let mut vault = SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &db_path, None).unwrap();
+        vault.rotate(KeySource::Bytes(vec![2u8; 32])).unwrap();
+        let reopened = SecretVault::new(KeySource::Bytes(vec![2u8; 32]), &db_path, None).unwrap();
+        assert_eq!(reopened.get("a").unwrap().unwrap(), b"1");
+        assert_eq!(reopened.key_usage().unwrap()[reopened.active_key_id()], 2);
+    }
+
+    #[test]
+    fn test_overwrite_deletes_unloaded_secrets() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("vault.db");
+
+        let mut vault = open(&db_path);
+        vault.set("a", b"1").unwrap();
+        vault.set("b", b"2").unwrap();
+
+        SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .vault_path(&db_path)
+            .format(crate::storage::StorageFormat::Sqlite)
+            .overwrite(true)
+            .create()
+            .unwrap();
+        let reopened = open(&db_path);
+        assert!(reopened.list_keys().is_empty());
+        assert!(reopened.get("a").unwrap().is_none());
+    }
+}
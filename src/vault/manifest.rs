@@ -0,0 +1,348 @@
+use super::{MasterKey, RetentionPolicy, SecretEntry, VaultData};
+use crate::encryption::CipherKind;
+use crate::error::{Result, VaultError};
+use crate::kdf::KdfParams;
+use crate::keyring::Keyring;
+use crate::storage::{read_if_exists, write_atomic};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MANIFEST_KEY_DOMAIN: &[u8] = b"rmsv-manifest-key-v1";
+const MANIFEST_DOMAIN: &[u8] = b"rmsv-manifest-v2";
+
+/// A MAC over the shape of a vault: its ID, generation, every secret name
+/// with its versions, the tombstones of deleted secrets and its settings.
+/// Entries are authenticated one by one already; the manifest also catches
+/// entries or whole secrets that were removed, settings such as retention
+/// that were weakened, and together with a pinned generation, a vault rolled
+/// back to an older copy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// ID of the master key the MAC key is derived from.
+    pub key_id: String,
+    #[serde(with = "crate::encoding::bytes")]
+    pub mac: Vec<u8>,
+}
+
+impl Manifest {
+    /// Computes the manifest of `data` stored as `generation`, whose
+    /// secrets have `versions`; lazy backends may leave `data.secrets`
+    /// incomplete.
+    pub(super) fn sign(
+        key: &MasterKey,
+        data: &VaultData,
+        generation: u64,
+        versions: &BTreeMap<String, Vec<u32>>,
+    ) -> Self {
+        Self {
+            key_id: key.key_id(),
+            mac: manifest_mac(key, data, generation, versions)
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+        }
+    }
+
+    /// Checks that the manifest matches the stored vault.
+    ///
+    /// # Errors
+    /// Returns `VaultError::UnknownKeyId` if its key is not in `keyring`, and
+    /// `VaultError::ManifestMismatch` if the vault does not match it.
+    pub(super) fn verify(
+        &self,
+        keyring: &Keyring,
+        data: &VaultData,
+        versions: &BTreeMap<String, Vec<u32>>,
+    ) -> Result<()> {
+        let key = keyring.get(&self.key_id)?;
+        manifest_mac(key, data, data.generation, versions)
+            .verify_slice(&self.mac)
+            .map_err(|_| {
+                VaultError::ManifestMismatch(
+                    "secrets, versions, tombstones or settings were changed".to_string(),
+                )
+            })
+    }
+}
+
+/// The vault settings the manifest covers.
+#[derive(Serialize)]
+struct Settings<'a> {
+    cipher: CipherKind,
+    kdf: &'a Option<KdfParams>,
+    active_key_id: &'a str,
+    entries_bound: bool,
+    retention: &'a Option<RetentionPolicy>,
+    key_retention: &'a BTreeMap<String, RetentionPolicy>,
+    // Left out when unset, so vaults signed before it existed still verify.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delete_grace_period_secs: Option<u64>,
+}
+
+/// Lists the versions of every secret in `secrets`.
+pub(super) fn versions_of(
+    secrets: &BTreeMap<String, Vec<SecretEntry>>,
+) -> BTreeMap<String, Vec<u32>> {
+    secrets
+        .iter()
+        .map(|(name, entries)| (name.clone(), entries.iter().map(|e| e.version).collect()))
+        .collect()
+}
+
+/// Returns the MAC of `data` stored as `generation`, ready to finalize or
+/// verify.
+fn manifest_mac(
+    key: &MasterKey,
+    data: &VaultData,
+    generation: u64,
+    versions: &BTreeMap<String, Vec<u32>>,
+) -> HmacSha256 {
+    let mac_key: Zeroizing<[u8; 32]> = Zeroizing::new(
+        HmacSha256::new_from_slice(key.as_bytes())
+            .expect("HMAC takes keys of any length")
+            .chain_update(MANIFEST_KEY_DOMAIN)
+            .finalize()
+            .into_bytes()
+            .into(),
+    );
+    let mut message = MANIFEST_DOMAIN.to_vec();
+    message.extend_from_slice(&(data.vault_id.len() as u32).to_be_bytes());
+    message.extend_from_slice(data.vault_id.as_bytes());
+    message.extend_from_slice(&generation.to_be_bytes());
+    message.extend_from_slice(&(versions.len() as u32).to_be_bytes());
+    for (name, versions) in versions {
+        let mut versions = versions.clone();
+        versions.sort_unstable();
+        message.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        message.extend_from_slice(name.as_bytes());
+        message.extend_from_slice(&(versions.len() as u32).to_be_bytes());
+        for version in versions {
+            message.extend_from_slice(&version.to_be_bytes());
+        }
+    }
+    message.extend_from_slice(&(data.tombstones.len() as u32).to_be_bytes());
+    for (name, deleted_at) in &data.tombstones {
+        message.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        message.extend_from_slice(name.as_bytes());
+        message.extend_from_slice(&deleted_at.timestamp().to_be_bytes());
+    }
+    let settings = Settings {
+        cipher: data.cipher,
+        kdf: &data.kdf,
+        active_key_id: &data.active_key_id,
+        // Without it, legacy entries would be accepted again.
+        entries_bound: data.entries_bound,
+        retention: &data.retention,
+        key_retention: &data.key_retention,
+        delete_grace_period_secs: data.delete_grace_period_secs,
+    };
+    ciborium::into_writer(&settings, &mut message).expect("settings serialize to CBOR");
+    HmacSha256::new_from_slice(&*mac_key)
+        .expect("HMAC takes keys of any length")
+        .chain_update(&message)
+}
+
+/// What the local state file remembers about one vault.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Pin {
+    /// Highest generation seen.
+    pub generation: u64,
+    /// Whether the vault had a manifest, so that stripping it is detected.
+    pub manifest: bool,
+}
+
+/// Reads the pin of `vault_id` from the state file at `path`.
+pub(super) fn read_pin(path: &Path, vault_id: &str) -> Result<Option<Pin>> {
+    Ok(read_pins(path)?.remove(vault_id))
+}
+
+/// Raises the pin of `vault_id` in the state file at `path` to `pin`; it
+/// never goes back down. The file can hold the pins of several vaults.
+pub(super) fn write_pin(path: &Path, vault_id: &str, pin: Pin) -> Result<()> {
+    let mut pins = read_pins(path)?;
+    let stored = pins.entry(vault_id.to_string()).or_default();
+    let raised = Pin {
+        generation: stored.generation.max(pin.generation),
+        manifest: stored.manifest || pin.manifest,
+    };
+    if raised == *stored {
+        return Ok(());
+    }
+    *stored = raised;
+    write_atomic(path, serde_yaml::to_string(&pins)?.as_bytes())
+}
+
+fn read_pins(path: &Path) -> Result<BTreeMap<String, Pin>> {
+    match read_if_exists(path)? {
+        Some(bytes) => Ok(serde_yaml::from_slice(&bytes)?),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, StorageFormat, VaultStorage};
+    use crate::vault::{KeySource, SecretVault, VaultBuilder};
+    use tempfile::TempDir;
+
+    fn builder(storage: &MemoryStorage) -> VaultBuilder {
+        SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .storage(storage.clone())
+    }
+
+    #[test]
+    fn test_manifest_detects_removed_entries() {
+        let storage = MemoryStorage::new();
+        let mut vault = builder(&storage).manifest(true).build().unwrap();
+        vault.set("a", b"1").unwrap();
+        vault.set("a", b"2").unwrap();
+        vault.set("b", b"3").unwrap();
+        let stored = storage.load().unwrap().unwrap();
+        assert!(stored.manifest.is_some());
+
+        // Without asking for it, the manifest is still checked and kept.
+        let mut reopened = builder(&storage).build().unwrap();
+        reopened.set("c", b"4").unwrap();
+        builder(&storage).build().unwrap();
+
+        let mut tampered = storage.load().unwrap().unwrap();
+        tampered.secrets.remove("b");
+        storage.store(&tampered).unwrap();
+        assert!(matches!(
+            builder(&storage).build(),
+            Err(VaultError::ManifestMismatch(_))
+        ));
+
+        let mut tampered = stored;
+        tampered.secrets.get_mut("a").unwrap().pop();
+        storage.store(&tampered).unwrap();
+        assert!(matches!(
+            builder(&storage).build(),
+            Err(VaultError::ManifestMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_pinned_generation_detects_rollback() {
+        let temp_dir = TempDir::new().unwrap();
+        let pin_path = temp_dir.path().join("vault.state");
+        let storage = MemoryStorage::new();
+        let mut vault = builder(&storage).pin_path(&pin_path).build().unwrap();
+        vault.set("revoked", b"old credential").unwrap();
+        let old_copy = storage.load().unwrap().unwrap();
+        vault.delete("revoked").unwrap();
+
+        storage.store(&old_copy).unwrap();
+        assert!(matches!(
+            builder(&storage).pin_path(&pin_path).build(),
+            Err(VaultError::RollbackDetected {
+                pinned: 2,
+                found: 1
+            })
+        ));
+        // The old copy carries a valid manifest; only the pin catches it.
+        builder(&storage).build().unwrap();
+
+        let mut stripped = old_copy;
+        stripped.generation = 5;
+        stripped.manifest = None;
+        storage.store(&stripped).unwrap();
+        assert!(matches!(
+            builder(&storage).pin_path(&pin_path).build(),
+            Err(VaultError::ManifestMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_required_manifest_cannot_be_stripped() {
+        let storage = MemoryStorage::new();
+        let mut vault = builder(&storage).manifest(true).build().unwrap();
+        vault.set("a", b"1").unwrap();
+
+        let mut stripped = storage.load().unwrap().unwrap();
+        stripped.manifest = None;
+        stripped.generation += 1;
+        storage.store(&stripped).unwrap();
+        assert!(matches!(
+            builder(&storage).manifest(true).build(),
+            Err(VaultError::ManifestMismatch(_))
+        ));
+        assert!(matches!(
+            vault.reload(),
+            Err(VaultError::ManifestMismatch(_))
+        ));
+
+        // Signing it again is an explicit step.
+        let mut unsigned = builder(&storage).build().unwrap();
+        unsigned.sign_manifest().unwrap();
+        builder(&storage).manifest(true).build().unwrap();
+    }
+
+    #[test]
+    fn test_manifest_covers_settings() {
+        let storage = MemoryStorage::new();
+        let mut vault = builder(&storage).manifest(true).build().unwrap();
+        vault.set("a", b"1").unwrap();
+        vault
+            .set_retention(Some(RetentionPolicy::keep_last(5)))
+            .unwrap();
+        let signed = storage.load().unwrap().unwrap();
+
+        let tamperings: [fn(&mut VaultData); 7] = [
+            |data| data.retention = None,
+            |data| data.kdf = Some(KdfParams::new(8, 1, 1).unwrap()),
+            |data| {
+                data.key_retention
+                    .insert("a".to_string(), RetentionPolicy::default());
+            },
+            |data| data.cipher = CipherKind::ChaCha20Poly1305,
+            |data| data.active_key_id = MasterKey::new(vec![2u8; 32]).unwrap().key_id(),
+            |data| data.entries_bound = false,
+            |data| data.delete_grace_period_secs = Some(0),
+        ];
+        for tamper in tamperings {
+            let mut tampered = signed.clone();
+            tamper(&mut tampered);
+            storage.store(&tampered).unwrap();
+            // The other key is loaded, so only the manifest tells.
+            let opened = builder(&storage)
+                .additional_key(KeySource::Bytes(vec![2u8; 32]))
+                .build();
+            assert!(matches!(opened, Err(VaultError::ManifestMismatch(_))));
+        }
+    }
+
+    #[test]
+    fn test_manifest_survives_rotation_and_lazy_loading() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("vault.rmsv");
+        let open = |key: u8| {
+            SecretVault::builder()
+                .master_key(KeySource::Bytes(vec![key; 32]))
+                .vault_path(&vault_path)
+                .format(StorageFormat::Binary)
+                .index_only(true)
+                .manifest(true)
+                .build()
+        };
+
+        let mut vault = open(1).unwrap();
+        vault.set("a", b"1").unwrap();
+        vault.set("b", b"2").unwrap();
+        let mut vault = open(1).unwrap();
+        vault.set("a", b"3").unwrap();
+        vault.rotate(KeySource::Bytes(vec![2u8; 32])).unwrap();
+
+        let reopened = open(2).unwrap();
+        assert_eq!(reopened.get("a").unwrap().unwrap(), b"3");
+        assert!(matches!(open(1), Err(VaultError::UnknownKeyId(_))));
+    }
+}
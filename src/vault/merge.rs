@@ -0,0 +1,496 @@
+use super::manifest::{versions_of, Manifest};
+use super::migration::{self, MigrationContext};
+use super::{EntryCrypto, SecretEntry, VaultData};
+use crate::error::Result;
+use crate::keyring::Keyring;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use zeroize::Zeroizing;
+
+/// Something a three-way merge could not decide on its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeConflict {
+    /// The secret concerned, or `None` for the vault as a whole.
+    pub name: Option<String>,
+    /// The version concerned, or `None` for the whole secret.
+    pub version: Option<u32>,
+    pub reason: String,
+}
+
+impl fmt::Display for MergeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.name, self.version) {
+            (Some(name), Some(version)) => write!(f, "{} v{}: {}", name, version, self.reason),
+            (Some(name), None) => write!(f, "{}: {}", name, self.reason),
+            (None, _) => write!(f, "vault: {}", self.reason),
+        }
+    }
+}
+
+/// The result of [`merge_vaults`].
+#[derive(Debug)]
+pub struct MergeOutcome {
+    /// The merged vault. Where there are conflicts it keeps our side.
+    pub data: VaultData,
+    /// Versions added on their side that were moved past ours, as
+    /// `(secret, old version, new version)`.
+    pub renumbered: Vec<(String, u32, u32)>,
+    /// Changes that need a human decision. The merge is clean when empty.
+    pub conflicts: Vec<MergeConflict>,
+}
+
+impl MergeOutcome {
+    /// Whether the merge needs no further resolution.
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// Merges two descendants of `base` secret by secret and version by version.
+///
+/// Secrets and versions added on either side are kept, and changes made on
+/// only one side win. Where both sides added the same version number, their
+/// additions are renumbered after ours; the same version changed differently
+/// on both sides is a conflict.
+///
+/// No key is needed as long as both sides use the same master key and no
+/// renumbered entry is bound to its version, which every entry written by
+/// current releases is. With `keyring`, renumbered entries are re-sealed
+/// under their new version and every entry ends up under the active key.
+///
+/// `base` is `None` when both sides created the vault independently.
+///
+/// # Errors
+/// Fails if an input comes from a newer release, or if an entry cannot be
+/// decrypted with `keyring`.
+pub fn merge_vaults(
+    base: Option<&VaultData>,
+    ours: &VaultData,
+    theirs: &VaultData,
+    keyring: Option<&Keyring>,
+) -> Result<MergeOutcome> {
+    let ctx = MigrationContext {
+        active_key_id: keyring.map_or("", |keyring| keyring.active_id()),
+    };
+    let upgrade = |data: &VaultData| -> Result<VaultData> {
+        let mut data = data.clone();
+        migration::migrate(&mut data, &ctx)?;
+        Ok(data)
+    };
+    let base = base.map(upgrade).transpose()?;
+    let ours = upgrade(ours)?;
+    let theirs = upgrade(theirs)?;
+
+    let mut merge = Merge {
+        keyring,
+        renumbered: Vec::new(),
+        conflicts: Vec::new(),
+    };
+    merge.check_compatible(&ours, &theirs);
+    if !merge.conflicts.is_empty() {
+        return Ok(merge.finish(ours));
+    }
+
+    let mut data = VaultData {
+        generation: ours.generation.max(theirs.generation) + 1,
+        // Entries taken from their side are only known to be bound if
+        // their vault was marked too.
+        entries_bound: ours.entries_bound && theirs.entries_bound,
+        tombstones: BTreeMap::new(),
+        ..ours.metadata()
+    };
+    let names: BTreeSet<&String> = ours.secrets.keys().chain(theirs.secrets.keys()).collect();
+    for name in names {
+        let base_entries = base.as_ref().and_then(|base| base.secrets.get(name));
+        let merged = match (ours.secrets.get(name), theirs.secrets.get(name)) {
+            (Some(o), Some(t)) if o == t => Some(o.clone()),
+            (Some(o), Some(t)) => Some(merge.versions(&data, name, base_entries, o, t)?),
+            (Some(side), None) | (None, Some(side)) => match base_entries {
+                None => Some(side.clone()),
+                Some(base_entries) if base_entries == side => None,
+                Some(_) => {
+                    merge.conflict(name, None, "deleted on one side and changed on the other");
+                    ours.secrets.get(name).cloned()
+                }
+            },
+            (None, None) => None,
+        };
+        let tombstone = merge.tombstone(name, base.as_ref(), &ours, &theirs);
+        if let Some(entries) = merged.filter(|entries| !entries.is_empty()) {
+            data.secrets.insert(name.clone(), entries);
+            if let Some(deleted_at) = tombstone {
+                data.tombstones.insert(name.clone(), deleted_at);
+            }
+        }
+    }
+
+    if let Some(keyring) = keyring {
+        merge.rewrap_to_active(&mut data, keyring)?;
+    }
+    // The manifest can only be signed again with the key. Without it, the
+    // merged vault has none until it is saved with a manifest again.
+    if data.manifest.is_some() {
+        data.manifest = keyring.map(|keyring| {
+            Manifest::sign(
+                keyring.active(),
+                &data,
+                data.generation,
+                &versions_of(&data.secrets),
+            )
+        });
+    }
+    Ok(merge.finish(data))
+}
+
+struct Merge<'a> {
+    keyring: Option<&'a Keyring>,
+    renumbered: Vec<(String, u32, u32)>,
+    conflicts: Vec<MergeConflict>,
+}
+
+impl Merge<'_> {
+    fn check_compatible(&mut self, ours: &VaultData, theirs: &VaultData) {
+        if ours.vault_id != theirs.vault_id {
+            self.vault_conflict("the files belong to different vaults".to_string());
+        }
+        if ours.cipher != theirs.cipher {
+            self.vault_conflict(format!(
+                "the ciphers differ ({} and {})",
+                ours.cipher, theirs.cipher
+            ));
+        }
+        if ours.kdf != theirs.kdf {
+            self.vault_conflict("the passphrase parameters differ".to_string());
+        }
+        if self.keyring.is_none() && ours.active_key_id != theirs.active_key_id {
+            self.vault_conflict(format!(
+                "the sides use different master keys ({} and {}); merge with both keys loaded",
+                ours.active_key_id, theirs.active_key_id
+            ));
+        }
+    }
+
+    fn versions(
+        &mut self,
+        data: &VaultData,
+        name: &str,
+        base: Option<&Vec<SecretEntry>>,
+        ours: &[SecretEntry],
+        theirs: &[SecretEntry],
+    ) -> Result<Vec<SecretEntry>> {
+        let index = |entries: &[SecretEntry]| -> BTreeMap<u32, SecretEntry> {
+            entries
+                .iter()
+                .map(|entry| (entry.version, entry.clone()))
+                .collect()
+        };
+        let base = index(base.map_or(&[], |base| base.as_slice()));
+        let (ours, theirs) = (index(ours), index(theirs));
+
+        let mut merged = BTreeMap::new();
+        let mut added_by_theirs = Vec::new();
+        let mut collided = false;
+        let versions: BTreeSet<u32> = ours.keys().chain(theirs.keys()).copied().collect();
+        for version in versions {
+            let original = base.get(&version);
+            match (ours.get(&version), theirs.get(&version)) {
+                (Some(o), Some(t)) if o == t => {
+                    merged.insert(version, o.clone());
+                }
+                (Some(o), Some(t)) => match original {
+                    Some(b) if b == o => {
+                        merged.insert(version, t.clone());
+                    }
+                    Some(b) if b == t => {
+                        merged.insert(version, o.clone());
+                    }
+                    Some(_) => {
+                        self.conflict(name, Some(version), "changed on both sides");
+                        merged.insert(version, o.clone());
+                    }
+                    None => {
+                        merged.insert(version, o.clone());
+                        added_by_theirs.push(t.clone());
+                        collided = true;
+                    }
+                },
+                (Some(o), None) => match original {
+                    None => {
+                        merged.insert(version, o.clone());
+                    }
+                    Some(b) if b == o => {}
+                    Some(_) => {
+                        self.conflict(name, Some(version), "changed by us and removed by them");
+                        merged.insert(version, o.clone());
+                    }
+                },
+                (None, Some(t)) => match original {
+                    None => added_by_theirs.push(t.clone()),
+                    Some(b) if b == t => {}
+                    Some(_) => {
+                        self.conflict(name, Some(version), "removed by us and changed by them");
+                    }
+                },
+                (None, None) => {}
+            }
+        }
+
+        // Their additions stay in order. If any of them collides with ours,
+        // all of them move past every version either side has seen.
+        let mut next = base
+            .keys()
+            .chain(ours.keys())
+            .chain(theirs.keys())
+            .max()
+            .map_or(1, |max| max + 1);
+        for entry in added_by_theirs {
+            if !collided {
+                merged.insert(entry.version, entry);
+                continue;
+            }
+            if let Some(moved) = self.renumber(data, name, &entry, next)? {
+                self.renumbered
+                    .push((name.to_string(), entry.version, next));
+                merged.insert(next, moved);
+            }
+            next += 1;
+        }
+        Ok(merged.into_values().collect())
+    }
+
+    /// Merges whether `name` is deleted. Deleting it on one side while the
+    /// other changed its versions is a conflict, which keeps our side.
+    fn tombstone(
+        &mut self,
+        name: &str,
+        base: Option<&VaultData>,
+        ours: &VaultData,
+        theirs: &VaultData,
+    ) -> Option<DateTime<Utc>> {
+        let of = |data: &VaultData| data.tombstones.get(name).copied();
+        let (original, o, t) = (base.and_then(of), of(ours), of(theirs));
+        let base_entries = base.and_then(|base| base.secrets.get(name));
+        let changed = |data: &VaultData| data.secrets.get(name) != base_entries;
+        // The tombstone one side changed, and the side that left it alone.
+        let (changed_tombstone, other) = if o == t {
+            return o;
+        } else if o == original {
+            (t, ours)
+        } else if t == original {
+            (o, theirs)
+        } else {
+            return o;
+        };
+        if original.is_none() && changed_tombstone.is_some() && changed(other) {
+            self.conflict(name, None, "deleted on one side and changed on the other");
+            return o;
+        }
+        changed_tombstone
+    }
+
+    /// Moves `entry` to version `to`. Entries bound to their version must be
+    /// re-sealed, which needs the key; without it this is a conflict.
+    fn renumber(
+        &mut self,
+        data: &VaultData,
+        name: &str,
+        entry: &SecretEntry,
+        to: u32,
+    ) -> Result<Option<SecretEntry>> {
+        if !entry.aad_bound || entry.shredded {
+            return Ok(Some(SecretEntry {
+                version: to,
+                ..entry.clone()
+            }));
+        }
+        let Some(keyring) = self.keyring else {
+            self.conflict(
+                name,
+                Some(entry.version),
+                "added on both sides; renumbering it needs the master key",
+            );
+            return Ok(None);
+        };
+        let crypto = EntryCrypto {
+            cipher: data.cipher,
+            keyring,
+            vault_id: &data.vault_id,
+            bound_only: data.entries_bound,
+        };
+        let value = Zeroizing::new(crypto.open(name, entry)?);
+        Ok(Some(SecretEntry {
+            created_at: entry.created_at,
+            ..crypto.seal(name, to, &value)?
+        }))
+    }
+
+    fn rewrap_to_active(&self, data: &mut VaultData, keyring: &Keyring) -> Result<()> {
+        let crypto = EntryCrypto {
+            cipher: data.cipher,
+            keyring,
+            vault_id: &data.vault_id,
+            bound_only: data.entries_bound,
+        };
+        for (name, entries) in data.secrets.iter_mut() {
+            for entry in entries.iter_mut() {
+                if !entry.shredded && entry.key_id != keyring.active_id() {
+                    *entry = crypto.rewrap(name, entry)?;
+                }
+            }
+        }
+        data.active_key_id = keyring.active_id().to_string();
+        Ok(())
+    }
+
+    fn conflict(&mut self, name: &str, version: Option<u32>, reason: &str) {
+        self.conflicts.push(MergeConflict {
+            name: Some(name.to_string()),
+            version,
+            reason: reason.to_string(),
+        });
+    }
+
+    fn vault_conflict(&mut self, reason: String) {
+        self.conflicts.push(MergeConflict {
+            name: None,
+            version: None,
+            reason,
+        });
+    }
+
+    fn finish(self, data: VaultData) -> MergeOutcome {
+        MergeOutcome {
+            data,
+            renumbered: self.renumbered,
+            conflicts: self.conflicts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, VaultStorage};
+    use crate::vault::{KeySource, MasterKey, SecretVault};
+
+    fn key() -> KeySource {
+        KeySource::Bytes(vec![1u8; 32])
+    }
+
+    fn open(storage: &MemoryStorage) -> SecretVault {
+        SecretVault::builder()
+            .master_key(key())
+            .storage(storage.clone())
+            .build()
+            .unwrap()
+    }
+
+    /// Returns the base vault and two independent copies of it.
+    fn branches() -> (VaultData, MemoryStorage, MemoryStorage) {
+        let storage = MemoryStorage::new();
+        let mut vault = open(&storage);
+        vault.set("shared", b"v1").unwrap();
+        vault.set("doomed", b"v1").unwrap();
+        let base = storage.load().unwrap().unwrap();
+
+        let fork = || {
+            let storage = MemoryStorage::new();
+            storage.store(&base).unwrap();
+            storage
+        };
+        (base.clone(), fork(), fork())
+    }
+
+    fn merged_vault(data: &VaultData) -> SecretVault {
+        let storage = MemoryStorage::new();
+        storage.store(data).unwrap();
+        open(&storage)
+    }
+
+    #[test]
+    fn test_merge_unions_secrets_without_key() {
+        let (base, ours, theirs) = branches();
+        open(&ours).set("ours_only", b"a").unwrap();
+        open(&theirs).set("theirs_only", b"b").unwrap();
+        open(&theirs).delete("doomed").unwrap();
+
+        let outcome = merge_vaults(
+            Some(&base),
+            &ours.load().unwrap().unwrap(),
+            &theirs.load().unwrap().unwrap(),
+            None,
+        )
+        .unwrap();
+        assert!(outcome.is_clean(), "{:?}", outcome.conflicts);
+
+        let vault = merged_vault(&outcome.data);
+        assert_eq!(
+            vault.list_keys(),
+            vec!["ours_only", "shared", "theirs_only"]
+        );
+        assert_eq!(vault.get("theirs_only").unwrap().unwrap(), b"b");
+    }
+
+    #[test]
+    fn test_merge_renumbers_colliding_versions() {
+        let (base, ours, theirs) = branches();
+        open(&ours).set("shared", b"ours").unwrap();
+        let mut their_vault = open(&theirs);
+        their_vault.set("shared", b"theirs").unwrap();
+        their_vault.set("shared", b"theirs again").unwrap();
+        let (ours, theirs) = (
+            ours.load().unwrap().unwrap(),
+            theirs.load().unwrap().unwrap(),
+        );
+
+        // Renumbering re-seals the entries, which needs the key.
+        let outcome = merge_vaults(Some(&base), &ours, &theirs, None).unwrap();
+        assert_eq!(outcome.conflicts.len(), 2);
+        assert_eq!(outcome.conflicts[0].version, Some(2));
+
+        let keyring = Keyring::new(MasterKey::new(vec![1u8; 32]).unwrap());
+        let outcome = merge_vaults(Some(&base), &ours, &theirs, Some(&keyring)).unwrap();
+        assert!(outcome.is_clean(), "{:?}", outcome.conflicts);
+        assert_eq!(
+            outcome.renumbered,
+            vec![("shared".to_string(), 2, 4), ("shared".to_string(), 3, 5)]
+        );
+
+        let vault = merged_vault(&outcome.data);
+        assert_eq!(vault.list_versions("shared").unwrap(), vec![1, 2, 4, 5]);
+        assert_eq!(vault.get_version("shared", 2).unwrap().unwrap(), b"ours");
+        assert_eq!(vault.get_version("shared", 4).unwrap().unwrap(), b"theirs");
+        assert_eq!(vault.get("shared").unwrap().unwrap(), b"theirs again");
+    }
+
+    #[test]
+    fn test_merge_reports_real_conflicts() {
+        let (base, ours, theirs) = branches();
+        open(&ours).shred_version("shared", 1).unwrap();
+        open(&theirs).delete("shared").unwrap();
+
+        let outcome = merge_vaults(
+            Some(&base),
+            &ours.load().unwrap().unwrap(),
+            &theirs.load().unwrap().unwrap(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            outcome.conflicts,
+            vec![MergeConflict {
+                name: Some("shared".to_string()),
+                version: None,
+                reason: "deleted on one side and changed on the other".to_string(),
+            }]
+        );
+
+        let other = VaultData {
+            vault_id: "another vault".to_string(),
+            ..base.clone()
+        };
+        let outcome = merge_vaults(None, &base, &other, None).unwrap();
+        assert_eq!(outcome.conflicts[0].name, None);
+    }
+}
@@ -0,0 +1,113 @@
+//! Upgrades vault data written in older layouts.
+//!
+//! Every vault records the `format_version` it was written in. Loading runs
+//! the registered migrations from that version up to [`FORMAT_VERSION`] in
+//! memory; the upgraded layout reaches storage with the next save, or
+//! immediately through [`SecretVault::upgrade`](super::SecretVault::upgrade).
+//!
+//! To change the layout, bump [`FORMAT_VERSION`] and append a [`Migration`]
+//! from the previous version to [`MIGRATIONS`].
+
+use super::{backfill_key_ids, VaultData};
+use crate::error::{Result, VaultError};
+
+/// The layout this release writes. Vaults without a `format_version` field
+/// predate versioning and count as version 0.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// What a migration may need beyond the data itself.
+pub(super) struct MigrationContext<'a> {
+    /// ID of the master key the vault is opened with.
+    pub active_key_id: &'a str,
+}
+
+/// One upgrade step, from `from` to `from + 1`.
+struct Migration {
+    from: u32,
+    apply: fn(&mut VaultData, &MigrationContext<'_>) -> Result<()>,
+}
+
+/// Every migration, in order. Each one must see all secrets, so lazy
+/// backends read everything before they run.
+const MIGRATIONS: &[Migration] = &[
+    // 0 -> 1: entries record the ID of the master key that wraps them.
+    Migration {
+        from: 0,
+        apply: |data, ctx| {
+            backfill_key_ids(data, ctx.active_key_id);
+            Ok(())
+        },
+    },
+    // 1 -> 2: byte fields are written as base64 strings in text formats.
+    // Decoding accepts both shapes, so only the version changes.
+    Migration {
+        from: 1,
+        apply: |_, _| Ok(()),
+    },
+    // 2 -> 3: deleted secrets keep their entries behind a tombstone. Older
+    // releases would show them again, so they must refuse these vaults.
+    Migration {
+        from: 2,
+        apply: |_, _| Ok(()),
+    },
+];
+
+/// Fails if `version` is newer than this release understands.
+pub(crate) fn check_version(version: u32) -> Result<()> {
+    if version > FORMAT_VERSION {
+        return Err(VaultError::UnsupportedFormatVersion {
+            found: version,
+            supported: FORMAT_VERSION,
+        });
+    }
+    Ok(())
+}
+
+/// Upgrades `data` to [`FORMAT_VERSION`], returning whether anything ran.
+///
+/// # Errors
+/// Returns `VaultError::UnsupportedFormatVersion` if `data` comes from a
+/// newer release.
+pub(super) fn migrate(data: &mut VaultData, ctx: &MigrationContext<'_>) -> Result<bool> {
+    check_version(data.format_version)?;
+    if data.format_version == FORMAT_VERSION {
+        return Ok(false);
+    }
+    let start = data.format_version;
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.from >= start)
+    {
+        (migration.apply)(data, ctx)?;
+        data.format_version = migration.from + 1;
+    }
+    debug_assert_eq!(data.format_version, FORMAT_VERSION);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_reaches_current_version() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.from, index as u32);
+        }
+        assert_eq!(MIGRATIONS.len() as u32, FORMAT_VERSION);
+    }
+
+    #[test]
+    fn test_newer_versions_are_rejected() {
+        let mut data = VaultData {
+            format_version: FORMAT_VERSION + 1,
+            ..VaultData::default()
+        };
+        let ctx = MigrationContext { active_key_id: "k" };
+        assert!(matches!(
+            migrate(&mut data, &ctx),
+            Err(VaultError::UnsupportedFormatVersion { found, supported })
+                if found == FORMAT_VERSION + 1 && supported == FORMAT_VERSION
+        ));
+    }
+}
@@ -0,0 +1,2562 @@
+use crate::audit::{AuditLogger, Operation};
+use crate::encryption::EnvelopeHeader;
+use crate::encryption::{self, decrypt_with, encrypt_with, CipherKind, KEY_SIZE};
+use crate::error::{Result, VaultError};
+use crate::kdf::KdfParams;
+use crate::keyring::Keyring;
+use crate::storage::{
+    rename_durable, sidecar_path, FileStorage, LockMode, LogStorage, MemoryStorage, SealedStorage,
+    StorageFormat, VaultStorage,
+};
+use base64::{engine::general_purpose, Engine as _};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use zeroize::{Zeroize, Zeroizing};
+
+mod manifest;
+mod merge;
+mod migration;
+mod retention;
+mod rotation;
+mod tombstone;
+mod transaction;
+mod verify;
+
+pub use manifest::Manifest;
+pub use merge::{merge_vaults, MergeConflict, MergeOutcome};
+pub(crate) use migration::check_version;
+pub use migration::FORMAT_VERSION;
+pub use retention::{format_age, parse_age, RetentionPolicy};
+pub use rotation::{RotationOptions, RotationReport};
+pub use transaction::Transaction;
+pub use verify::{IssueKind, VerifyIssue, VerifyReport};
+
+/// How often a change is reapplied after losing a race with another writer.
+const DEFAULT_MAX_RETRIES: u32 = 3;
+/// How long deleted secrets are kept by default: 30 days.
+const DEFAULT_DELETE_GRACE_PERIOD: Duration = Duration::from_secs(30 * 24 * 3600);
+
+#[derive(Clone, Zeroize)]
+#[zeroize(drop)]
+pub struct MasterKey(Vec<u8>);
+
+impl MasterKey {
+    pub fn new(key: Vec<u8>) -> Result<Self> {
+        if key.len() != KEY_SIZE {
+            return Err(VaultError::InvalidKeySize {
+                expected: KEY_SIZE,
+                found: key.len(),
+            });
+        }
+        Ok(Self(key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the public identifier recorded in ciphertexts sealed by this key.
+    pub fn key_id(&self) -> String {
+        encryption::key_id(&self.0)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SecretEntry {
+    #[serde(with = "crate::encoding::bytes")]
+    pub encrypted_value: Vec<u8>,
+    pub version: u32,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// Whether `encrypted_value` is bound to the vault ID, secret name and
+    /// version through AEAD associated data. Entries written before binding
+    /// existed are `false` until [`SecretVault::bind_legacy_entries`] runs.
+    ///
+    /// The flag itself is not authenticated; once the vault is marked
+    /// [`VaultData::entries_bound`], unbound entries are refused.
+    #[serde(default)]
+    pub aad_bound: bool,
+    /// The per-entry data-encryption key, sealed under the master key. `None`
+    /// for entries encrypted directly under the master key by older releases.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::encoding::option_bytes"
+    )]
+    pub wrapped_dek: Option<Vec<u8>>,
+    /// Set once the DEK has been destroyed; the value can no longer be read.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shredded: bool,
+    /// ID of the master key that wraps this entry's data key.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub key_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct VaultData {
+    /// Incremented on every save; used to detect concurrent writers.
+    #[serde(default)]
+    pub generation: u64,
+    /// Layout version of the stored data; see [`FORMAT_VERSION`]. Vaults
+    /// written before the field existed are version 0.
+    #[serde(default)]
+    pub format_version: u32,
+    /// Cipher used for every entry in this vault. Vaults written before the
+    /// field existed are AES-256-GCM.
+    #[serde(default)]
+    pub cipher: CipherKind,
+    /// Random identifier bound into every ciphertext so entries cannot be
+    /// moved between vaults. Assigned on first load if missing.
+    #[serde(default)]
+    pub vault_id: String,
+    /// Argon2id salt and cost parameters for vaults unlocked by passphrase.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KdfParams>,
+    /// ID of the master key used for new writes when the vault was last saved.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub active_key_id: String,
+    /// Set once every entry is bound to its slot and sealed under a data key,
+    /// by [`SecretVault::bind_legacy_entries`], rotation, or for vaults
+    /// created that way. From then on, entries without binding or a data key
+    /// are refused rather than decrypted the legacy way.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub entries_bound: bool,
+    /// MAC over the secret names and versions; see [`VaultBuilder::manifest`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<Manifest>,
+    /// Versions kept of secrets without a policy of their own; see
+    /// [`SecretVault::set_retention`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<RetentionPolicy>,
+    /// Retention policies of single secrets, by secret name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub key_retention: BTreeMap<String, RetentionPolicy>,
+    /// Seconds a deleted secret is kept before saves purge it; see
+    /// [`SecretVault::set_delete_grace_period`]. `None` keeps them 30 days.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete_grace_period_secs: Option<u64>,
+    /// When each deleted secret was deleted. Its entries are kept in
+    /// `secrets` until it is purged; see [`SecretVault::delete`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tombstones: BTreeMap<String, chrono::DateTime<chrono::Utc>>,
+    /// Entries by secret name, sorted so that every save writes the secrets
+    /// in the same order.
+    #[serde(default)]
+    pub secrets: BTreeMap<String, Vec<SecretEntry>>,
+}
+
+impl VaultData {
+    /// Returns a copy of everything except the secrets.
+    pub fn metadata(&self) -> VaultData {
+        VaultData {
+            generation: self.generation,
+            format_version: self.format_version,
+            cipher: self.cipher,
+            vault_id: self.vault_id.clone(),
+            kdf: self.kdf.clone(),
+            active_key_id: self.active_key_id.clone(),
+            entries_bound: self.entries_bound,
+            manifest: self.manifest.clone(),
+            retention: self.retention,
+            key_retention: self.key_retention.clone(),
+            delete_grace_period_secs: self.delete_grace_period_secs,
+            tombstones: self.tombstones.clone(),
+            secrets: BTreeMap::new(),
+        }
+    }
+}
+
+/// A secure vault for storing encrypted secrets with versioning support.
+pub struct SecretVault {
+    keyring: Keyring,
+    storage: Box<dyn VaultStorage>,
+    data: VaultData,
+    /// Secrets a lazy backend holds that have not been read into `data` yet.
+    unloaded: BTreeSet<String>,
+    /// Secrets changed since the last save.
+    dirty: BTreeSet<String>,
+    audit_logger: AuditLogger,
+    max_retries: u32,
+    /// Whether changes are saved as soon as they are made.
+    auto_save: bool,
+    /// Whether the vault was opened read-only.
+    read_only: bool,
+    /// Whether saves sign a [`Manifest`].
+    manifest: bool,
+    /// Local state file pinning the last generation seen.
+    pin_path: Option<PathBuf>,
+    /// Format version the stored vault was written in, before migrations.
+    stored_format_version: u32,
+}
+
+/// Approximate memory an open vault holds for its secrets, as reported by
+/// [`SecretVault::memory_usage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryUsage {
+    /// Secrets whose entries are held in memory.
+    pub loaded_secrets: usize,
+    /// Bytes held by those entries.
+    pub loaded_bytes: usize,
+    /// Secrets left in storage and read when asked for.
+    pub indexed_secrets: usize,
+    /// Bytes held to find those secrets in storage.
+    pub index_bytes: usize,
+}
+
+impl MemoryUsage {
+    /// Returns the total bytes held.
+    pub fn total_bytes(&self) -> usize {
+        self.loaded_bytes + self.index_bytes
+    }
+}
+
+/// Source for loading the master encryption key.
+pub enum KeySource {
+    /// Load key from environment variable (base64 encoded)
+    Env(String),
+    /// Load key from file (base64 encoded)
+    File(PathBuf),
+    /// Use raw key bytes directly
+    Bytes(Vec<u8>),
+    /// Derive the key from a passphrase with Argon2id, using the salt and cost
+    /// parameters stored in the vault file
+    Passphrase(String),
+}
+
+impl KeySource {
+    /// Loads the master key.
+    ///
+    /// Passphrase sources need the vault's KDF parameters and fail here; the
+    /// builder resolves them through [`KeySource::load_with_kdf`].
+    pub fn load(self) -> Result<MasterKey> {
+        self.load_with_kdf(None)
+    }
+
+    /// Loads the master key, deriving it with `kdf` for passphrase sources.
+    ///
+    /// # Errors
+    /// Returns `VaultError::KeyLoadError` if a passphrase source has no KDF
+    /// parameters or the passphrase does not match the one the vault was
+    /// created with.
+    pub fn load_with_kdf(self, kdf: Option<&KdfParams>) -> Result<MasterKey> {
+        let key_bytes = match self {
+            KeySource::Env(var_name) => {
+                let val = std::env::var(&var_name).map_err(|_| {
+                    VaultError::KeyLoadError(format!("Environment variable {} not found", var_name))
+                })?;
+                general_purpose::STANDARD.decode(val)?
+            }
+            KeySource::File(path) => {
+                let content = fs::read_to_string(&path).map_err(|e| {
+                    VaultError::KeyLoadError(format!("Failed to read key file {:?}: {}", path, e))
+                })?;
+                general_purpose::STANDARD.decode(content.trim())?
+            }
+            KeySource::Bytes(bytes) => bytes,
+            KeySource::Passphrase(passphrase) => {
+                let passphrase = Zeroizing::new(passphrase);
+                let params = kdf.ok_or_else(|| {
+                    VaultError::KeyLoadError(
+                        "Passphrase keys require KDF parameters from the vault file".to_string(),
+                    )
+                })?;
+                let key = MasterKey::new(params.derive(passphrase.as_bytes())?.to_vec())?;
+                if !params.key_id.is_empty() && params.key_id != key.key_id() {
+                    return Err(VaultError::KeyLoadError("Incorrect passphrase".to_string()));
+                }
+                return Ok(key);
+            }
+        };
+        MasterKey::new(key_bytes)
+    }
+
+    fn is_passphrase(&self) -> bool {
+        matches!(self, KeySource::Passphrase(_))
+    }
+}
+
+/// Builder for creating a `SecretVault`.
+pub struct VaultBuilder {
+    master_key: Option<KeySource>,
+    additional_keys: Vec<KeySource>,
+    vault_path: Option<PathBuf>,
+    storage: Option<Box<dyn VaultStorage>>,
+    audit_path: Option<PathBuf>,
+    cipher: CipherKind,
+    kdf_params: Option<KdfParams>,
+    format: StorageFormat,
+    backup: bool,
+    max_retries: u32,
+    auto_save: bool,
+    index_only: bool,
+    read_only: bool,
+    create_if_missing: bool,
+    overwrite: bool,
+    manifest: bool,
+    pin_path: Option<PathBuf>,
+    grace_period: Option<Duration>,
+}
+
+impl VaultBuilder {
+    /// Creates a new vault builder.
+    pub fn new() -> Self {
+        Self {
+            master_key: None,
+            additional_keys: Vec::new(),
+            vault_path: None,
+            storage: None,
+            audit_path: None,
+            cipher: CipherKind::default(),
+            kdf_params: None,
+            format: StorageFormat::default(),
+            backup: false,
+            max_retries: DEFAULT_MAX_RETRIES,
+            auto_save: true,
+            index_only: false,
+            read_only: false,
+            create_if_missing: true,
+            overwrite: false,
+            manifest: false,
+            pin_path: None,
+            grace_period: None,
+        }
+    }
+
+    /// Sets the master key source.
+    pub fn master_key(mut self, key_source: KeySource) -> Self {
+        self.master_key = Some(key_source);
+        self
+    }
+
+    /// Adds a master key that can decrypt entries written under it, without
+    /// using it for new writes. May be called repeatedly.
+    pub fn additional_key(mut self, key_source: KeySource) -> Self {
+        self.additional_keys.push(key_source);
+        self
+    }
+
+    /// Sets the vault file path.
+    pub fn vault_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.vault_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the storage backend, in place of a vault file path.
+    pub fn storage(mut self, storage: impl VaultStorage + 'static) -> Self {
+        self.storage = Some(Box::new(storage));
+        self
+    }
+
+    /// Sets the audit log file path.
+    pub fn audit_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.audit_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the cipher used when creating a new vault.
+    ///
+    /// Existing vaults always keep the cipher recorded in their file.
+    pub fn cipher(mut self, cipher: CipherKind) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Sets the Argon2id cost parameters used when creating a new vault with
+    /// [`KeySource::Passphrase`]. Defaults to [`KdfParams::mobile`].
+    ///
+    /// Existing vaults always keep the parameters recorded in their file.
+    pub fn kdf_params(mut self, params: KdfParams) -> Self {
+        self.kdf_params = Some(params);
+        self
+    }
+
+    /// Sets the format used when creating a new vault file. Defaults to YAML.
+    ///
+    /// Existing files are always opened in the format detected from their
+    /// first bytes.
+    pub fn format(mut self, format: StorageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Keeps a copy of the previous vault file as `<vault>.bak` on every save.
+    ///
+    /// Applies to the file backend created from [`VaultBuilder::vault_path`].
+    pub fn backup(mut self, enabled: bool) -> Self {
+        self.backup = enabled;
+        self
+    }
+
+    /// Sets how many times a change is reapplied when another process saved
+    /// the vault first. Defaults to 3.
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Sets whether `set`, `delete` and the other changing methods save the
+    /// vault immediately. Defaults to `true`.
+    ///
+    /// With auto-save off, changes stay in memory until
+    /// [`SecretVault::save`] is called, and a save that loses a race with
+    /// another writer fails instead of being retried. Rotation and
+    /// [`SecretVault::upgrade`] always save.
+    pub fn auto_save(mut self, enabled: bool) -> Self {
+        self.auto_save = enabled;
+        self
+    }
+
+    /// Opens the vault file index-only: the vault is walked once to record
+    /// where each entry is stored, and entries are read from the file when
+    /// `get` or `get_version` asks for them. Defaults to `false`.
+    ///
+    /// Applies to binary and log-structured files opened through
+    /// [`VaultBuilder::vault_path`]; YAML and sealed files cannot be indexed. SQLite
+    /// vaults always load this way. See [`SecretVault::memory_usage`].
+    pub fn index_only(mut self, enabled: bool) -> Self {
+        self.index_only = enabled;
+        self
+    }
+
+    /// Opens the vault read-only.
+    ///
+    /// Only a shared lock is taken and nothing is ever written: `set`,
+    /// `delete`, `rotate` and the other changing methods fail with
+    /// `VaultError::ReadOnly`. A missing vault fails with
+    /// `VaultError::VaultNotFound` instead of being started empty, so vaults
+    /// on read-only mounts and in read-only containers can be opened.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Sets whether [`VaultBuilder::build`] starts an empty vault when none
+    /// exists yet; it is first written by the first save. Defaults to `true`.
+    ///
+    /// With `false`, a missing vault fails with `VaultError::VaultNotFound`,
+    /// so a mistyped path is reported instead of silently starting over.
+    pub fn create_if_missing(mut self, enabled: bool) -> Self {
+        self.create_if_missing = enabled;
+        self
+    }
+
+    /// Sets whether [`VaultBuilder::create`] replaces an existing vault,
+    /// discarding all of its secrets. Defaults to `false`.
+    pub fn overwrite(mut self, enabled: bool) -> Self {
+        self.overwrite = enabled;
+        self
+    }
+
+    /// Sets whether saves sign a [`Manifest`]: a MAC, derived from the master
+    /// key, over every secret name and version, the vault's settings and its
+    /// generation. Opening the vault then fails with
+    /// `VaultError::ManifestMismatch` if entries or secrets were removed or
+    /// added behind its back, or if its manifest is missing; add one to an
+    /// existing vault with [`SecretVault::sign_manifest`]. Defaults to
+    /// `false`; a vault that has a manifest keeps it either way.
+    pub fn manifest(mut self, enabled: bool) -> Self {
+        self.manifest = enabled;
+        self
+    }
+
+    /// Pins the highest generation seen of the vault in the local state file
+    /// at `path`, which can be shared by several vaults. Opening a vault
+    /// older than its pin fails with `VaultError::RollbackDetected`, and
+    /// opening it without the manifest it had fails too. Turns on
+    /// [`VaultBuilder::manifest`].
+    pub fn pin_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.pin_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets how long deleted secrets of a vault created by this builder are
+    /// kept, restorable with [`SecretVault::undelete`], before saves remove
+    /// them for good. Defaults to 30 days.
+    ///
+    /// The period is stored in the vault; existing vaults keep theirs, which
+    /// [`SecretVault::set_delete_grace_period`] changes.
+    pub fn delete_grace_period(mut self, period: Duration) -> Self {
+        self.grace_period = Some(period);
+        self
+    }
+
+    /// Opens the vault, starting an empty one if none exists and
+    /// [`VaultBuilder::create_if_missing`] allows it.
+    ///
+    /// # Errors
+    /// Returns `VaultError::UnknownKeyId` if the key the vault was last
+    /// written under is not among the loaded keys.
+    pub fn build(self) -> Result<SecretVault> {
+        self.finish(false)
+    }
+
+    /// Creates a new, empty vault and saves it right away.
+    ///
+    /// The format, cipher and KDF settings of the builder apply. A vault
+    /// that cannot be replaced in place, as one stored in another format, is
+    /// replaced by renaming the new vault over it once that is saved, so a
+    /// failure leaves the old vault as it was.
+    ///
+    /// # Errors
+    /// Returns `VaultError::VaultExists` if a vault already exists, unless
+    /// [`VaultBuilder::overwrite`] is set.
+    pub fn create(self) -> Result<SecretVault> {
+        let replaced = match (&self.storage, &self.vault_path) {
+            (None, Some(path)) if self.overwrite && path.exists() => {
+                // Sealed and log vaults cannot be read under the new key to
+                // be replaced in place.
+                let existing = StorageFormat::detect(path)?;
+                (existing != self.format
+                    || matches!(existing, StorageFormat::Sealed | StorageFormat::Log))
+                .then(|| path.clone())
+            }
+            _ => None,
+        };
+        match replaced {
+            Some(path) => self.replace(path),
+            None => self.finish(true),
+        }
+    }
+
+    /// Creates the vault next to the one at `path` and renames it over that
+    /// once it is saved.
+    fn replace(mut self, path: PathBuf) -> Result<SecretVault> {
+        let staged = sidecar_path(&path, "new");
+        remove_staged(&staged)?;
+        let (format, index_only, backup) = (self.format, self.index_only, self.backup);
+        self.vault_path = Some(staged.clone());
+        let result = self.finish(true).and_then(|mut vault| {
+            // Close the staged storage before its file moves.
+            drop(std::mem::replace(
+                &mut vault.storage,
+                Box::new(MemoryStorage::new()),
+            ));
+            rename_durable(&staged, &path)?;
+            vault.storage = open_storage(format, path, index_only, backup, false)?;
+            vault.storage.unlock(&vault.keyring)?;
+            // A staged rotation of the old vault cannot be resumed.
+            vault.storage.store_sidecar(rotation::CHECKPOINT, None)?;
+            Ok(vault)
+        });
+        let removed = remove_staged(&staged);
+        let vault = result?;
+        removed?;
+        Ok(vault)
+    }
+
+    fn finish(self, create: bool) -> Result<SecretVault> {
+        let key_source = self
+            .master_key
+            .ok_or_else(|| VaultError::KeyLoadError("Master key not provided".to_string()))?;
+        let may_create = create || (self.create_if_missing && !self.read_only);
+
+        let (storage, location): (Box<dyn VaultStorage>, String) =
+            match (self.storage, self.vault_path) {
+                (Some(storage), _) => (storage, "the given storage".to_string()),
+                (None, Some(path)) => {
+                    let location = path.display().to_string();
+                    let format = if !path.exists() {
+                        if !may_create {
+                            return Err(VaultError::VaultNotFound(location));
+                        }
+                        self.format
+                    } else if create {
+                        if !self.overwrite {
+                            return Err(VaultError::VaultExists(location));
+                        }
+                        self.format
+                    } else {
+                        StorageFormat::detect(&path)?
+                    };
+                    let storage =
+                        open_storage(format, path, self.index_only, self.backup, self.read_only)?;
+                    (storage, location)
+                }
+                (None, None) => {
+                    return Err(VaultError::InvalidDataFormat(
+                        "Vault path not provided".to_string(),
+                    ))
+                }
+            };
+
+        let stored = load_locked(storage.as_ref())?;
+        let mut replaced = BTreeSet::new();
+        let mut generation = 0;
+        let loaded = match stored {
+            Some(_) if create && !self.overwrite => {
+                return Err(VaultError::VaultExists(location));
+            }
+            Some(existing) if create => {
+                // Saving the new vault deletes every secret of the old one.
+                generation = existing.generation;
+                replaced.extend(existing.secrets.into_keys());
+                replaced.extend(unloaded_names(storage.as_ref())?);
+                None
+            }
+            None if !may_create => return Err(VaultError::VaultNotFound(location)),
+            loaded => loaded,
+        };
+        let is_new = loaded.is_none();
+        let mut data = loaded.unwrap_or_else(|| VaultData {
+            format_version: FORMAT_VERSION,
+            cipher: self.cipher,
+            generation,
+            entries_bound: true,
+            delete_grace_period_secs: self.grace_period.map(|period| period.as_secs()),
+            ..VaultData::default()
+        });
+        check_version(data.format_version)?;
+
+        let is_passphrase = key_source.is_passphrase();
+        if is_passphrase && data.kdf.is_none() {
+            if !is_new {
+                return Err(VaultError::KeyLoadError(
+                    "Vault was not created with a passphrase".to_string(),
+                ));
+            }
+            data.kdf = Some(self.kdf_params.unwrap_or_default());
+        }
+
+        let master_key = key_source.load_with_kdf(data.kdf.as_ref())?;
+        if let Some(kdf) = data.kdf.as_mut().filter(|_| is_passphrase) {
+            if kdf.key_id.is_empty() {
+                kdf.key_id = master_key.key_id();
+            }
+        }
+
+        let mut keyring = Keyring::new(master_key);
+        for source in self.additional_keys {
+            if source.is_passphrase() {
+                return Err(VaultError::KeyLoadError(
+                    "Only the active master key may be a passphrase".to_string(),
+                ));
+            }
+            keyring.insert(source.load()?);
+        }
+
+        if storage.is_sealed() {
+            // Only the header could be read so far; the rest needs the keys.
+            storage.unlock(&keyring)?;
+            if !is_new {
+                data = load_locked(storage.as_ref())?
+                    .ok_or_else(|| VaultError::VaultNotFound(location.clone()))?;
+                check_version(data.format_version)?;
+            }
+        }
+        let unloaded = if is_new {
+            BTreeSet::new()
+        } else {
+            unloaded_names(storage.as_ref())?
+        };
+        // Writing under an unrelated key would mix two keys' entries.
+        if !is_new && !data.active_key_id.is_empty() && !keyring.contains(&data.active_key_id) {
+            return Err(VaultError::UnknownKeyId(data.active_key_id));
+        }
+        if data.vault_id.is_empty() {
+            data.vault_id = new_vault_id();
+        }
+
+        let mut vault = SecretVault {
+            keyring,
+            storage,
+            data,
+            unloaded,
+            dirty: replaced,
+            audit_logger: AuditLogger::new(self.audit_path.as_deref()),
+            max_retries: self.max_retries,
+            auto_save: self.auto_save,
+            read_only: self.read_only,
+            manifest: self.manifest || self.pin_path.is_some(),
+            pin_path: self.pin_path,
+            stored_format_version: FORMAT_VERSION,
+        };
+        vault.authenticate(self.manifest && !is_new)?;
+        vault.migrate_loaded()?;
+        vault.data.active_key_id = vault.keyring.active_id().to_string();
+        if create {
+            vault.abort_rotation()?;
+            vault.save()?;
+        }
+        Ok(vault)
+    }
+}
+
+impl Default for VaultBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SecretVault {
+    /// Creates a new vault builder.
+    pub fn builder() -> VaultBuilder {
+        VaultBuilder::new()
+    }
+
+    /// Creates a new, empty vault file at `path` and saves it.
+    ///
+    /// # Errors
+    /// Returns `VaultError::VaultExists` if `path` already holds a vault; use
+    /// [`VaultBuilder::overwrite`] to replace it.
+    pub fn create(master_key: KeySource, path: &Path, audit_path: Option<&Path>) -> Result<Self> {
+        let mut builder = VaultBuilder::new().master_key(master_key).vault_path(path);
+        if let Some(audit) = audit_path {
+            builder = builder.audit_path(audit);
+        }
+        builder.create()
+    }
+
+    /// Opens the existing vault file at `path`.
+    ///
+    /// # Errors
+    /// Returns `VaultError::VaultNotFound` if `path` does not exist, where
+    /// [`SecretVault::new`] would start an empty vault.
+    pub fn open(master_key: KeySource, path: &Path, audit_path: Option<&Path>) -> Result<Self> {
+        let mut builder = VaultBuilder::new()
+            .master_key(master_key)
+            .vault_path(path)
+            .create_if_missing(false);
+        if let Some(audit) = audit_path {
+            builder = builder.audit_path(audit);
+        }
+        builder.build()
+    }
+
+    /// Creates a new secret vault, or opens the one at `path`.
+    ///
+    /// # Arguments
+    /// * `master_key` - Source for the master encryption key
+    /// * `path` - Path to the vault file
+    /// * `audit_path` - Optional path to the audit log file
+    pub fn new(master_key: KeySource, path: &Path, audit_path: Option<&Path>) -> Result<Self> {
+        let mut builder = VaultBuilder::new().master_key(master_key).vault_path(path);
+
+        if let Some(audit) = audit_path {
+            builder = builder.audit_path(audit);
+        }
+
+        builder.build()
+    }
+
+    /// Returns the cipher this vault encrypts its secrets with.
+    pub fn cipher(&self) -> CipherKind {
+        self.data.cipher
+    }
+
+    /// Saves the vault to disk.
+    ///
+    /// The file is replaced atomically and durably: a crash leaves either the
+    /// previous vault or the new one, never a truncated file. Secrets deleted
+    /// longer ago than the vault's
+    /// [grace period](SecretVault::delete_grace_period) are purged as part
+    /// of the save.
+    ///
+    /// # Errors
+    /// Returns `VaultError::ConcurrentModification` if another process saved
+    /// the vault since it was loaded. With auto-save on, the methods that
+    /// change the vault handle this themselves by reloading and reapplying
+    /// their change.
+    pub fn save(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let purged = self.stage_purge_expired();
+        if self.manifest {
+            let versions = self.stored_versions()?;
+            self.data.manifest = Some(Manifest::sign(
+                self.keyring.active(),
+                &self.data,
+                self.data.generation + 1,
+                &versions,
+            ));
+        }
+        let changed: Vec<(String, Option<Vec<SecretEntry>>)> = self
+            .dirty
+            .iter()
+            .map(|name| (name.clone(), self.data.secrets.get(name).cloned()))
+            .collect();
+        let changes: Vec<(&str, Option<&[SecretEntry]>)> = changed
+            .iter()
+            .map(|(name, entries)| (name.as_str(), entries.as_deref()))
+            .collect();
+        // A sealed backend seals under the active key, which may have changed.
+        self.storage.unlock(&self.keyring)?;
+        self.storage.save_changes(&mut self.data, &changes)?;
+        self.dirty.clear();
+        self.stored_format_version = self.data.format_version;
+        self.evict_saved();
+        self.pin()?;
+        for name in &purged {
+            self.audit_logger.log(Operation::Purge, name)?;
+        }
+        Ok(())
+    }
+
+    /// Signs a [`Manifest`] on this and every later save.
+    ///
+    /// Use this to add a manifest to a vault that has none, such as one
+    /// created without [`VaultBuilder::manifest`] or merged without the key;
+    /// until then, opening it with [`VaultBuilder::manifest`] fails.
+    pub fn sign_manifest(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.manifest = true;
+        self.modify_and_save(|_| Ok(true))?;
+        Ok(())
+    }
+
+    /// Returns the format version the stored vault is written in.
+    ///
+    /// Older vaults are upgraded in memory when opened, and stored in the
+    /// current [`FORMAT_VERSION`] by the next save.
+    pub fn stored_format_version(&self) -> u32 {
+        self.stored_format_version
+    }
+
+    /// Rewrites the stored vault in the current format if it is older.
+    ///
+    /// Returns the version it was upgraded from, or `None` if the vault was
+    /// already current.
+    pub fn upgrade(&mut self) -> Result<Option<u32>> {
+        let from = self.stored_format_version;
+        if from == FORMAT_VERSION {
+            return Ok(None);
+        }
+        self.modify_and_save(|_| Ok(true))?;
+        Ok(Some(from))
+    }
+
+    /// Rewrites the storage in its most compact form, such as folding a
+    /// [`LogStorage`](crate::storage::LogStorage) log into one snapshot.
+    ///
+    /// Returns whether anything was rewritten. Unsaved changes are not
+    /// included.
+    pub fn compact(&self) -> Result<bool> {
+        self.check_writable()?;
+        self.storage.compact()
+    }
+
+    /// Returns the generation of the stored vault this vault was last loaded
+    /// from or saved as.
+    pub fn generation(&self) -> u64 {
+        self.data.generation
+    }
+
+    /// Returns whether the vault was opened [read-only](VaultBuilder::read_only).
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Reports the memory held for the vault's secrets.
+    ///
+    /// With a lazy backend, such as an [index-only](VaultBuilder::index_only)
+    /// file, this stays bounded however long the history grows: entries are
+    /// read when asked for and dropped again once saved, so only the index
+    /// and unsaved changes are kept. Operations over the whole vault, such as
+    /// rotation, read every secret while they run.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            loaded_secrets: self.data.secrets.len(),
+            loaded_bytes: self
+                .data
+                .secrets
+                .iter()
+                .map(|(name, entries)| name.len() + entries.iter().map(entry_size).sum::<usize>())
+                .sum(),
+            indexed_secrets: self.unloaded.len(),
+            index_bytes: self.unloaded.iter().map(String::len).sum::<usize>()
+                + self.storage.index_size(),
+        }
+    }
+
+    /// Returns the storage backend this vault persists to.
+    pub fn storage(&self) -> &dyn VaultStorage {
+        self.storage.as_ref()
+    }
+
+    /// Rereads the vault from storage, discarding unsaved changes.
+    ///
+    /// # Errors
+    /// Returns `VaultError::UnknownKeyId` if the file is now written under a
+    /// master key that is not loaded, e.g. after another process rotated it.
+    pub fn reload(&mut self) -> Result<()> {
+        let data = load_locked(self.storage.as_ref())?.ok_or_else(|| {
+            VaultError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "vault was removed from storage",
+            ))
+        })?;
+        check_version(data.format_version)?;
+        if !data.active_key_id.is_empty() && !self.keyring.contains(&data.active_key_id) {
+            return Err(VaultError::UnknownKeyId(data.active_key_id));
+        }
+        self.unloaded = unloaded_names(self.storage.as_ref())?;
+        self.data = data;
+        self.dirty.clear();
+        // Once a vault is signed, a copy without a manifest was stripped.
+        self.authenticate(self.manifest)?;
+        self.migrate_loaded()?;
+        self.data.active_key_id = self.keyring.active_id().to_string();
+        Ok(())
+    }
+
+    /// Copies the whole vault into `target`, which must not hold a vault yet.
+    ///
+    /// This converts a vault between storage backends; the copy starts a new
+    /// generation history and this vault keeps using its own storage.
+    pub fn export_to(&mut self, target: &dyn VaultStorage) -> Result<()> {
+        self.load_all()?;
+        target.unlock(&self.keyring)?;
+        if target.current_generation()? != 0 {
+            return Err(VaultError::Storage(
+                "Target storage already holds a vault".to_string(),
+            ));
+        }
+        let mut copy = self.data.clone();
+        copy.generation = 0;
+        if self.manifest {
+            copy.manifest = Some(Manifest::sign(
+                self.keyring.active(),
+                &copy,
+                1,
+                &manifest::versions_of(&copy.secrets),
+            ));
+        }
+        target.save(&mut copy)
+    }
+
+    /// Sets or updates a secret.
+    ///
+    /// # Arguments
+    /// * `key` - The secret identifier
+    /// * `value` - The secret value to encrypt and store
+    ///
+    /// # Errors
+    /// Returns `VaultError::SecretDeleted` if `key` is deleted; restore it
+    /// with [`SecretVault::undelete`] or remove it with
+    /// [`SecretVault::purge`] first.
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        validate_secret_key(key)?;
+
+        let mut pruned = Vec::new();
+        self.modify(|vault| {
+            pruned = vault.stage_set(key, value)?;
+            Ok(true)
+        })?;
+        self.audit_logger.log(Operation::Set, key)?;
+        self.log_pruned(key, &pruned)
+    }
+
+    /// Gets the latest version of a secret.
+    ///
+    /// # Arguments
+    /// * `key` - The secret identifier
+    ///
+    /// # Returns
+    /// The decrypted secret value, or None if the secret doesn't exist.
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.audit_logger.log(Operation::Get, key)?;
+        if self.is_deleted(key) {
+            return Ok(None);
+        }
+        if let Some(latest) = self.entry(key, None)? {
+            let decrypted = self.crypto().open(key, &latest)?;
+            return Ok(Some(decrypted));
+        }
+        Ok(None)
+    }
+
+    /// Gets a specific version of a secret.
+    ///
+    /// # Arguments
+    /// * `key` - The secret identifier
+    /// * `version` - The version number to retrieve
+    ///
+    /// # Returns
+    /// The decrypted secret value for the specified version.
+    pub fn get_version(&self, key: &str, version: u32) -> Result<Option<Vec<u8>>> {
+        self.audit_logger.log(Operation::Get, key)?;
+        if self.is_deleted(key) {
+            return Ok(None);
+        }
+
+        if let Some(entry) = self.entry(key, Some(version))? {
+            let decrypted = self.crypto().open(key, &entry)?;
+            return Ok(Some(decrypted));
+        }
+        Ok(None)
+    }
+
+    /// Deletes a secret, leaving a tombstone in its place.
+    ///
+    /// The secret disappears from `get`, `list_keys` and `list_versions` at
+    /// once, but its versions are kept so that [`SecretVault::undelete`] can
+    /// restore it, until [`SecretVault::purge`] removes it or the grace
+    /// period of [`VaultBuilder::delete_grace_period`] runs out.
+    ///
+    /// # Arguments
+    /// * `key` - The secret identifier
+    pub fn delete(&mut self, key: &str) -> Result<()> {
+        let deleted = self.modify(|vault| Ok(vault.stage_delete(key)))?;
+        if deleted {
+            self.audit_logger.log(Operation::Delete, key)?;
+        }
+        Ok(())
+    }
+
+    /// Lists all available versions for a secret.
+    ///
+    /// # Arguments
+    /// * `key` - The secret identifier
+    ///
+    /// # Returns
+    /// A vector of version numbers, or an empty vector if the secret doesn't exist.
+    pub fn list_versions(&self, key: &str) -> Result<Vec<u32>> {
+        if self.is_deleted(key) {
+            return Ok(vec![]);
+        }
+        if let Some(entries) = self.entries(key)? {
+            Ok(entries.iter().map(|e| e.version).collect())
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    /// Lists all secret keys in the vault, except deleted ones.
+    pub fn list_keys(&self) -> Vec<String> {
+        self.data
+            .secrets
+            .keys()
+            .chain(&self.unloaded)
+            .filter(|name| !self.is_deleted(name))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the ID of the key that wraps the data keys of new entries.
+    pub fn active_key_id(&self) -> &str {
+        self.keyring.active_id()
+    }
+
+    /// Adds a master key to the keyring of this handle. The keyring is not
+    /// stored; later opens need the key given again.
+    ///
+    /// When `activate` is set, new writes use the added key immediately while
+    /// existing entries keep decrypting under their original key. Passphrase
+    /// keys cannot be added this way; use [`SecretVault::rotate`].
+    ///
+    /// # Returns
+    /// The ID of the added key.
+    pub fn add_key(&mut self, source: KeySource, activate: bool) -> Result<String> {
+        if source.is_passphrase() {
+            return Err(VaultError::KeyLoadError(
+                "Passphrase keys can only be introduced by rotate".to_string(),
+            ));
+        }
+        let key_id = self.keyring.insert(source.load()?);
+        if activate {
+            self.set_active_key(&key_id)?;
+        }
+        Ok(key_id)
+    }
+
+    /// Makes a key already in this handle's keyring the active key for new
+    /// writes.
+    ///
+    /// If the change cannot be saved, the previous key stays active.
+    pub fn set_active_key(&mut self, key_id: &str) -> Result<()> {
+        let previous = self.keyring.active_id().to_string();
+        let result = self.modify(|vault| {
+            vault.keyring.set_active(key_id)?;
+            vault.data.active_key_id = key_id.to_string();
+            Ok(true)
+        });
+        if let Err(e) = result {
+            self.keyring.set_active(&previous)?;
+            self.data.active_key_id = previous;
+            return Err(e);
+        }
+        self.audit_logger.log(Operation::Rotate, key_id)?;
+        Ok(())
+    }
+
+    /// Reports how many entries each master key still protects.
+    ///
+    /// Every key in the keyring is listed, including those with no entries.
+    /// Keys referenced by entries but not loaded are listed too. Shredded
+    /// entries depend on no key and are not counted.
+    pub fn key_usage(&self) -> Result<BTreeMap<String, usize>> {
+        let mut usage: BTreeMap<String, usize> =
+            self.keyring.ids().map(|id| (id.to_string(), 0)).collect();
+        let mut count = |entries: &[SecretEntry]| {
+            for entry in entries.iter().filter(|entry| !entry.shredded) {
+                *usage.entry(entry.key_id.clone()).or_default() += 1;
+            }
+        };
+        for entries in self.data.secrets.values() {
+            count(entries);
+        }
+        for name in &self.unloaded {
+            count(&self.read_secret(name)?.unwrap_or_default());
+        }
+        Ok(usage)
+    }
+
+    /// Rewraps entries that are not yet protected by the active key.
+    ///
+    /// This is the lazy half of a rotation: call it with a `limit` to migrate
+    /// a vault in small batches while it stays in service.
+    ///
+    /// # Returns
+    /// The number of entries rewrapped.
+    pub fn rewrap_to_active(&mut self, limit: Option<usize>) -> Result<usize> {
+        let mut count = 0;
+        self.modify(|vault| {
+            vault.load_all()?;
+            let crypto = vault.crypto();
+            let active = vault.keyring.active_id();
+            let mut rewrapped = Vec::new();
+            'outer: for (key, entries) in &vault.data.secrets {
+                for (index, entry) in entries.iter().enumerate() {
+                    if limit.is_some_and(|limit| rewrapped.len() >= limit) {
+                        break 'outer;
+                    }
+                    if entry.shredded || entry.key_id == active {
+                        continue;
+                    }
+                    rewrapped.push((key.clone(), index, crypto.rewrap(key, entry)?));
+                }
+            }
+
+            count = rewrapped.len();
+            vault.replace_entries(rewrapped);
+            Ok(count > 0)
+        })?;
+        if count > 0 {
+            self.audit_logger
+                .log(Operation::Rotate, self.keyring.active_id())?;
+        }
+        Ok(count)
+    }
+
+    /// Removes a master key from the keyring of this handle once no entry
+    /// depends on it, confirming that the key can be destroyed. The keyring
+    /// is not stored, so later opens accept the key if it is given again.
+    ///
+    /// # Errors
+    /// Returns `VaultError::KeyInUse` if the key is active or still protects
+    /// entries, and `VaultError::UnknownKeyId` if it is not in the keyring.
+    pub fn retire_key(&mut self, key_id: &str) -> Result<()> {
+        if !self.keyring.contains(key_id) {
+            return Err(VaultError::UnknownKeyId(key_id.to_string()));
+        }
+        let entries = self.key_usage()?.get(key_id).copied().unwrap_or(0);
+        let active = key_id == self.keyring.active_id();
+        if active || entries > 0 {
+            return Err(VaultError::KeyInUse {
+                key_id: key_id.to_string(),
+                entries,
+                active,
+            });
+        }
+        self.keyring.remove(key_id);
+        Ok(())
+    }
+
+    /// Destroys the data-encryption key of one version of a secret.
+    ///
+    /// The version stays in the history, but its value can never be decrypted
+    /// again. Older copies of the vault file still hold the wrapped key, so
+    /// rotate the master key and discard the old one to make shredding final.
+    ///
+    /// # Returns
+    /// `true` if the version existed and was shredded by this call.
+    pub fn shred_version(&mut self, key: &str, version: u32) -> Result<bool> {
+        let shredded = self.modify(|vault| {
+            vault.load_secret(key)?;
+            let Some(entry) = vault
+                .data
+                .secrets
+                .get_mut(key)
+                .and_then(|entries| entries.iter_mut().find(|e| e.version == version))
+            else {
+                return Ok(false);
+            };
+            if entry.shredded {
+                return Ok(false);
+            }
+
+            entry.wrapped_dek = None;
+            entry.encrypted_value.zeroize();
+            entry.encrypted_value.clear();
+            entry.key_id.clear();
+            entry.shredded = true;
+            vault.dirty.insert(key.to_string());
+            Ok(true)
+        })?;
+        if !shredded {
+            return Ok(false);
+        }
+
+        self.audit_logger
+            .log(Operation::Shred, &format!("{}@{}", key, version))?;
+        Ok(true)
+    }
+
+    /// Re-encrypts entries written before associated data binding or data
+    /// keys existed so that they are sealed under a data key and bound to
+    /// this vault, their secret name and version.
+    ///
+    /// The vault is then marked [`VaultData::entries_bound`], so that
+    /// entries are never again decrypted the legacy way.
+    ///
+    /// # Returns
+    /// The number of entries that were migrated.
+    pub fn bind_legacy_entries(&mut self) -> Result<usize> {
+        let mut count = 0;
+        self.modify(|vault| {
+            vault.load_all()?;
+            let crypto = vault.crypto();
+            let mut migrated = Vec::new();
+            for (key, entries) in &vault.data.secrets {
+                for (index, entry) in entries.iter().enumerate() {
+                    if entry.shredded || (entry.aad_bound && entry.wrapped_dek.is_some()) {
+                        continue;
+                    }
+                    let decrypted = Zeroizing::new(crypto.open(key, entry)?);
+                    let sealed = SecretEntry {
+                        created_at: entry.created_at,
+                        ..crypto.seal(key, entry.version, &decrypted)?
+                    };
+                    migrated.push((key.clone(), index, sealed));
+                }
+            }
+
+            count = migrated.len();
+            vault.replace_entries(migrated);
+            let marked = !vault.data.entries_bound;
+            vault.data.entries_bound = true;
+            Ok(count > 0 || marked)
+        })?;
+        Ok(count)
+    }
+
+    /// Appends a new version of `key` in memory and applies its retention
+    /// policy, returning the versions that were pruned.
+    fn stage_set(&mut self, key: &str, value: &[u8]) -> Result<Vec<u32>> {
+        if self.is_deleted(key) {
+            return Err(VaultError::SecretDeleted(key.to_string()));
+        }
+        self.load_secret(key)?;
+        let version = self
+            .data
+            .secrets
+            .get(key)
+            .and_then(|entries| entries.last())
+            .map(|e| e.version + 1)
+            .unwrap_or(1);
+        let entry = self.crypto().seal(key, version, value)?;
+
+        self.data
+            .secrets
+            .entry(key.to_string())
+            .or_default()
+            .push(entry);
+        self.dirty.insert(key.to_string());
+        Ok(self.stage_prune(key))
+    }
+
+    /// Marks `key` deleted in memory, returning whether it existed and was
+    /// not deleted already.
+    fn stage_delete(&mut self, key: &str) -> bool {
+        let exists = self.data.secrets.contains_key(key) || self.unloaded.contains(key);
+        if !exists || self.is_deleted(key) {
+            return false;
+        }
+        self.data
+            .tombstones
+            .insert(key.to_string(), chrono::Utc::now());
+        true
+    }
+
+    /// Applies `change` to the in-memory vault and, with auto-save on, saves
+    /// it through [`SecretVault::modify_and_save`].
+    ///
+    /// # Returns
+    /// Whether the vault was changed.
+    fn modify(&mut self, mut change: impl FnMut(&mut Self) -> Result<bool>) -> Result<bool> {
+        self.check_writable()?;
+        if !self.auto_save {
+            return change(self);
+        }
+        self.modify_and_save(change)
+    }
+
+    /// Applies `change` to the in-memory vault and saves it if `change`
+    /// reports that it changed anything.
+    ///
+    /// If another process saved the vault in the meantime, whether noticed
+    /// while `change` read entries or while saving, the file is reloaded and
+    /// `change` is applied again, up to `max_retries` times.
+    ///
+    /// # Returns
+    /// Whether the vault was changed and saved.
+    fn modify_and_save(
+        &mut self,
+        mut change: impl FnMut(&mut Self) -> Result<bool>,
+    ) -> Result<bool> {
+        let mut retries = 0;
+        loop {
+            let saved = change(self).and_then(|changed| {
+                if changed {
+                    self.save()?;
+                }
+                Ok(changed)
+            });
+            match saved {
+                Err(VaultError::ConcurrentModification { .. }) if retries < self.max_retries => {
+                    retries += 1;
+                    self.reload()?;
+                }
+                saved => return saved,
+            }
+        }
+    }
+
+    /// Fails with `VaultError::ReadOnly` if the vault was opened read-only.
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(VaultError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn replace_entries(&mut self, replacements: Vec<(String, usize, SecretEntry)>) {
+        for (key, index, replacement) in replacements {
+            if let Some(entry) = self
+                .data
+                .secrets
+                .get_mut(&key)
+                .and_then(|entries| entries.get_mut(index))
+            {
+                *entry = replacement;
+                self.dirty.insert(key);
+            }
+        }
+    }
+
+    /// Returns the entries of `name`, reading them from a lazy backend if
+    /// they are not in memory yet.
+    fn entries(&self, name: &str) -> Result<Option<Cow<'_, [SecretEntry]>>> {
+        if let Some(entries) = self.data.secrets.get(name) {
+            return Ok(Some(Cow::Borrowed(entries)));
+        }
+        if !self.unloaded.contains(name) {
+            return Ok(None);
+        }
+        Ok(self.read_secret(name)?.map(Cow::Owned))
+    }
+
+    /// Returns one entry of `name`: `version`, or the latest with `None`,
+    /// reading just that entry from a lazy backend.
+    fn entry(&self, name: &str, version: Option<u32>) -> Result<Option<Cow<'_, SecretEntry>>> {
+        if let Some(entries) = self.data.secrets.get(name) {
+            let entry = match version {
+                Some(version) => entries.iter().find(|e| e.version == version),
+                None => entries.last(),
+            };
+            return Ok(entry.map(Cow::Borrowed));
+        }
+        if !self.unloaded.contains(name) {
+            return Ok(None);
+        }
+        let mut entry = self.storage.load_entry(name, version)?;
+        if let Some(entry) = entry.as_mut() {
+            backfill_entries(std::slice::from_mut(entry), self.keyring.active_id());
+        }
+        Ok(entry.map(Cow::Owned))
+    }
+
+    fn read_secret(&self, name: &str) -> Result<Option<Vec<SecretEntry>>> {
+        let mut entries = self.storage.load_secret(name)?;
+        if let Some(entries) = entries.as_mut() {
+            backfill_entries(entries, self.keyring.active_id());
+        }
+        Ok(entries)
+    }
+
+    /// Reads the entries of `name` into memory so they can be changed.
+    fn load_secret(&mut self, name: &str) -> Result<()> {
+        if self.unloaded.contains(name) {
+            if let Some(entries) = self.read_secret(name)? {
+                self.data.secrets.insert(name.to_string(), entries);
+            }
+            self.unloaded.remove(name);
+        }
+        Ok(())
+    }
+
+    /// Reads every secret into memory, for operations over the whole vault.
+    fn load_all(&mut self) -> Result<()> {
+        while let Some(name) = self.unloaded.first().cloned() {
+            self.load_secret(&name)?;
+        }
+        Ok(())
+    }
+
+    /// Drops saved secrets from memory when the backend can read them back,
+    /// so that memory does not grow with every secret that is changed.
+    fn evict_saved(&mut self) {
+        if !self.storage.is_lazy() {
+            return;
+        }
+        let saved: Vec<String> = self
+            .data
+            .secrets
+            .keys()
+            .filter(|name| !self.dirty.contains(*name))
+            .cloned()
+            .collect();
+        for name in saved {
+            self.data.secrets.remove(&name);
+            self.unloaded.insert(name);
+        }
+    }
+
+    /// Checks the loaded vault against its manifest and the pinned state
+    /// file, then pins its generation. With `required`, a vault without a
+    /// manifest is refused.
+    fn authenticate(&mut self, required: bool) -> Result<()> {
+        let pin = match &self.pin_path {
+            Some(path) => manifest::read_pin(path, &self.data.vault_id)?,
+            None => None,
+        };
+        if let Some(pin) = pin.filter(|pin| pin.generation > self.data.generation) {
+            return Err(VaultError::RollbackDetected {
+                pinned: pin.generation,
+                found: self.data.generation,
+            });
+        }
+        match &self.data.manifest {
+            Some(manifest) => {
+                manifest.verify(&self.keyring, &self.data, &self.stored_versions()?)?
+            }
+            None if pin.is_some_and(|pin| pin.manifest) => {
+                return Err(VaultError::ManifestMismatch(
+                    "the vault had a manifest, which was removed".to_string(),
+                ))
+            }
+            None if required => {
+                return Err(VaultError::ManifestMismatch(
+                    "the vault has no manifest; sign one with `sign_manifest`".to_string(),
+                ))
+            }
+            None => {}
+        }
+        self.manifest |= self.data.manifest.is_some();
+        self.pin()
+    }
+
+    /// Raises the pinned generation to the current one, unless read-only.
+    fn pin(&self) -> Result<()> {
+        match &self.pin_path {
+            Some(path) if !self.read_only => manifest::write_pin(
+                path,
+                &self.data.vault_id,
+                manifest::Pin {
+                    generation: self.data.generation,
+                    manifest: self.data.manifest.is_some(),
+                },
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Lists the versions of every secret, reading those not in memory from
+    /// a lazy backend without loading their entries.
+    fn stored_versions(&self) -> Result<BTreeMap<String, Vec<u32>>> {
+        let mut versions = manifest::versions_of(&self.data.secrets);
+        for name in &self.unloaded {
+            versions.insert(name.clone(), self.storage.entry_versions(name)?);
+        }
+        Ok(versions)
+    }
+
+    /// Upgrades freshly loaded data to the current format. Every secret is
+    /// marked changed so that the next save stores the new layout.
+    fn migrate_loaded(&mut self) -> Result<()> {
+        self.stored_format_version = self.data.format_version;
+        if self.data.format_version == FORMAT_VERSION {
+            return Ok(());
+        }
+        self.load_all()?;
+        let ctx = migration::MigrationContext {
+            active_key_id: self.keyring.active_id(),
+        };
+        migration::migrate(&mut self.data, &ctx)?;
+        self.dirty.extend(self.data.secrets.keys().cloned());
+        Ok(())
+    }
+
+    fn crypto(&self) -> EntryCrypto<'_> {
+        EntryCrypto {
+            cipher: self.data.cipher,
+            keyring: &self.keyring,
+            vault_id: &self.data.vault_id,
+            bound_only: self.data.entries_bound,
+        }
+    }
+}
+
+/// Returns the approximate heap bytes of an entry.
+fn entry_size(entry: &SecretEntry) -> usize {
+    size_of::<SecretEntry>()
+        + entry.encrypted_value.capacity()
+        + entry.wrapped_dek.as_ref().map_or(0, Vec::capacity)
+        + entry.key_id.capacity()
+}
+
+/// Opens the vault file at `path` in `format`.
+fn open_storage(
+    format: StorageFormat,
+    path: PathBuf,
+    index_only: bool,
+    backup: bool,
+    read_only: bool,
+) -> Result<Box<dyn VaultStorage>> {
+    Ok(match format {
+        StorageFormat::Yaml | StorageFormat::Sealed if index_only => {
+            return Err(VaultError::InvalidDataFormat(
+                "Index-only loading needs a binary or log-structured vault file".to_string(),
+            ))
+        }
+        StorageFormat::Yaml => Box::new(FileStorage::new(path).backup(backup)),
+        StorageFormat::Binary => Box::new(
+            FileStorage::binary(path)
+                .backup(backup)
+                .index_only(index_only),
+        ),
+        StorageFormat::Log => Box::new(LogStorage::new(path).index_only(index_only)),
+        StorageFormat::Sealed => Box::new(SealedStorage::new(path).backup(backup)),
+        format if read_only => format.open_read_only(&path)?,
+        format => format.open(&path)?,
+    })
+}
+
+/// Removes a vault file staged by [`VaultBuilder::create`] and its lock
+/// file, if they exist.
+fn remove_staged(path: &Path) -> Result<()> {
+    for path in [path.to_path_buf(), sidecar_path(path, "lock")] {
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+fn load_locked(storage: &dyn VaultStorage) -> Result<Option<VaultData>> {
+    let _lock = storage.lock(LockMode::Shared)?;
+    storage.load()
+}
+
+/// Names of the secrets a lazy backend leaves to be read on demand.
+fn unloaded_names(storage: &dyn VaultStorage) -> Result<BTreeSet<String>> {
+    if !storage.is_lazy() {
+        return Ok(BTreeSet::new());
+    }
+    Ok(storage.secret_names()?.into_iter().collect())
+}
+
+/// Fills in the key ID of entries written before key IDs were recorded.
+///
+/// Such entries belong to the key the vault was last written with, or to the
+/// only key it ever had.
+fn backfill_key_ids(data: &mut VaultData, active_key_id: &str) {
+    let fallback_key_id = if data.active_key_id.is_empty() {
+        active_key_id.to_string()
+    } else {
+        data.active_key_id.clone()
+    };
+    for entries in data.secrets.values_mut() {
+        backfill_entries(entries, &fallback_key_id);
+    }
+}
+
+fn backfill_entries(entries: &mut [SecretEntry], fallback_key_id: &str) {
+    for entry in entries {
+        if entry.key_id.is_empty() && !entry.shredded {
+            entry.key_id = entry
+                .wrapped_dek
+                .as_deref()
+                .and_then(|wrapped| EnvelopeHeader::parse(wrapped).ok().flatten())
+                .map(|(header, _)| header.key_id)
+                .filter(|id| !id.is_empty())
+                .unwrap_or_else(|| fallback_key_id.to_string());
+        }
+    }
+}
+
+/// Encrypts and decrypts individual entries with the associated data that
+/// pins each ciphertext to its vault, secret name and version.
+///
+/// New entries use envelope encryption: the value is sealed under a fresh
+/// random data-encryption key (DEK), and only the DEK is sealed under the
+/// active master key of the keyring.
+#[derive(Clone, Copy)]
+struct EntryCrypto<'a> {
+    cipher: CipherKind,
+    keyring: &'a Keyring,
+    vault_id: &'a str,
+    /// Refuse entries that are unbound or have no data key, as in a vault
+    /// marked [`VaultData::entries_bound`].
+    bound_only: bool,
+}
+
+impl EntryCrypto<'_> {
+    fn seal(&self, name: &str, version: u32, value: &[u8]) -> Result<SecretEntry> {
+        let mut dek = Zeroizing::new(vec![0u8; KEY_SIZE]);
+        OsRng.fill_bytes(&mut dek);
+
+        let encrypted_value = encrypt_with(
+            self.cipher,
+            "",
+            &dek,
+            value,
+            &entry_aad(VALUE_DOMAIN, self.vault_id, name, version),
+        )?;
+
+        let master = self.keyring.active();
+        Ok(SecretEntry {
+            encrypted_value,
+            version,
+            created_at: chrono::Utc::now(),
+            aad_bound: true,
+            wrapped_dek: Some(self.wrap_dek(master, name, version, &dek)?),
+            shredded: false,
+            key_id: master.key_id(),
+        })
+    }
+
+    fn open(&self, name: &str, entry: &SecretEntry) -> Result<Vec<u8>> {
+        if entry.shredded {
+            return Err(VaultError::SecretShredded {
+                key: name.to_string(),
+                version: entry.version,
+            });
+        }
+        if self.bound_only && (!entry.aad_bound || entry.wrapped_dek.is_none()) {
+            return Err(integrity_error(
+                VaultError::DecryptionFailed("entry is not bound to its slot".to_string()),
+                name,
+                entry.version,
+            ));
+        }
+        let master = self.master_for(entry)?;
+        if !entry.aad_bound {
+            return decrypt_with(self.cipher, master.as_bytes(), &entry.encrypted_value, b"");
+        }
+
+        let aad = entry_aad(VALUE_DOMAIN, self.vault_id, name, entry.version);
+        let result = match &entry.wrapped_dek {
+            Some(wrapped) => {
+                let dek = self.unwrap_dek(master, name, entry.version, wrapped)?;
+                decrypt_with(self.cipher, &dek, &entry.encrypted_value, &aad)
+            }
+            None => decrypt_with(self.cipher, master.as_bytes(), &entry.encrypted_value, &aad),
+        };
+        result.map_err(|e| integrity_error(e, name, entry.version))
+    }
+
+    /// Moves an entry to the active master key.
+    ///
+    /// Envelope entries only have their DEK rewrapped; the value ciphertext is
+    /// untouched. Entries still encrypted directly under a master key are
+    /// converted to envelope form.
+    fn rewrap(&self, name: &str, entry: &SecretEntry) -> Result<SecretEntry> {
+        if entry.shredded {
+            return Ok(entry.clone());
+        }
+
+        match &entry.wrapped_dek {
+            Some(wrapped) => {
+                let old = self.master_for(entry)?;
+                let new = self.keyring.active();
+                let dek = self.unwrap_dek(old, name, entry.version, wrapped)?;
+                Ok(SecretEntry {
+                    wrapped_dek: Some(self.wrap_dek(new, name, entry.version, &dek)?),
+                    key_id: new.key_id(),
+                    ..entry.clone()
+                })
+            }
+            None => {
+                let value = Zeroizing::new(self.open(name, entry)?);
+                Ok(SecretEntry {
+                    created_at: entry.created_at,
+                    ..self.seal(name, entry.version, &value)?
+                })
+            }
+        }
+    }
+
+    fn master_for(&self, entry: &SecretEntry) -> Result<&MasterKey> {
+        if entry.key_id.is_empty() {
+            return Ok(self.keyring.active());
+        }
+        self.keyring.get(&entry.key_id)
+    }
+
+    fn wrap_dek(
+        &self,
+        master: &MasterKey,
+        name: &str,
+        version: u32,
+        dek: &[u8],
+    ) -> Result<Vec<u8>> {
+        encrypt_with(
+            self.cipher,
+            &master.key_id(),
+            master.as_bytes(),
+            dek,
+            &entry_aad(DEK_DOMAIN, self.vault_id, name, version),
+        )
+    }
+
+    fn unwrap_dek(
+        &self,
+        master: &MasterKey,
+        name: &str,
+        version: u32,
+        wrapped: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        decrypt_with(
+            self.cipher,
+            master.as_bytes(),
+            wrapped,
+            &entry_aad(DEK_DOMAIN, self.vault_id, name, version),
+        )
+        .map(Zeroizing::new)
+        .map_err(|e| integrity_error(e, name, version))
+    }
+}
+
+fn integrity_error(err: VaultError, name: &str, version: u32) -> VaultError {
+    match err {
+        VaultError::DecryptionFailed(_) => VaultError::IntegrityCheckFailed {
+            key: name.to_string(),
+            version,
+        },
+        other => other,
+    }
+}
+
+const VALUE_DOMAIN: &[u8] = b"rmsv-entry-v1";
+const DEK_DOMAIN: &[u8] = b"rmsv-dek-v1";
+
+/// Associated data for an entry: a domain tag followed by the vault ID,
+/// secret name and version, each length-delimited so no two slots collide.
+fn entry_aad(domain: &[u8], vault_id: &str, name: &str, version: u32) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(domain.len() + 12 + vault_id.len() + name.len());
+    aad.extend_from_slice(domain);
+    for part in [vault_id.as_bytes(), name.as_bytes()] {
+        aad.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        aad.extend_from_slice(part);
+    }
+    aad.extend_from_slice(&version.to_be_bytes());
+    aad
+}
+
+/// Generates a random 128-bit vault identifier, hex encoded.
+fn new_vault_id() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Validates a secret key name.
+fn validate_secret_key(key: &str) -> Result<()> {
+    if key.is_empty() {
+        return Err(VaultError::InvalidSecretKey(
+            "Secret key cannot be empty".to_string(),
+        ));
+    }
+
+    if key.contains('\0') {
+        return Err(VaultError::InvalidSecretKey(
+            "Secret key cannot contain null bytes".to_string(),
+        ));
+    }
+
+    if key.len() > 256 {
+        return Err(VaultError::InvalidSecretKey(
+            "Secret key too long (max 256 characters)".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_vault_builder() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let key = vec![42u8; 32];
+
+        let vault = SecretVault::builder()
+            .master_key(KeySource::Bytes(key))
+            .vault_path(&vault_path)
+            .build();
+
+        assert!(vault.is_ok());
+    }
+
+    #[test]
+    fn test_get_version() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let key = vec![42u8; 32];
+
+        let mut vault = SecretVault::new(KeySource::Bytes(key), &vault_path, None).unwrap();
+
+        vault.set("test", b"value1").unwrap();
+        vault.set("test", b"value2").unwrap();
+        vault.set("test", b"value3").unwrap();
+
+        let v1 = vault.get_version("test", 1).unwrap().unwrap();
+        let v2 = vault.get_version("test", 2).unwrap().unwrap();
+        let v3 = vault.get_version("test", 3).unwrap().unwrap();
+
+        assert_eq!(v1, b"value1");
+        assert_eq!(v2, b"value2");
+        assert_eq!(v3, b"value3");
+    }
+
+    #[test]
+    fn test_list_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let key = vec![42u8; 32];
+
+        let mut vault = SecretVault::new(KeySource::Bytes(key), &vault_path, None).unwrap();
+
+        vault.set("key1", b"value1").unwrap();
+        vault.set("key2", b"value2").unwrap();
+        vault.set("key3", b"value3").unwrap();
+
+        let mut keys = vault.list_keys();
+        keys.sort();
+
+        assert_eq!(keys, vec!["key1", "key2", "key3"]);
+    }
+
+    #[test]
+    fn test_cipher_recorded_in_vault_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let key = vec![42u8; 32];
+
+        let mut vault = SecretVault::builder()
+            .master_key(KeySource::Bytes(key.clone()))
+            .vault_path(&vault_path)
+            .cipher(CipherKind::XChaCha20Poly1305)
+            .build()
+            .unwrap();
+        vault.set("token", b"abc").unwrap();
+
+        // Reopening with a different builder cipher must not change the algorithm.
+        let reopened = SecretVault::builder()
+            .master_key(KeySource::Bytes(key))
+            .vault_path(&vault_path)
+            .cipher(CipherKind::Aes256Gcm)
+            .build()
+            .unwrap();
+
+        assert_eq!(reopened.cipher(), CipherKind::XChaCha20Poly1305);
+        assert_eq!(reopened.get("token").unwrap().unwrap(), b"abc");
+    }
+
+    #[test]
+    fn test_legacy_vault_defaults_to_aes() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("legacy.vault");
+        let key = vec![42u8; 32];
+
+        let encrypted = crate::encryption::encrypt(&key, b"old", b"").unwrap();
+        let legacy = format!(
+            "secrets:\n  legacy:\n  - encrypted_value: {:?}\n    version: 1\n    created_at: 2024-01-01T00:00:00Z\n",
+            encrypted
+        );
+        fs::write(&vault_path, legacy).unwrap();
+
+        let mut vault = SecretVault::new(KeySource::Bytes(key), &vault_path, None).unwrap();
+        assert_eq!(vault.cipher(), CipherKind::Aes256Gcm);
+        assert_eq!(vault.get("legacy").unwrap().unwrap(), b"old");
+
+        assert!(!vault.data.entries_bound);
+        assert_eq!(vault.bind_legacy_entries().unwrap(), 1);
+        assert!(vault.data.secrets["legacy"][0].aad_bound);
+        assert!(vault.data.entries_bound);
+        assert_eq!(vault.get("legacy").unwrap().unwrap(), b"old");
+        assert_eq!(vault.bind_legacy_entries().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_bound_vault_refuses_legacy_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let key = vec![42u8; 32];
+
+        let mut vault = SecretVault::new(KeySource::Bytes(key.clone()), &vault_path, None).unwrap();
+        assert!(vault.data.entries_bound);
+        vault.set("db_password", b"hunter2").unwrap();
+        vault.set("api_key", b"sk_live").unwrap();
+
+        // Clearing the flag does not make a moved ciphertext decrypt.
+        let stolen = vault.data.secrets["api_key"][0].clone();
+        let entry = &mut vault.data.secrets.get_mut("db_password").unwrap()[0];
+        entry.encrypted_value = stolen.encrypted_value;
+        entry.aad_bound = false;
+        assert!(matches!(
+            vault.get("db_password"),
+            Err(VaultError::IntegrityCheckFailed { version: 1, .. })
+        ));
+
+        // Nor does an entry sealed directly under the master key.
+        let entry = &mut vault.data.secrets.get_mut("api_key").unwrap()[0];
+        entry.encrypted_value = crate::encryption::encrypt(&key, b"forged", b"").unwrap();
+        entry.wrapped_dek = None;
+        assert!(matches!(
+            vault.get("api_key"),
+            Err(VaultError::IntegrityCheckFailed { version: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_unversioned_vault_is_upgraded() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("legacy.vault");
+        let key = vec![42u8; 32];
+
+        let encrypted = crate::encryption::encrypt(&key, b"old", b"").unwrap();
+        let legacy = format!(
+            "secrets:\n  legacy:\n  - encrypted_value: {:?}\n    version: 1\n    created_at: 2024-01-01T00:00:00Z\n",
+            encrypted
+        );
+        fs::write(&vault_path, legacy).unwrap();
+
+        let mut vault = SecretVault::new(KeySource::Bytes(key.clone()), &vault_path, None).unwrap();
+        assert_eq!(vault.stored_format_version(), 0);
+        assert_eq!(
+            vault.data.secrets["legacy"][0].key_id,
+            vault.active_key_id()
+        );
+        assert_eq!(vault.upgrade().unwrap(), Some(0));
+        assert_eq!(vault.upgrade().unwrap(), None);
+
+        let stored = FileStorage::new(&vault_path).load().unwrap().unwrap();
+        assert_eq!(stored.format_version, FORMAT_VERSION);
+        assert!(!stored.secrets["legacy"][0].key_id.is_empty());
+        let reopened = SecretVault::new(KeySource::Bytes(key), &vault_path, None).unwrap();
+        assert_eq!(reopened.stored_format_version(), FORMAT_VERSION);
+        assert_eq!(reopened.get("legacy").unwrap().unwrap(), b"old");
+    }
+
+    #[test]
+    fn test_newer_format_version_is_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let mut vault =
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        vault.set("a", b"1").unwrap();
+
+        let yaml = fs::read_to_string(&vault_path).unwrap().replace(
+            &format!("format_version: {}", FORMAT_VERSION),
+            "format_version: 99\nfuture_field: [1, 2]",
+        );
+        fs::write(&vault_path, yaml).unwrap();
+        assert!(matches!(
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &vault_path, None),
+            Err(VaultError::UnsupportedFormatVersion { found: 99, .. })
+        ));
+
+        // Even when the newer layout does not decode at all.
+        fs::write(&vault_path, "format_version: 99\nsecrets: 7\n").unwrap();
+        assert!(matches!(
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &vault_path, None),
+            Err(VaultError::UnsupportedFormatVersion { found: 99, .. })
+        ));
+    }
+
+    #[test]
+    fn test_vault_file_diffs_stay_local() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let mut vault =
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        for name in ["zeta", "alpha", "mid"] {
+            vault.set(name, name.as_bytes()).unwrap();
+        }
+        let before = fs::read_to_string(&vault_path).unwrap();
+        vault.set("mid", b"changed").unwrap();
+        let after = fs::read_to_string(&vault_path).unwrap();
+
+        let block = |yaml: &str, from: &str, to: &str| {
+            let start = yaml.find(from).unwrap();
+            yaml[start..start + yaml[start..].find(to).unwrap()].to_string()
+        };
+        assert_eq!(
+            block(&before, "  alpha:\n", "  mid:\n"),
+            block(&after, "  alpha:\n", "  mid:\n")
+        );
+        assert_eq!(
+            before[before.find("  zeta:\n").unwrap()..],
+            after[after.find("  zeta:\n").unwrap()..]
+        );
+        assert_eq!(before.lines().count() + 6, after.lines().count());
+
+        let value = after
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("- encrypted_value: "))
+            .unwrap();
+        assert!(general_purpose::STANDARD.decode(value).is_ok());
+    }
+
+    #[test]
+    fn test_swapped_ciphertexts_are_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let key = vec![42u8; 32];
+
+        let mut vault = SecretVault::new(KeySource::Bytes(key), &vault_path, None).unwrap();
+        vault.set("db_password", b"hunter2").unwrap();
+        vault.set("api_key", b"sk_live").unwrap();
+        vault.set("api_key", b"sk_live_2").unwrap();
+
+        // Transplant a ciphertext from one secret into another.
+        let stolen = vault.data.secrets["api_key"][0].encrypted_value.clone();
+        vault.data.secrets.get_mut("db_password").unwrap()[0].encrypted_value = stolen;
+        assert!(matches!(
+            vault.get("db_password"),
+            Err(VaultError::IntegrityCheckFailed { ref key, version: 1 }) if key == "db_password"
+        ));
+
+        // Swap two versions of the same secret.
+        let entries = vault.data.secrets.get_mut("api_key").unwrap();
+        let first = entries[0].encrypted_value.clone();
+        entries[0].encrypted_value = entries[1].encrypted_value.clone();
+        entries[1].encrypted_value = first;
+        assert!(matches!(
+            vault.get_version("api_key", 1),
+            Err(VaultError::IntegrityCheckFailed { version: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_entries_bound_to_vault_id() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = vec![42u8; 32];
+
+        let mut first = SecretVault::new(
+            KeySource::Bytes(key.clone()),
+            &temp_dir.path().join("a"),
+            None,
+        )
+        .unwrap();
+        let mut second =
+            SecretVault::new(KeySource::Bytes(key), &temp_dir.path().join("b"), None).unwrap();
+        assert_ne!(first.data.vault_id, second.data.vault_id);
+
+        first.set("token", b"from-a").unwrap();
+        second.set("token", b"from-b").unwrap();
+        let copied = first.data.secrets["token"].clone();
+        second.data.secrets.insert("token".to_string(), copied);
+
+        assert!(matches!(
+            second.get("token"),
+            Err(VaultError::IntegrityCheckFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_save_keeps_backup_of_previous_generation() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let backup_path = temp_dir.path().join("test.vault.bak");
+
+        let mut vault = SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .vault_path(&vault_path)
+            .backup(true)
+            .build()
+            .unwrap();
+        vault.set("token", b"one").unwrap();
+        let first = fs::read(&vault_path).unwrap();
+        vault.set("token", b"two").unwrap();
+
+        assert_eq!(fs::read(&backup_path).unwrap(), first);
+        let restored =
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &backup_path, None).unwrap();
+        assert_eq!(restored.get("token").unwrap().unwrap(), b"one");
+    }
+
+    #[test]
+    fn test_concurrent_writers_retry() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+
+        let mut first =
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        let mut second =
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        first.set("a", b"1").unwrap();
+        second.set("b", b"2").unwrap();
+        assert_eq!(second.generation(), 2);
+
+        let reopened =
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        assert_eq!(reopened.get("a").unwrap().unwrap(), b"1");
+        assert_eq!(reopened.get("b").unwrap().unwrap(), b"2");
+
+        let mut stale = SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .vault_path(&vault_path)
+            .max_retries(0)
+            .build()
+            .unwrap();
+        first.set("a", b"3").unwrap();
+        assert!(matches!(
+            stale.set("c", b"4"),
+            Err(VaultError::ConcurrentModification {
+                expected: 2,
+                found: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn test_rotate_only_rewraps_data_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+
+        let mut vault =
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        vault.set("token", b"abc").unwrap();
+        let before = vault.data.secrets["token"][0].clone();
+
+        vault.rotate(KeySource::Bytes(vec![2u8; 32])).unwrap();
+        let after = &vault.data.secrets["token"][0];
+
+        assert_eq!(after.encrypted_value, before.encrypted_value);
+        assert_ne!(after.wrapped_dek, before.wrapped_dek);
+        assert_eq!(vault.get("token").unwrap().unwrap(), b"abc");
+
+        let reopened =
+            SecretVault::new(KeySource::Bytes(vec![2u8; 32]), &vault_path, None).unwrap();
+        assert_eq!(reopened.get("token").unwrap().unwrap(), b"abc");
+    }
+
+    #[test]
+    fn test_rotate_converts_direct_entries_to_envelope() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("legacy.vault");
+        let key = vec![42u8; 32];
+
+        let encrypted = crate::encryption::encrypt(&key, b"old", b"").unwrap();
+        let legacy = format!(
+            "secrets:\n  legacy:\n  - encrypted_value: {:?}\n    version: 1\n    created_at: 2024-01-01T00:00:00Z\n",
+            encrypted
+        );
+        fs::write(&vault_path, legacy).unwrap();
+
+        let mut vault = SecretVault::new(KeySource::Bytes(key), &vault_path, None).unwrap();
+        vault.rotate(KeySource::Bytes(vec![7u8; 32])).unwrap();
+
+        let entry = &vault.data.secrets["legacy"][0];
+        assert!(entry.wrapped_dek.is_some());
+        assert!(entry.aad_bound);
+        assert_eq!(entry.created_at.to_rfc3339(), "2024-01-01T00:00:00+00:00");
+        assert!(vault.data.entries_bound);
+        assert_eq!(vault.get("legacy").unwrap().unwrap(), b"old");
+    }
+
+    #[test]
+    fn test_shred_version() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let key = vec![42u8; 32];
+
+        let mut vault = SecretVault::new(KeySource::Bytes(key), &vault_path, None).unwrap();
+        vault.set("api_key", b"v1").unwrap();
+        vault.set("api_key", b"v2").unwrap();
+
+        assert!(vault.shred_version("api_key", 1).unwrap());
+        assert!(!vault.shred_version("api_key", 1).unwrap());
+        assert!(!vault.shred_version("api_key", 9).unwrap());
+
+        assert!(matches!(
+            vault.get_version("api_key", 1),
+            Err(VaultError::SecretShredded { version: 1, .. })
+        ));
+        assert_eq!(vault.get("api_key").unwrap().unwrap(), b"v2");
+        assert_eq!(vault.list_versions("api_key").unwrap(), vec![1, 2]);
+
+        // Shredded entries survive rotation untouched.
+        vault.rotate(KeySource::Bytes(vec![9u8; 32])).unwrap();
+        assert!(vault.data.secrets["api_key"][0].shredded);
+        assert_eq!(vault.get("api_key").unwrap().unwrap(), b"v2");
+    }
+
+    #[test]
+    fn test_wrong_master_key_is_reported() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+
+        let mut vault =
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        vault.set("token", b"abc").unwrap();
+
+        match SecretVault::new(KeySource::Bytes(vec![2u8; 32]), &vault_path, None) {
+            Err(VaultError::UnknownKeyId(key_id)) => {
+                assert_eq!(key_id, MasterKey::new(vec![1u8; 32]).unwrap().key_id());
+            }
+            other => panic!("expected UnknownKeyId, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(vault.get("token").unwrap().unwrap(), b"abc");
+    }
+
+    fn passphrase_vault(path: &Path, passphrase: &str) -> Result<SecretVault> {
+        SecretVault::builder()
+            .master_key(KeySource::Passphrase(passphrase.to_string()))
+            .vault_path(path)
+            .kdf_params(KdfParams::new(64, 1, 1).unwrap())
+            .build()
+    }
+
+    #[test]
+    fn test_passphrase_vault() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+
+        let mut vault = passphrase_vault(&vault_path, "correct horse").unwrap();
+        vault.set("token", b"abc").unwrap();
+        let kdf = vault.data.kdf.clone().unwrap();
+        assert_eq!(kdf.memory_kib, 64);
+        assert_eq!(kdf.key_id, vault.active_key_id());
+
+        // Cost parameters come from the file, not the builder, on reopen.
+        let reopened = SecretVault::builder()
+            .master_key(KeySource::Passphrase("correct horse".to_string()))
+            .vault_path(&vault_path)
+            .build()
+            .unwrap();
+        assert_eq!(reopened.data.kdf, Some(kdf));
+        assert_eq!(reopened.get("token").unwrap().unwrap(), b"abc");
+
+        assert!(matches!(
+            passphrase_vault(&vault_path, "wrong"),
+            Err(VaultError::KeyLoadError(msg)) if msg == "Incorrect passphrase"
+        ));
+    }
+
+    #[test]
+    fn test_passphrase_requires_kdf_vault() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+
+        let mut vault =
+            SecretVault::new(KeySource::Bytes(vec![42u8; 32]), &vault_path, None).unwrap();
+        vault.set("token", b"abc").unwrap();
+
+        assert!(matches!(
+            passphrase_vault(&vault_path, "anything"),
+            Err(VaultError::KeyLoadError(_))
+        ));
+        assert!(matches!(
+            KeySource::Passphrase("anything".to_string()).load(),
+            Err(VaultError::KeyLoadError(_))
+        ));
+    }
+
+    #[test]
+    fn test_rotate_passphrase() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+
+        let mut vault = passphrase_vault(&vault_path, "old passphrase").unwrap();
+        vault.set("token", b"abc").unwrap();
+        let old_salt = vault.data.kdf.as_ref().unwrap().salt.clone();
+
+        vault
+            .rotate(KeySource::Passphrase("new passphrase".to_string()))
+            .unwrap();
+        let kdf = vault.data.kdf.as_ref().unwrap();
+        assert_ne!(kdf.salt, old_salt);
+        assert_eq!(kdf.memory_kib, 64);
+
+        assert!(passphrase_vault(&vault_path, "old passphrase").is_err());
+        let reopened = passphrase_vault(&vault_path, "new passphrase").unwrap();
+        assert_eq!(reopened.get("token").unwrap().unwrap(), b"abc");
+
+        // Rotating back to a raw key drops the KDF parameters.
+        let mut vault = reopened;
+        vault.rotate(KeySource::Bytes(vec![5u8; 32])).unwrap();
+        assert!(vault.data.kdf.is_none());
+    }
+
+    #[test]
+    fn test_lazy_rotation_with_keyring() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let old_id = MasterKey::new(vec![1u8; 32]).unwrap().key_id();
+
+        let mut vault =
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        vault.set("a", b"1").unwrap();
+        vault.set("b", b"2").unwrap();
+        vault.set("c", b"3").unwrap();
+
+        let new_id = vault
+            .add_key(KeySource::Bytes(vec![2u8; 32]), true)
+            .unwrap();
+        assert_eq!(vault.active_key_id(), new_id);
+        vault.set("d", b"4").unwrap();
+        assert_eq!(vault.data.secrets["d"][0].key_id, new_id);
+
+        let usage = vault.key_usage().unwrap();
+        assert_eq!(usage[&old_id], 3);
+        assert_eq!(usage[&new_id], 1);
+
+        // Old entries keep decrypting under the old key.
+        assert_eq!(vault.get("a").unwrap().unwrap(), b"1");
+        assert!(matches!(
+            vault.retire_key(&old_id),
+            Err(VaultError::KeyInUse {
+                entries: 3,
+                active: false,
+                ..
+            })
+        ));
+        assert!(matches!(
+            vault.retire_key(&new_id),
+            Err(VaultError::KeyInUse { active: true, .. })
+        ));
+
+        assert_eq!(vault.rewrap_to_active(Some(2)).unwrap(), 2);
+        assert_eq!(vault.key_usage().unwrap()[&old_id], 1);
+        assert_eq!(vault.rewrap_to_active(None).unwrap(), 1);
+        assert_eq!(vault.key_usage().unwrap()[&old_id], 0);
+
+        vault.retire_key(&old_id).unwrap();
+        assert!(!vault.key_usage().unwrap().contains_key(&old_id));
+        assert!(matches!(
+            vault.retire_key(&old_id),
+            Err(VaultError::UnknownKeyId(_))
+        ));
+
+        let reopened =
+            SecretVault::new(KeySource::Bytes(vec![2u8; 32]), &vault_path, None).unwrap();
+        for (key, value) in [("a", b"1"), ("b", b"2"), ("c", b"3"), ("d", b"4")] {
+            assert_eq!(reopened.get(key).unwrap().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_additional_keys_decrypt_old_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+
+        let mut vault =
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        vault.set("a", b"1").unwrap();
+        vault
+            .add_key(KeySource::Bytes(vec![2u8; 32]), true)
+            .unwrap();
+        vault.set("b", b"2").unwrap();
+
+        // Opening with only the new key cannot read entries under the old one.
+        let partial = SecretVault::new(KeySource::Bytes(vec![2u8; 32]), &vault_path, None).unwrap();
+        assert_eq!(partial.get("b").unwrap().unwrap(), b"2");
+        assert!(matches!(partial.get("a"), Err(VaultError::UnknownKeyId(_))));
+
+        let full = SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![2u8; 32]))
+            .additional_key(KeySource::Bytes(vec![1u8; 32]))
+            .vault_path(&vault_path)
+            .build()
+            .unwrap();
+        assert_eq!(full.get("a").unwrap().unwrap(), b"1");
+        assert_eq!(full.get("b").unwrap().unwrap(), b"2");
+    }
+
+    #[test]
+    fn test_read_only_vault() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let key = vec![42u8; 32];
+
+        let missing = SecretVault::builder()
+            .master_key(KeySource::Bytes(key.clone()))
+            .vault_path(&vault_path)
+            .read_only()
+            .build();
+        assert!(matches!(missing, Err(VaultError::VaultNotFound(_))));
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+
+        let mut vault = SecretVault::new(KeySource::Bytes(key.clone()), &vault_path, None).unwrap();
+        vault.set("api_key", b"secret").unwrap();
+        let stored = fs::read(&vault_path).unwrap();
+
+        let mut reader = SecretVault::builder()
+            .master_key(KeySource::Bytes(key))
+            .vault_path(&vault_path)
+            .read_only()
+            .build()
+            .unwrap();
+        assert!(reader.is_read_only());
+        assert_eq!(reader.get("api_key").unwrap().unwrap(), b"secret");
+        assert_eq!(reader.list_versions("api_key").unwrap(), vec![1]);
+
+        assert!(matches!(
+            reader.set("api_key", b"changed"),
+            Err(VaultError::ReadOnly)
+        ));
+        assert!(matches!(
+            reader.delete("api_key"),
+            Err(VaultError::ReadOnly)
+        ));
+        assert!(matches!(
+            reader.rotate(KeySource::Bytes(vec![7u8; 32])),
+            Err(VaultError::ReadOnly)
+        ));
+        assert!(matches!(reader.save(), Err(VaultError::ReadOnly)));
+        let active = reader.active_key_id().to_string();
+        assert!(matches!(
+            reader.add_key(KeySource::Bytes(vec![8u8; 32]), true),
+            Err(VaultError::ReadOnly)
+        ));
+        assert_eq!(reader.active_key_id(), active);
+        assert_eq!(reader.get("api_key").unwrap().unwrap(), b"secret");
+        assert_eq!(fs::read(&vault_path).unwrap(), stored);
+    }
+
+    #[test]
+    fn test_read_only_empty_storage() {
+        let vault = SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![42u8; 32]))
+            .storage(crate::storage::MemoryStorage::new())
+            .read_only()
+            .build();
+        assert!(matches!(vault, Err(VaultError::VaultNotFound(_))));
+    }
+
+    #[test]
+    fn test_create_and_open() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+
+        assert!(matches!(
+            SecretVault::open(KeySource::Bytes(vec![1u8; 32]), &vault_path, None),
+            Err(VaultError::VaultNotFound(_))
+        ));
+        assert!(!vault_path.exists());
+
+        let mut vault =
+            SecretVault::create(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        assert!(vault_path.exists());
+        vault.set("api_key", b"old").unwrap();
+        assert!(matches!(
+            SecretVault::create(KeySource::Bytes(vec![2u8; 32]), &vault_path, None),
+            Err(VaultError::VaultExists(_))
+        ));
+
+        let opened = SecretVault::open(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        assert_eq!(opened.get("api_key").unwrap().unwrap(), b"old");
+
+        let replaced = SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![2u8; 32]))
+            .vault_path(&vault_path)
+            .format(StorageFormat::Binary)
+            .overwrite(true)
+            .create()
+            .unwrap();
+        assert!(replaced.list_keys().is_empty());
+        assert_eq!(
+            StorageFormat::detect(&vault_path).unwrap(),
+            StorageFormat::Binary
+        );
+        let reopened =
+            SecretVault::open(KeySource::Bytes(vec![2u8; 32]), &vault_path, None).unwrap();
+        assert!(reopened.list_keys().is_empty());
+    }
+
+    #[test]
+    fn test_failed_overwrite_keeps_old_vault() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let sealed = |key: KeySource| {
+            SecretVault::builder()
+                .master_key(key)
+                .vault_path(&vault_path)
+                .format(StorageFormat::Sealed)
+                .overwrite(true)
+        };
+        sealed(KeySource::Bytes(vec![1u8; 32]))
+            .create()
+            .unwrap()
+            .set("api_key", b"old")
+            .unwrap();
+
+        let missing = KeySource::File(temp_dir.path().join("missing.key"));
+        assert!(sealed(missing).create().is_err());
+        let opened = SecretVault::open(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        assert_eq!(opened.get("api_key").unwrap().unwrap(), b"old");
+
+        let mut replaced = sealed(KeySource::Bytes(vec![2u8; 32])).create().unwrap();
+        replaced.set("api_key", b"new").unwrap();
+        let reopened =
+            SecretVault::open(KeySource::Bytes(vec![2u8; 32]), &vault_path, None).unwrap();
+        assert_eq!(reopened.get("api_key").unwrap().unwrap(), b"new");
+        let mut names: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["test.vault", "test.vault.lock"]);
+    }
+
+    #[test]
+    fn test_overwrite_replaces_stored_secrets() {
+        let storage = crate::storage::MemoryStorage::new();
+        let mut vault = SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .storage(storage.clone())
+            .build()
+            .unwrap();
+        vault.set("a", b"1").unwrap();
+        vault.set("b", b"2").unwrap();
+
+        let replaced = SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![2u8; 32]))
+            .storage(storage.clone())
+            .overwrite(true)
+            .create()
+            .unwrap();
+        assert!(replaced.generation() > vault.generation());
+
+        let reopened = SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![2u8; 32]))
+            .storage(storage)
+            .create_if_missing(false)
+            .build()
+            .unwrap();
+        assert!(reopened.list_keys().is_empty());
+    }
+}
@@ -0,0 +1,354 @@
+use super::{validate_secret_key, SecretEntry, SecretVault};
+use crate::audit::Operation;
+use crate::error::{Result, VaultError};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+/// Which versions of a secret to keep. Older versions are pruned by `set` and
+/// by [`SecretVault::prune`]; the latest version is always kept.
+///
+/// A version is kept only if every limit that is set keeps it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Number of newest versions kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<u32>,
+    /// Age in seconds beyond which versions are pruned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_secs: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// Keeps the newest `count` versions.
+    pub fn keep_last(count: u32) -> Self {
+        Self {
+            keep_last: Some(count),
+            max_age_secs: None,
+        }
+    }
+
+    /// Keeps versions younger than `age`.
+    pub fn max_age(age: Duration) -> Self {
+        Self {
+            keep_last: None,
+            max_age_secs: Some(age.as_secs()),
+        }
+    }
+
+    /// Also keeps no more than the newest `count` versions.
+    pub fn with_keep_last(mut self, count: u32) -> Self {
+        self.keep_last = Some(count);
+        self
+    }
+
+    /// Also prunes versions older than `age`.
+    pub fn with_max_age(mut self, age: Duration) -> Self {
+        self.max_age_secs = Some(age.as_secs());
+        self
+    }
+
+    /// Removes the versions of `entries` this policy does not keep as of
+    /// `now`, returning their version numbers.
+    pub(super) fn prune(&self, entries: &mut Vec<SecretEntry>, now: DateTime<Utc>) -> Vec<u32> {
+        let count = entries.len();
+        let mut pruned = Vec::new();
+        let mut index = 0;
+        entries.retain(|entry| {
+            let newer = count - index - 1;
+            index += 1;
+            let too_many = self.keep_last.is_some_and(|keep| newer >= keep as usize);
+            let too_old = self.max_age_secs.is_some_and(|age| {
+                now.signed_duration_since(entry.created_at)
+                    .num_seconds()
+                    .max(0) as u64
+                    >= age
+            });
+            if newer > 0 && (too_many || too_old) {
+                pruned.push(entry.version);
+                return false;
+            }
+            true
+        });
+        pruned
+    }
+}
+
+impl fmt::Display for RetentionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut limits = Vec::new();
+        if let Some(count) = self.keep_last {
+            limits.push(format!("keep the last {} versions", count));
+        }
+        if let Some(age) = self.max_age_secs {
+            limits.push(format!(
+                "prune versions older than {}",
+                format_age(Duration::from_secs(age))
+            ));
+        }
+        if limits.is_empty() {
+            return f.write_str("keep every version");
+        }
+        f.write_str(&limits.join(" and "))
+    }
+}
+
+const AGE_UNITS: [(char, u64); 5] = [
+    ('w', 7 * 24 * 3600),
+    ('d', 24 * 3600),
+    ('h', 3600),
+    ('m', 60),
+    ('s', 1),
+];
+
+/// Parses an age such as `90d`, `12h`, `2w` or `30m`; plain numbers are
+/// seconds.
+pub fn parse_age(s: &str) -> Result<Duration> {
+    let invalid = || {
+        VaultError::InvalidDataFormat(format!(
+            "Invalid age '{}': use a number with s, m, h, d or w",
+            s
+        ))
+    };
+    let s = s.trim();
+    let (number, unit) = match s.char_indices().last() {
+        Some((at, unit)) if unit.is_ascii_alphabetic() => (&s[..at], unit),
+        _ => (s, 's'),
+    };
+    let (_, seconds) = AGE_UNITS
+        .iter()
+        .find(|(name, _)| *name == unit)
+        .ok_or_else(invalid)?;
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    number
+        .checked_mul(*seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(invalid)
+}
+
+/// Formats an age in the largest unit that divides it, the way
+/// [`parse_age`] reads it.
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    AGE_UNITS
+        .iter()
+        .find(|(_, unit)| secs > 0 && secs.is_multiple_of(*unit))
+        .map_or_else(
+            || format!("{}s", secs),
+            |(name, unit)| format!("{}{}", secs / unit, name),
+        )
+}
+
+impl SecretVault {
+    /// Returns the retention policy applied to every secret without one of
+    /// its own.
+    pub fn retention(&self) -> Option<RetentionPolicy> {
+        self.data.retention
+    }
+
+    /// Returns the retention policy set for `key` alone.
+    pub fn key_retention(&self, key: &str) -> Option<RetentionPolicy> {
+        self.data.key_retention.get(key).copied()
+    }
+
+    /// Sets, or with `None` removes, the retention policy of the vault. It
+    /// applies to every secret without a policy of its own.
+    ///
+    /// Existing versions are left alone until the secret is next set or
+    /// [`SecretVault::prune`] runs.
+    pub fn set_retention(&mut self, policy: Option<RetentionPolicy>) -> Result<()> {
+        self.modify(|vault| {
+            let changed = vault.data.retention != policy;
+            vault.data.retention = policy;
+            Ok(changed)
+        })?;
+        Ok(())
+    }
+
+    /// Sets, or with `None` removes, the retention policy of one secret,
+    /// which replaces the vault's policy for it. The secret need not exist
+    /// yet.
+    pub fn set_key_retention(&mut self, key: &str, policy: Option<RetentionPolicy>) -> Result<()> {
+        validate_secret_key(key)?;
+        self.modify(|vault| {
+            let previous = match policy {
+                Some(policy) => vault.data.key_retention.insert(key.to_string(), policy),
+                None => vault.data.key_retention.remove(key),
+            };
+            Ok(previous != policy)
+        })?;
+        Ok(())
+    }
+
+    /// Removes every version that the retention policies do not keep.
+    ///
+    /// Each pruned version is recorded in the audit log.
+    ///
+    /// # Returns
+    /// The pruned versions of each secret that lost any.
+    pub fn prune(&mut self) -> Result<BTreeMap<String, Vec<u32>>> {
+        let mut pruned = BTreeMap::new();
+        self.modify(|vault| {
+            vault.load_all()?;
+            pruned.clear();
+            let names: Vec<String> = vault.data.secrets.keys().cloned().collect();
+            for name in names {
+                let versions = vault.stage_prune(&name);
+                if !versions.is_empty() {
+                    pruned.insert(name, versions);
+                }
+            }
+            Ok(!pruned.is_empty())
+        })?;
+
+        for (name, versions) in &pruned {
+            self.log_pruned(name, versions)?;
+        }
+        Ok(pruned)
+    }
+
+    /// Prunes `key` in memory under its retention policy, returning the
+    /// versions removed. Its entries must already be loaded.
+    pub(super) fn stage_prune(&mut self, key: &str) -> Vec<u32> {
+        let Some(policy) = self
+            .data
+            .key_retention
+            .get(key)
+            .or(self.data.retention.as_ref())
+            .copied()
+        else {
+            return Vec::new();
+        };
+        let Some(entries) = self.data.secrets.get_mut(key) else {
+            return Vec::new();
+        };
+        let pruned = policy.prune(entries, Utc::now());
+        if !pruned.is_empty() {
+            self.dirty.insert(key.to_string());
+        }
+        pruned
+    }
+
+    pub(super) fn log_pruned(&self, key: &str, versions: &[u32]) -> Result<()> {
+        for version in versions {
+            self.audit_logger
+                .log(Operation::Prune, &format!("{}@{}", key, version))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditEntry;
+    use crate::storage::{MemoryStorage, VaultStorage};
+    use crate::vault::KeySource;
+    use chrono::Duration as Age;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn entries(ages_in_days: &[i64], now: DateTime<Utc>) -> Vec<SecretEntry> {
+        ages_in_days
+            .iter()
+            .enumerate()
+            .map(|(i, days)| SecretEntry {
+                encrypted_value: vec![i as u8],
+                version: i as u32 + 1,
+                created_at: now - Age::days(*days),
+                aad_bound: true,
+                wrapped_dek: None,
+                shredded: false,
+                key_id: String::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_policy_keeps_latest_version() {
+        let now = Utc::now();
+        let mut history = entries(&[40, 30, 20, 10, 0], now);
+        assert_eq!(
+            RetentionPolicy::keep_last(2).prune(&mut history, now),
+            [1, 2, 3]
+        );
+        assert_eq!(
+            history.iter().map(|e| e.version).collect::<Vec<_>>(),
+            [4, 5]
+        );
+
+        let mut history = entries(&[40, 30, 20, 10, 0], now);
+        let policy = RetentionPolicy::max_age(Duration::from_secs(15 * 24 * 3600));
+        assert_eq!(policy.prune(&mut history, now), [1, 2, 3]);
+
+        // Even when every version is too old, the latest one is kept.
+        let mut history = entries(&[40, 30], now);
+        assert_eq!(policy.with_keep_last(0).prune(&mut history, now), [1]);
+        assert_eq!(history.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_and_format_age() {
+        assert_eq!(parse_age("90d").unwrap(), Duration::from_secs(90 * 86400));
+        assert_eq!(parse_age("120").unwrap(), Duration::from_secs(120));
+        assert!(parse_age("5y").is_err());
+        assert!(parse_age("d").is_err());
+        let policy = RetentionPolicy::keep_last(3).with_max_age(parse_age("2w").unwrap());
+        assert_eq!(
+            policy.to_string(),
+            "keep the last 3 versions and prune versions older than 2w"
+        );
+    }
+
+    #[test]
+    fn test_set_enforces_policies_and_prune_logs() {
+        let temp_dir = TempDir::new().unwrap();
+        let audit_path = temp_dir.path().join("audit.log");
+        let storage = MemoryStorage::new();
+        let open = || {
+            SecretVault::builder()
+                .master_key(KeySource::Bytes(vec![1u8; 32]))
+                .storage(storage.clone())
+                .audit_path(&audit_path)
+                .build()
+                .unwrap()
+        };
+
+        let mut vault = open();
+        for value in [b"1", b"2", b"3"] {
+            vault.set("a", value).unwrap();
+            vault.set("b", value).unwrap();
+        }
+        vault
+            .set_retention(Some(RetentionPolicy::keep_last(2)))
+            .unwrap();
+        vault
+            .set_key_retention("b", Some(RetentionPolicy::keep_last(1)))
+            .unwrap();
+
+        // Policies are stored with the vault.
+        let mut vault = open();
+        assert_eq!(vault.retention(), Some(RetentionPolicy::keep_last(2)));
+        vault.set("a", b"4").unwrap();
+        assert_eq!(vault.list_versions("a").unwrap(), [3, 4]);
+        assert_eq!(vault.list_versions("b").unwrap(), [1, 2, 3]);
+
+        let pruned = vault.prune().unwrap();
+        assert_eq!(pruned, BTreeMap::from([("b".to_string(), vec![1, 2])]));
+        assert!(vault.prune().unwrap().is_empty());
+        let stored = storage.load().unwrap().unwrap();
+        assert_eq!(stored.secrets["b"].len(), 1);
+        assert_eq!(vault.get("b").unwrap().unwrap(), b"3");
+
+        let pruned: Vec<String> = fs::read_to_string(&audit_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<AuditEntry>(line).unwrap())
+            .filter(|entry| entry.operation == Operation::Prune)
+            .map(|entry| entry.key)
+            .collect();
+        assert_eq!(pruned, ["a@1", "a@2", "b@1", "b@2"]);
+    }
+}
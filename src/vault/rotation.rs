@@ -0,0 +1,460 @@
+use super::manifest::{versions_of, Manifest};
+use super::{EntryCrypto, KeySource, MasterKey, SecretVault, VaultData};
+use crate::audit::Operation;
+use crate::error::{Result, VaultError};
+use crate::kdf::KdfParams;
+use crate::keyring::Keyring;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use zeroize::Zeroizing;
+
+/// Options for [`SecretVault::rotate_with`].
+#[derive(Debug, Clone)]
+pub struct RotationOptions {
+    /// Decrypt and rewrap everything in memory, but write nothing.
+    pub dry_run: bool,
+    /// Number of secrets rewrapped between checkpoints.
+    pub checkpoint_every: usize,
+    /// Stop after this many secrets, leaving the rotation staged so a later
+    /// call can resume it.
+    pub limit: Option<usize>,
+}
+
+impl Default for RotationOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            checkpoint_every: 100,
+            limit: None,
+        }
+    }
+}
+
+/// What a rotation did, or would do in a dry run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RotationReport {
+    /// ID of the new master key.
+    pub new_key_id: String,
+    /// Whether this was a dry run.
+    pub dry_run: bool,
+    /// Whether the vault now uses the new key. False if `limit` stopped the
+    /// rotation early.
+    pub complete: bool,
+    /// Number of secrets already rotated by an earlier, interrupted run.
+    pub resumed: usize,
+    /// Envelope entries whose data key was rewrapped.
+    pub rewrapped: usize,
+    /// Entries encrypted directly under the old master key, converted to
+    /// envelope form.
+    pub converted: usize,
+    /// Shredded entries, carried over untouched.
+    pub skipped: usize,
+}
+
+/// Name of the storage sidecar holding a staged rotation.
+pub(super) const CHECKPOINT: &str = "rotation";
+
+/// Rotation progress, staged next to the vault as the `rotation` sidecar
+/// (`<vault>.rotation` for the file backend).
+///
+/// The staged data holds data keys wrapped under the new master key only, so
+/// the file is as sensitive as the vault itself and no more.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    new_key_id: String,
+    /// KDF parameters of the new key, so a passphrase resumes with the same salt.
+    kdf: Option<KdfParams>,
+    /// Generation of the vault the rotation started from.
+    source_generation: u64,
+    completed: BTreeSet<String>,
+    staged: VaultData,
+}
+
+impl SecretVault {
+    /// Rotates the master encryption key.
+    ///
+    /// The new key becomes the only key in the ring: every entry's data key is
+    /// rewrapped under it and all other keys are dropped. Secret values are not
+    /// re-encrypted. Entries still encrypted directly under an old master key
+    /// are converted to envelope form on the way. For gradual rotation, use
+    /// [`SecretVault::add_key`] and [`SecretVault::rewrap_to_active`] instead.
+    ///
+    /// Rotating to a [`KeySource::Passphrase`] derives the new key with a fresh
+    /// salt and the vault's current cost parameters ([`KdfParams::mobile`] if
+    /// the vault had none).
+    ///
+    /// # Arguments
+    /// * `new_master_source` - Source for the new master key
+    pub fn rotate(&mut self, new_master_source: KeySource) -> Result<()> {
+        self.rotate_with(new_master_source, RotationOptions::default())?;
+        Ok(())
+    }
+
+    /// Rotates the master key without risking the vault on a crash.
+    ///
+    /// Rewrapped entries are staged in `<vault>.rotation` and checkpointed
+    /// every `checkpoint_every` secrets; the stored vault itself is untouched
+    /// until every staged entry has been verified to decrypt under the new
+    /// key alone, and is then replaced atomically. If the process dies part
+    /// way, calling this again with the same new key resumes from the last
+    /// checkpoint.
+    ///
+    /// # Errors
+    /// Returns `VaultError::RotationInProgress` if a staged rotation exists for
+    /// a different key, or the vault changed since it was staged. Use
+    /// [`SecretVault::abort_rotation`] to discard it.
+    pub fn rotate_with(
+        &mut self,
+        new_master_source: KeySource,
+        options: RotationOptions,
+    ) -> Result<RotationReport> {
+        self.check_writable()?;
+        self.load_all()?;
+        let mut checkpoint = self.read_checkpoint()?;
+
+        let (new_master_key, new_kdf) = match &checkpoint {
+            Some(staged) => {
+                let key = if new_master_source.is_passphrase() {
+                    new_master_source.load_with_kdf(staged.kdf.as_ref())?
+                } else {
+                    new_master_source.load()?
+                };
+                if key.key_id() != staged.new_key_id {
+                    return Err(VaultError::RotationInProgress(format!(
+                        "a rotation to key {} is staged; resume it with that key or abort it",
+                        staged.new_key_id
+                    )));
+                }
+                (key, staged.kdf.clone())
+            }
+            None => self.new_rotation_key(new_master_source)?,
+        };
+
+        let mut keyring = self.keyring.clone();
+        let new_id = keyring.insert(new_master_key);
+        keyring.set_active(&new_id)?;
+
+        if let Some(staged) = &checkpoint {
+            if staged.source_generation != self.data.generation {
+                if !self.uses_only(&new_id) {
+                    return Err(VaultError::RotationInProgress(
+                        "the vault changed since the rotation was staged; abort it and start again"
+                            .to_string(),
+                    ));
+                }
+                // The vault was replaced but the checkpoint was not removed.
+                checkpoint = None;
+                if !options.dry_run {
+                    self.storage.store_sidecar(CHECKPOINT, None)?;
+                }
+            }
+        }
+
+        let mut checkpoint = checkpoint.unwrap_or_else(|| Checkpoint {
+            new_key_id: new_id.clone(),
+            kdf: new_kdf.clone(),
+            source_generation: self.data.generation,
+            completed: BTreeSet::new(),
+            staged: self.data.clone(),
+        });
+        let mut report = RotationReport {
+            new_key_id: new_id.clone(),
+            dry_run: options.dry_run,
+            resumed: checkpoint.completed.len(),
+            ..RotationReport::default()
+        };
+
+        let crypto = EntryCrypto {
+            keyring: &keyring,
+            ..self.crypto()
+        };
+        let mut names: Vec<&String> = self
+            .data
+            .secrets
+            .keys()
+            .filter(|name| !checkpoint.completed.contains(*name))
+            .collect();
+        names.sort();
+
+        let mut processed = 0;
+        for name in names {
+            if options.limit.is_some_and(|limit| processed >= limit) {
+                break;
+            }
+            let entries = &self.data.secrets[name];
+            let mut rewrapped = Vec::with_capacity(entries.len());
+            for entry in entries {
+                if entry.shredded {
+                    report.skipped += 1;
+                } else if entry.wrapped_dek.is_some() {
+                    report.rewrapped += 1;
+                } else {
+                    report.converted += 1;
+                }
+                rewrapped.push(crypto.rewrap(name, entry).map_err(|e| match e {
+                    VaultError::DecryptionFailed(msg) => VaultError::DecryptionFailed(format!(
+                        "Failed to decrypt secret '{}' during rotation: {}",
+                        name, msg
+                    )),
+                    other => other,
+                })?);
+            }
+            checkpoint.staged.secrets.insert(name.clone(), rewrapped);
+            checkpoint.completed.insert(name.clone());
+            processed += 1;
+
+            if !options.dry_run && processed % options.checkpoint_every.max(1) == 0 {
+                self.write_checkpoint(&checkpoint)?;
+            }
+        }
+
+        if checkpoint.completed.len() < self.data.secrets.len() {
+            if !options.dry_run {
+                self.write_checkpoint(&checkpoint)?;
+            }
+            return Ok(report);
+        }
+
+        // A sealed backend reads the vault under the old key and seals it
+        // under the new one.
+        let sealing = keyring.clone();
+        keyring.retain_active();
+        let mut staged = checkpoint.staged;
+        staged.kdf = new_kdf;
+        staged.active_key_id = new_id;
+        staged.entries_bound = true;
+        verify_staged(&staged, &keyring)?;
+        report.complete = true;
+        if options.dry_run {
+            return Ok(report);
+        }
+
+        if self.manifest {
+            staged.manifest = Some(Manifest::sign(
+                keyring.active(),
+                &staged,
+                staged.generation + 1,
+                &versions_of(&staged.secrets),
+            ));
+        }
+        self.storage.unlock(&sealing)?;
+        if let Err(e) = self.storage.save(&mut staged) {
+            self.storage.unlock(&self.keyring)?;
+            return Err(e);
+        }
+        self.storage.store_sidecar(CHECKPOINT, None)?;
+        self.data = staged;
+        self.dirty.clear();
+        self.evict_saved();
+        self.keyring = keyring;
+        self.storage.unlock(&self.keyring)?;
+        self.pin()?;
+        self.audit_logger.log(Operation::Rotate, "ALL")?;
+        Ok(report)
+    }
+
+    /// Discards a staged rotation, leaving the vault on its current key.
+    ///
+    /// # Returns
+    /// Whether a staged rotation existed.
+    pub fn abort_rotation(&mut self) -> Result<bool> {
+        self.check_writable()?;
+        let existed = self.storage.load_sidecar(CHECKPOINT)?.is_some();
+        self.storage.store_sidecar(CHECKPOINT, None)?;
+        Ok(existed)
+    }
+
+    /// Returns the ID of the key a staged rotation is moving to, if any.
+    pub fn pending_rotation(&self) -> Result<Option<String>> {
+        Ok(self.read_checkpoint()?.map(|c| c.new_key_id))
+    }
+
+    fn new_rotation_key(&self, source: KeySource) -> Result<(MasterKey, Option<KdfParams>)> {
+        if source.is_passphrase() {
+            let mut params = self
+                .data
+                .kdf
+                .as_ref()
+                .map(KdfParams::with_fresh_salt)
+                .unwrap_or_default();
+            let key = source.load_with_kdf(Some(&params))?;
+            params.key_id = key.key_id();
+            Ok((key, Some(params)))
+        } else {
+            Ok((source.load()?, None))
+        }
+    }
+
+    fn read_checkpoint(&self) -> Result<Option<Checkpoint>> {
+        match self.storage.load_sidecar(CHECKPOINT)? {
+            Some(bytes) => Ok(Some(serde_yaml::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn write_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let yaml = serde_yaml::to_string(checkpoint)?;
+        self.storage
+            .store_sidecar(CHECKPOINT, Some(yaml.as_bytes()))
+    }
+
+    /// Returns whether every live entry is already wrapped under `key_id`.
+    fn uses_only(&self, key_id: &str) -> bool {
+        self.data
+            .secrets
+            .values()
+            .flatten()
+            .all(|entry| entry.shredded || entry.key_id == key_id)
+    }
+}
+
+/// Decrypts every live staged entry with the new key alone.
+fn verify_staged(staged: &VaultData, keyring: &Keyring) -> Result<()> {
+    let crypto = EntryCrypto {
+        cipher: staged.cipher,
+        keyring,
+        vault_id: &staged.vault_id,
+        bound_only: staged.entries_bound,
+    };
+    for (name, entries) in &staged.secrets {
+        for entry in entries.iter().filter(|entry| !entry.shredded) {
+            Zeroizing::new(crypto.open(name, entry)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn vault_with_secrets(path: &Path, count: usize) -> SecretVault {
+        let mut vault = SecretVault::new(KeySource::Bytes(vec![1u8; 32]), path, None).unwrap();
+        for i in 0..count {
+            vault.set(&format!("secret{}", i), b"value").unwrap();
+        }
+        vault
+    }
+
+    fn interrupted() -> RotationOptions {
+        RotationOptions {
+            checkpoint_every: 1,
+            limit: Some(2),
+            ..RotationOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_dry_run_writes_nothing() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let mut vault = vault_with_secrets(&vault_path, 3);
+        let before = fs::read(&vault_path).unwrap();
+
+        let report = vault
+            .rotate_with(
+                KeySource::Bytes(vec![2u8; 32]),
+                RotationOptions {
+                    dry_run: true,
+                    ..RotationOptions::default()
+                },
+            )
+            .unwrap();
+
+        assert!(report.dry_run && report.complete);
+        assert_eq!(report.rewrapped, 3);
+        assert_eq!(fs::read(&vault_path).unwrap(), before);
+        assert_eq!(vault.pending_rotation().unwrap(), None);
+        assert_ne!(vault.active_key_id(), report.new_key_id);
+    }
+
+    #[test]
+    fn test_resume_interrupted_rotation() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let mut vault = vault_with_secrets(&vault_path, 5);
+        let before = fs::read(&vault_path).unwrap();
+
+        let report = vault
+            .rotate_with(KeySource::Bytes(vec![2u8; 32]), interrupted())
+            .unwrap();
+        assert!(!report.complete);
+        assert_eq!(fs::read(&vault_path).unwrap(), before);
+        assert_eq!(
+            vault.pending_rotation().unwrap(),
+            Some(report.new_key_id.clone())
+        );
+
+        // A fresh process with the old key picks up where the first left off.
+        let mut vault =
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        let report = vault
+            .rotate_with(KeySource::Bytes(vec![2u8; 32]), RotationOptions::default())
+            .unwrap();
+        assert!(report.complete);
+        assert_eq!(report.resumed, 2);
+        assert_eq!(report.rewrapped, 3);
+        assert_eq!(vault.pending_rotation().unwrap(), None);
+
+        let reopened =
+            SecretVault::new(KeySource::Bytes(vec![2u8; 32]), &vault_path, None).unwrap();
+        for i in 0..5 {
+            let name = format!("secret{}", i);
+            assert_eq!(reopened.get(&name).unwrap().unwrap(), b"value");
+        }
+    }
+
+    #[test]
+    fn test_staged_rotation_guards() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let mut vault = vault_with_secrets(&vault_path, 3);
+
+        vault
+            .rotate_with(KeySource::Bytes(vec![2u8; 32]), interrupted())
+            .unwrap();
+        assert!(matches!(
+            vault.rotate(KeySource::Bytes(vec![3u8; 32])),
+            Err(VaultError::RotationInProgress(_))
+        ));
+
+        // Changing the vault invalidates the staged copy.
+        vault.set("secret0", b"changed").unwrap();
+        assert!(matches!(
+            vault.rotate(KeySource::Bytes(vec![2u8; 32])),
+            Err(VaultError::RotationInProgress(_))
+        ));
+
+        assert!(vault.abort_rotation().unwrap());
+        assert!(!vault.abort_rotation().unwrap());
+        vault.rotate(KeySource::Bytes(vec![3u8; 32])).unwrap();
+        assert_eq!(vault.get("secret0").unwrap().unwrap(), b"changed");
+    }
+
+    #[test]
+    fn test_leftover_checkpoint_after_replace() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let mut vault = vault_with_secrets(&vault_path, 3);
+
+        vault
+            .rotate_with(KeySource::Bytes(vec![2u8; 32]), interrupted())
+            .unwrap();
+        let checkpoint = vault.storage().load_sidecar(CHECKPOINT).unwrap();
+        vault.rotate(KeySource::Bytes(vec![2u8; 32])).unwrap();
+
+        // Simulate a crash between replacing the vault and removing the checkpoint.
+        vault
+            .storage()
+            .store_sidecar(CHECKPOINT, checkpoint.as_deref())
+            .unwrap();
+        let mut vault =
+            SecretVault::new(KeySource::Bytes(vec![2u8; 32]), &vault_path, None).unwrap();
+        vault.rotate(KeySource::Bytes(vec![2u8; 32])).unwrap();
+        assert_eq!(vault.pending_rotation().unwrap(), None);
+        assert_eq!(vault.get("secret1").unwrap().unwrap(), b"value");
+    }
+}
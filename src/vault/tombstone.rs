@@ -0,0 +1,296 @@
+use super::{SecretVault, DEFAULT_DELETE_GRACE_PERIOD};
+use crate::audit::Operation;
+use crate::error::Result;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+impl SecretVault {
+    /// Returns whether `key` is deleted but not purged yet.
+    pub fn is_deleted(&self, key: &str) -> bool {
+        self.data.tombstones.contains_key(key)
+    }
+
+    /// Lists the deleted secrets that can still be restored, with the time
+    /// each was deleted.
+    pub fn deleted_keys(&self) -> BTreeMap<String, DateTime<Utc>> {
+        self.data.tombstones.clone()
+    }
+
+    /// Restores a deleted secret with all of its versions.
+    ///
+    /// # Returns
+    /// `true` if the secret was deleted and is now restored.
+    pub fn undelete(&mut self, key: &str) -> Result<bool> {
+        let restored = self.modify(|vault| Ok(vault.data.tombstones.remove(key).is_some()))?;
+        if restored {
+            self.audit_logger.log(Operation::Undelete, key)?;
+        }
+        Ok(restored)
+    }
+
+    /// Removes a deleted secret and all of its versions for good, without
+    /// waiting for its grace period to run out. Secrets that are not
+    /// deleted are left alone.
+    ///
+    /// # Returns
+    /// `true` if the secret was deleted and is now purged.
+    pub fn purge(&mut self, key: &str) -> Result<bool> {
+        let purged = self.modify(|vault| Ok(vault.stage_purge(key)))?;
+        if purged {
+            self.audit_logger.log(Operation::Purge, key)?;
+        }
+        Ok(purged)
+    }
+
+    /// Returns how long deleted secrets are kept before saves purge them.
+    pub fn delete_grace_period(&self) -> Duration {
+        self.data
+            .delete_grace_period_secs
+            .map_or(DEFAULT_DELETE_GRACE_PERIOD, Duration::from_secs)
+    }
+
+    /// Sets how long deleted secrets are kept before saves purge them. The
+    /// period is stored in the vault, so every handle that opens it uses it;
+    /// secrets already deleted for longer are purged by this save.
+    pub fn set_delete_grace_period(&mut self, period: Duration) -> Result<()> {
+        let secs = Some(period.as_secs());
+        self.modify(|vault| {
+            let changed = vault.data.delete_grace_period_secs != secs;
+            vault.data.delete_grace_period_secs = secs;
+            Ok(changed)
+        })?;
+        Ok(())
+    }
+
+    /// Purges every secret deleted longer ago than the
+    /// [grace period](SecretVault::delete_grace_period), without waiting for
+    /// the next change to be saved.
+    ///
+    /// # Returns
+    /// The names of the purged secrets.
+    pub fn purge_expired(&mut self) -> Result<Vec<String>> {
+        let mut purged = Vec::new();
+        self.modify(|vault| {
+            purged = vault.stage_purge_expired();
+            Ok(!purged.is_empty())
+        })?;
+
+        for name in &purged {
+            self.audit_logger.log(Operation::Purge, name)?;
+        }
+        Ok(purged)
+    }
+
+    /// Removes the secrets deleted longer ago than the grace period in
+    /// memory, returning their names.
+    pub(super) fn stage_purge_expired(&mut self) -> Vec<String> {
+        let grace_period =
+            chrono::Duration::from_std(self.delete_grace_period()).unwrap_or(chrono::Duration::MAX);
+        let now = Utc::now();
+        let expired: Vec<String> = self
+            .data
+            .tombstones
+            .iter()
+            .filter(|(_, deleted_at)| now.signed_duration_since(**deleted_at) >= grace_period)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &expired {
+            self.stage_purge(name);
+        }
+        expired
+    }
+
+    /// Removes the deleted secret `key` in memory, returning whether it was
+    /// deleted.
+    pub(super) fn stage_purge(&mut self, key: &str) -> bool {
+        if self.data.tombstones.remove(key).is_none() {
+            return false;
+        }
+        self.unloaded.remove(key);
+        self.data.secrets.remove(key);
+        self.dirty.insert(key.to_string());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditEntry;
+    use crate::error::VaultError;
+    use crate::storage::{MemoryStorage, StorageFormat, VaultStorage};
+    use crate::vault::{KeySource, VaultBuilder};
+    use std::fs;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn builder(storage: &MemoryStorage) -> VaultBuilder {
+        SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .storage(storage.clone())
+    }
+
+    #[test]
+    fn test_delete_hides_and_undelete_restores() {
+        let temp_dir = TempDir::new().unwrap();
+        let audit_path = temp_dir.path().join("audit.log");
+        let storage = MemoryStorage::new();
+        let mut vault = builder(&storage).audit_path(&audit_path).build().unwrap();
+        vault.set("api_key", b"1").unwrap();
+        vault.set("api_key", b"2").unwrap();
+        vault.set("other", b"3").unwrap();
+
+        vault.delete("api_key").unwrap();
+        assert_eq!(vault.get("api_key").unwrap(), None);
+        assert_eq!(vault.get_version("api_key", 1).unwrap(), None);
+        assert!(vault.list_versions("api_key").unwrap().is_empty());
+        assert_eq!(vault.list_keys(), ["other"]);
+        assert_eq!(storage.load().unwrap().unwrap().secrets["api_key"].len(), 2);
+
+        let mut vault = builder(&storage).audit_path(&audit_path).build().unwrap();
+        assert!(vault.is_deleted("api_key"));
+        assert!(!vault.purge("other").unwrap());
+        assert!(vault.undelete("api_key").unwrap());
+        assert!(!vault.undelete("api_key").unwrap());
+        assert_eq!(vault.get("api_key").unwrap().unwrap(), b"2");
+        assert_eq!(vault.list_versions("api_key").unwrap(), [1, 2]);
+
+        vault.delete("api_key").unwrap();
+        assert!(vault.purge("api_key").unwrap());
+        assert!(!vault.undelete("api_key").unwrap());
+        let stored = storage.load().unwrap().unwrap();
+        assert!(!stored.secrets.contains_key("api_key"));
+        assert!(stored.tombstones.is_empty());
+
+        let operations: Vec<(Operation, String)> = fs::read_to_string(&audit_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<AuditEntry>(line).unwrap())
+            .filter(|entry| entry.operation != Operation::Get)
+            .skip(3)
+            .map(|entry| (entry.operation, entry.key))
+            .collect();
+        assert_eq!(
+            operations,
+            [
+                (Operation::Delete, "api_key".to_string()),
+                (Operation::Undelete, "api_key".to_string()),
+                (Operation::Delete, "api_key".to_string()),
+                (Operation::Purge, "api_key".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_refuses_deleted_secret() {
+        let storage = MemoryStorage::new();
+        let mut vault = builder(&storage).build().unwrap();
+        vault.set("a", b"1").unwrap();
+        vault.delete("a").unwrap();
+        assert!(matches!(
+            vault.set("a", b"2"),
+            Err(VaultError::SecretDeleted(_))
+        ));
+        assert!(vault.is_deleted("a"));
+
+        // Purging starts the name over at version 1.
+        vault.purge("a").unwrap();
+        vault.set("a", b"2").unwrap();
+        assert_eq!(vault.list_versions("a").unwrap(), [1]);
+        assert_eq!(vault.get("a").unwrap().unwrap(), b"2");
+    }
+
+    #[test]
+    fn test_purge_expired_after_grace_period() {
+        let storage = MemoryStorage::new();
+        let mut vault = builder(&storage).build().unwrap();
+        vault.set("old", b"1").unwrap();
+        vault.set("new", b"2").unwrap();
+        vault.delete("old").unwrap();
+        vault.delete("new").unwrap();
+        assert!(vault.purge_expired().unwrap().is_empty());
+
+        let mut data = storage.load().unwrap().unwrap();
+        *data.tombstones.get_mut("old").unwrap() -= chrono::Duration::days(31);
+        storage.save(&mut data).unwrap();
+        let mut vault = builder(&storage).build().unwrap();
+        assert_eq!(vault.purge_expired().unwrap(), ["old"]);
+        assert_eq!(
+            vault.deleted_keys().into_keys().collect::<Vec<_>>(),
+            ["new"]
+        );
+
+        vault.set_delete_grace_period(Duration::ZERO).unwrap();
+        assert!(vault.deleted_keys().is_empty());
+        let stored = storage.load().unwrap().unwrap();
+        assert!(stored.secrets.is_empty());
+        assert_eq!(stored.delete_grace_period_secs, Some(0));
+    }
+
+    #[test]
+    fn test_save_purges_expired_secrets() {
+        let temp_dir = TempDir::new().unwrap();
+        let audit_path = temp_dir.path().join("audit.log");
+        let storage = MemoryStorage::new();
+        let mut vault = builder(&storage).build().unwrap();
+        vault.set("old", b"1").unwrap();
+        vault.set("new", b"2").unwrap();
+        vault.delete("old").unwrap();
+        vault.delete("new").unwrap();
+
+        let mut data = storage.load().unwrap().unwrap();
+        *data.tombstones.get_mut("old").unwrap() -= chrono::Duration::days(31);
+        storage.save(&mut data).unwrap();
+        // The period is the vault's, not that of whoever writes to it.
+        let mut vault = builder(&storage)
+            .audit_path(&audit_path)
+            .delete_grace_period(Duration::ZERO)
+            .build()
+            .unwrap();
+        vault.set("other", b"3").unwrap();
+
+        let stored = storage.load().unwrap().unwrap();
+        assert!(!stored.secrets.contains_key("old"));
+        assert_eq!(stored.tombstones.keys().collect::<Vec<_>>(), ["new"]);
+        let purges: Vec<String> = fs::read_to_string(&audit_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<AuditEntry>(line).unwrap())
+            .filter(|entry| entry.operation == Operation::Purge)
+            .map(|entry| entry.key)
+            .collect();
+        assert_eq!(purges, ["old"]);
+    }
+
+    #[test]
+    fn test_tombstones_with_lazy_loading() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("vault.rmsv");
+        let open = || {
+            SecretVault::builder()
+                .master_key(KeySource::Bytes(vec![1u8; 32]))
+                .vault_path(&vault_path)
+                .format(StorageFormat::Binary)
+                .index_only(true)
+                .manifest(true)
+                .build()
+                .unwrap()
+        };
+
+        let mut vault = open();
+        vault.set("a", b"1").unwrap();
+        vault.set("b", b"2").unwrap();
+        let mut vault = open();
+        vault.delete("a").unwrap();
+        let mut vault = open();
+        assert_eq!(vault.list_keys(), ["b"]);
+        assert!(vault.undelete("a").unwrap());
+        assert_eq!(open().get("a").unwrap().unwrap(), b"1");
+
+        let mut vault = open();
+        vault.delete("a").unwrap();
+        vault.purge("a").unwrap();
+        assert_eq!(open().list_keys(), ["b"]);
+    }
+}
@@ -0,0 +1,178 @@
+use super::{validate_secret_key, SecretVault};
+use crate::audit::{AuditChange, Operation};
+use crate::error::Result;
+
+/// A batch of changes that [`SecretVault::transaction`] saves together.
+///
+/// Reads see the changes made so far in the transaction.
+pub struct Transaction<'a> {
+    vault: &'a mut SecretVault,
+    changes: Vec<AuditChange>,
+}
+
+impl Transaction<'_> {
+    /// Sets or updates a secret.
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        validate_secret_key(key)?;
+        let pruned = self.vault.stage_set(key, value)?;
+        self.record(Operation::Set, key);
+        for version in pruned {
+            self.record(Operation::Prune, &format!("{}@{}", key, version));
+        }
+        Ok(())
+    }
+
+    /// Deletes a secret, leaving a tombstone, and returns whether it existed.
+    pub fn delete(&mut self, key: &str) -> Result<bool> {
+        let deleted = self.vault.stage_delete(key);
+        if deleted {
+            self.record(Operation::Delete, key);
+        }
+        Ok(deleted)
+    }
+
+    /// Gets the latest version of a secret.
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.vault.get(key)
+    }
+
+    /// Lists all secret keys.
+    pub fn list_keys(&self) -> Vec<String> {
+        self.vault.list_keys()
+    }
+
+    fn record(&mut self, operation: Operation, key: &str) {
+        self.changes.push(AuditChange {
+            operation,
+            key: key.to_string(),
+        });
+    }
+}
+
+impl SecretVault {
+    /// Applies several changes as one: they are saved together with a single
+    /// write and logged as a single audit record, or not at all.
+    ///
+    /// If `body` fails, or the save does, every change it made is rolled back
+    /// and the error returned. If another process saved the vault first, the
+    /// vault is reloaded and `body` runs again, so it may run more than once.
+    /// With auto-save off the changes are applied in memory only and saved by
+    /// the next [`SecretVault::save`].
+    pub fn transaction<T>(
+        &mut self,
+        mut body: impl FnMut(&mut Transaction<'_>) -> Result<T>,
+    ) -> Result<T> {
+        let snapshot = (self.data.clone(), self.unloaded.clone(), self.dirty.clone());
+        let mut changes = Vec::new();
+        let mut output = None;
+        let result = self.modify(|vault| {
+            let mut tx = Transaction {
+                vault,
+                changes: Vec::new(),
+            };
+            output = Some(body(&mut tx)?);
+            changes = tx.changes;
+            Ok(!changes.is_empty())
+        });
+
+        if let Err(e) = result {
+            (self.data, self.unloaded, self.dirty) = snapshot;
+            return Err(e);
+        }
+        if !changes.is_empty() {
+            self.audit_logger.log_transaction(&changes)?;
+        }
+        Ok(output.expect("transaction body ran"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditEntry;
+    use crate::error::VaultError;
+    use crate::storage::{FileStorage, VaultStorage};
+    use crate::vault::KeySource;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_transaction_saves_once_with_one_audit_record() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let audit_path = temp_dir.path().join("audit.log");
+        let mut vault = SecretVault::new(
+            KeySource::Bytes(vec![1u8; 32]),
+            &vault_path,
+            Some(&audit_path),
+        )
+        .unwrap();
+        vault.set("old", b"0").unwrap();
+
+        let count = vault
+            .transaction(|tx| {
+                for i in 0..50 {
+                    tx.set(&format!("key_{}", i), b"value")?;
+                }
+                tx.delete("old")?;
+                Ok(tx.list_keys().len())
+            })
+            .unwrap();
+        assert_eq!(count, 50);
+        assert_eq!(vault.generation(), 2);
+
+        let log = fs::read_to_string(&audit_path).unwrap();
+        let records: Vec<AuditEntry> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].operation, Operation::Transaction);
+        assert_eq!(records[1].changes.len(), 51);
+    }
+
+    #[test]
+    fn test_failed_transaction_rolls_back() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let mut vault =
+            SecretVault::new(KeySource::Bytes(vec![1u8; 32]), &vault_path, None).unwrap();
+        vault.set("kept", b"1").unwrap();
+
+        let result = vault.transaction(|tx| {
+            tx.set("added", b"2")?;
+            tx.delete("kept")?;
+            tx.set("", b"3")
+        });
+        assert!(matches!(result, Err(VaultError::InvalidSecretKey(_))));
+        assert_eq!(vault.list_keys(), vec!["kept".to_string()]);
+        assert_eq!(vault.generation(), 1);
+
+        // Nothing is left to be saved later either.
+        vault.set("other", b"4").unwrap();
+        let stored = FileStorage::new(&vault_path).load().unwrap().unwrap();
+        assert_eq!(stored.secrets.keys().collect::<Vec<_>>(), ["kept", "other"]);
+    }
+
+    #[test]
+    fn test_auto_save_off_defers_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let vault_path = temp_dir.path().join("test.vault");
+        let mut vault = SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .vault_path(&vault_path)
+            .auto_save(false)
+            .build()
+            .unwrap();
+
+        vault.set("a", b"1").unwrap();
+        vault.transaction(|tx| tx.set("b", b"2")).unwrap();
+        assert!(!vault_path.exists());
+        assert_eq!(vault.get("b").unwrap().unwrap(), b"2");
+
+        vault.save().unwrap();
+        let stored = FileStorage::new(&vault_path).load().unwrap().unwrap();
+        assert_eq!(stored.generation, 1);
+        assert_eq!(stored.secrets.len(), 2);
+    }
+}
@@ -0,0 +1,415 @@
+use super::{SecretEntry, SecretVault};
+use crate::error::{Result, VaultError};
+use chrono::{Duration, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Name of the storage sidecar holding quarantined entries.
+const QUARANTINE: &str = "quarantine";
+
+/// How far in the future an entry's `created_at` may lie before it is
+/// reported, to allow for clock differences between writers.
+const MAX_CLOCK_SKEW_MINUTES: i64 = 5;
+
+/// What is wrong with an entry or a secret, as found by
+/// [`SecretVault::verify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// The entry does not decrypt with its key: it was damaged or tampered
+    /// with. [`SecretVault::repair`] quarantines these.
+    Undecryptable,
+    /// The key the entry is wrapped under is not loaded.
+    MissingKey,
+    /// Another entry of the secret has the same version.
+    DuplicateVersion,
+    /// The version is lower than the one before it.
+    VersionOrder,
+    /// The entry was created in the future, or before the version before it.
+    Timestamp,
+    /// The secret has no versions at all.
+    EmptyHistory,
+}
+
+/// One problem found by [`SecretVault::verify`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VerifyIssue {
+    pub secret: String,
+    /// The version concerned, or `None` for the whole secret.
+    pub version: Option<u32>,
+    pub kind: IssueKind,
+    pub detail: String,
+}
+
+impl fmt::Display for VerifyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.version {
+            Some(version) => write!(f, "{} v{}: {}", self.secret, version, self.detail),
+            None => write!(f, "{}: {}", self.secret, self.detail),
+        }
+    }
+}
+
+/// The result of [`SecretVault::verify`] or [`SecretVault::repair`].
+///
+/// Serializes to JSON for machine consumption, as `vault fsck` prints it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct VerifyReport {
+    /// Secrets checked.
+    pub secrets: usize,
+    /// Entries checked, shredded ones included.
+    pub entries: usize,
+    /// Entries whose data key was destroyed on purpose; not an issue.
+    pub shredded: usize,
+    /// Entries moved to the quarantine by [`SecretVault::repair`].
+    pub quarantined: usize,
+    /// Bytes of a torn final write cut off by [`SecretVault::repair`].
+    pub discarded_bytes: u64,
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    /// Whether no issues were found.
+    pub fn is_healthy(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl SecretVault {
+    /// Checks every entry of every secret, deleted ones included, without
+    /// changing anything.
+    ///
+    /// Each entry is decrypted, and each history is checked for duplicate,
+    /// decreasing or missing versions and for timestamps in the future or
+    /// before the previous version. Values are decrypted into memory and
+    /// dropped straight away.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let names: BTreeSet<&String> = self.data.secrets.keys().chain(&self.unloaded).collect();
+        for name in names {
+            let entries = self.entries(name)?.unwrap_or_default();
+            report.secrets += 1;
+            self.check_secret(name, &entries, &mut report);
+        }
+        Ok(report)
+    }
+
+    /// Moves entries that cannot be decrypted into the `quarantine` sidecar
+    /// (`<vault>.quarantine` for file backends) and saves the vault without
+    /// them, so the rest of the vault stays usable. Secrets left without any
+    /// version are removed. Other issues are reported but left alone.
+    ///
+    /// The quarantine is YAML of the removed entries by secret name; entries
+    /// quarantined earlier are kept.
+    ///
+    /// Before that, a record a crash left half-written at the end of a
+    /// log-structured vault is cut off; saves refuse to append after one.
+    ///
+    /// # Returns
+    /// The report of the repaired vault, with the number of entries moved.
+    ///
+    /// # Errors
+    /// Returns `VaultError::DecryptionFailed` without changing anything if no
+    /// entry decrypts at all, as that points to a wrong key rather than
+    /// damaged entries.
+    pub fn repair(&mut self) -> Result<VerifyReport> {
+        self.check_writable()?;
+        let discarded_bytes = self.storage.repair()?;
+        let found = VerifyReport {
+            discarded_bytes,
+            ..self.verify()?
+        };
+        let count = |kind| found.issues.iter().filter(|i| i.kind == kind).count();
+        let undecryptable = count(IssueKind::Undecryptable);
+        if undecryptable == 0 {
+            return Ok(found);
+        }
+        if undecryptable + count(IssueKind::MissingKey) == found.entries - found.shredded {
+            return Err(VaultError::DecryptionFailed(
+                "No entry decrypts with the loaded keys; refusing to quarantine them all"
+                    .to_string(),
+            ));
+        }
+
+        let mut quarantined = 0;
+        self.modify(|vault| {
+            vault.load_all()?;
+            let mut moved: BTreeMap<String, Vec<SecretEntry>> = BTreeMap::new();
+            let crypto = vault.crypto();
+            for (name, entries) in &vault.data.secrets {
+                let bad: Vec<SecretEntry> = entries
+                    .iter()
+                    .filter(|entry| !entry.shredded && is_undecryptable(&crypto, name, entry))
+                    .cloned()
+                    .collect();
+                if !bad.is_empty() {
+                    moved.insert(name.clone(), bad);
+                }
+            }
+            if moved.is_empty() {
+                return Ok(false);
+            }
+
+            vault.add_to_quarantine(&moved)?;
+            quarantined = 0;
+            for (name, bad) in moved {
+                quarantined += bad.len();
+                let entries = vault.data.secrets.entry(name.clone()).or_default();
+                entries.retain(|entry| {
+                    !bad.iter()
+                        .any(|b| b.encrypted_value == entry.encrypted_value)
+                });
+                if entries.is_empty() {
+                    vault.data.secrets.remove(&name);
+                    vault.data.tombstones.remove(&name);
+                }
+                vault.dirty.insert(name);
+            }
+            Ok(true)
+        })?;
+
+        let mut report = self.verify()?;
+        report.quarantined = quarantined;
+        report.discarded_bytes = discarded_bytes;
+        Ok(report)
+    }
+
+    fn check_secret(&self, name: &str, entries: &[SecretEntry], report: &mut VerifyReport) {
+        let mut issue = |version: Option<u32>, kind: IssueKind, detail: String| {
+            report.issues.push(VerifyIssue {
+                secret: name.to_string(),
+                version,
+                kind,
+                detail,
+            })
+        };
+        if entries.is_empty() {
+            issue(None, IssueKind::EmptyHistory, "has no versions".to_string());
+        }
+
+        let latest_allowed = Utc::now() + Duration::minutes(MAX_CLOCK_SKEW_MINUTES);
+        let crypto = self.crypto();
+        for (index, entry) in entries.iter().enumerate() {
+            let version = Some(entry.version);
+            if entries[..index].iter().any(|e| e.version == entry.version) {
+                issue(
+                    version,
+                    IssueKind::DuplicateVersion,
+                    "version appears more than once".to_string(),
+                );
+            } else if let Some(previous) = index.checked_sub(1).map(|i| &entries[i]) {
+                if entry.version < previous.version {
+                    issue(
+                        version,
+                        IssueKind::VersionOrder,
+                        format!("follows version {}", previous.version),
+                    );
+                }
+            }
+            if entry.created_at > latest_allowed {
+                issue(
+                    version,
+                    IssueKind::Timestamp,
+                    format!("created in the future, at {}", entry.created_at),
+                );
+            } else if let Some(previous) = index.checked_sub(1).map(|i| &entries[i]) {
+                if entry.created_at < previous.created_at {
+                    issue(
+                        version,
+                        IssueKind::Timestamp,
+                        format!("created before version {}", previous.version),
+                    );
+                }
+            }
+
+            if entry.shredded {
+                continue;
+            }
+            match crypto.open(name, entry) {
+                Ok(_) => {}
+                Err(VaultError::UnknownKeyId(key_id)) => issue(
+                    version,
+                    IssueKind::MissingKey,
+                    format!("key {} is not loaded", key_id),
+                ),
+                Err(e) => issue(version, IssueKind::Undecryptable, e.to_string()),
+            }
+        }
+
+        report.entries += entries.len();
+        report.shredded += entries.iter().filter(|entry| entry.shredded).count();
+    }
+
+    fn add_to_quarantine(&self, moved: &BTreeMap<String, Vec<SecretEntry>>) -> Result<()> {
+        let mut quarantine: BTreeMap<String, Vec<SecretEntry>> =
+            match self.storage.load_sidecar(QUARANTINE)? {
+                Some(bytes) => serde_yaml::from_slice(&bytes)?,
+                None => BTreeMap::new(),
+            };
+        for (name, entries) in moved {
+            let kept = quarantine.entry(name.clone()).or_default();
+            for entry in entries {
+                // A retried save quarantines the same entries again.
+                if !kept
+                    .iter()
+                    .any(|e| e.encrypted_value == entry.encrypted_value)
+                {
+                    kept.push(entry.clone());
+                }
+            }
+        }
+        let yaml = serde_yaml::to_string(&quarantine)?;
+        self.storage
+            .store_sidecar(QUARANTINE, Some(yaml.as_bytes()))
+    }
+}
+
+/// Whether `entry` fails to decrypt for a reason other than a missing key.
+fn is_undecryptable(crypto: &super::EntryCrypto<'_>, name: &str, entry: &SecretEntry) -> bool {
+    !matches!(
+        crypto.open(name, entry),
+        Ok(_) | Err(VaultError::UnknownKeyId(_))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, VaultStorage};
+    use crate::vault::KeySource;
+
+    fn open(storage: &MemoryStorage) -> SecretVault {
+        SecretVault::builder()
+            .master_key(KeySource::Bytes(vec![1u8; 32]))
+            .storage(storage.clone())
+            .build()
+            .unwrap()
+    }
+
+    /// Edits the stored vault behind the vault's back.
+    fn tamper(storage: &MemoryStorage, edit: impl FnOnce(&mut BTreeMap<String, Vec<SecretEntry>>)) {
+        let mut data = storage.load().unwrap().unwrap();
+        edit(&mut data.secrets);
+        storage.save(&mut data).unwrap();
+    }
+
+    #[test]
+    fn test_verify_healthy_vault() {
+        let storage = MemoryStorage::new();
+        let mut vault = open(&storage);
+        vault.set("a", b"1").unwrap();
+        vault.set("a", b"2").unwrap();
+        vault.set("b", b"3").unwrap();
+        vault.shred_version("a", 1).unwrap();
+
+        let report = vault.verify().unwrap();
+        assert!(report.is_healthy(), "{:?}", report.issues);
+        assert_eq!((report.secrets, report.entries, report.shredded), (2, 3, 1));
+    }
+
+    #[test]
+    fn test_verify_reports_issues() {
+        let storage = MemoryStorage::new();
+        let mut vault = open(&storage);
+        vault.set("a", b"1").unwrap();
+        vault.set("a", b"2").unwrap();
+        vault.set("b", b"3").unwrap();
+        vault.set("c", b"4").unwrap();
+        tamper(&storage, |secrets| {
+            let a = secrets.get_mut("a").unwrap();
+            a[0].encrypted_value[0] ^= 1;
+            a[1].created_at = Utc::now() + Duration::days(1);
+            let b = secrets.get_mut("b").unwrap();
+            b.push(b[0].clone());
+            secrets.get_mut("c").unwrap().clear();
+        });
+
+        let report = open(&storage).verify().unwrap();
+        let found: Vec<_> = report
+            .issues
+            .iter()
+            .map(|issue| (issue.secret.as_str(), issue.version, issue.kind))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("a", Some(1), IssueKind::Undecryptable),
+                ("a", Some(2), IssueKind::Timestamp),
+                ("b", Some(1), IssueKind::DuplicateVersion),
+                ("c", None, IssueKind::EmptyHistory),
+            ]
+        );
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["issues"][0]["kind"], "undecryptable");
+    }
+
+    #[test]
+    fn test_repair_quarantines_undecryptable_entries() {
+        let storage = MemoryStorage::new();
+        let mut vault = open(&storage);
+        vault.set("a", b"1").unwrap();
+        vault.set("a", b"2").unwrap();
+        vault.set("b", b"3").unwrap();
+        tamper(&storage, |secrets| {
+            secrets.get_mut("a").unwrap()[1].encrypted_value[0] ^= 1;
+            secrets.get_mut("b").unwrap()[0].encrypted_value[0] ^= 1;
+        });
+
+        let mut vault = open(&storage);
+        let report = vault.repair().unwrap();
+        assert!(report.is_healthy(), "{:?}", report.issues);
+        assert_eq!(report.quarantined, 2);
+
+        let reopened = open(&storage);
+        assert_eq!(reopened.list_keys(), vec!["a".to_string()]);
+        assert_eq!(reopened.get("a").unwrap().unwrap(), b"1");
+        let quarantine: BTreeMap<String, Vec<SecretEntry>> =
+            serde_yaml::from_slice(&storage.load_sidecar(QUARANTINE).unwrap().unwrap()).unwrap();
+        assert_eq!(quarantine["a"][0].version, 2);
+        assert_eq!(quarantine["b"].len(), 1);
+    }
+
+    #[test]
+    fn test_verify_checks_deleted_secrets() {
+        let storage = MemoryStorage::new();
+        let mut vault = open(&storage);
+        vault.set("a", b"1").unwrap();
+        vault.set("b", b"2").unwrap();
+        vault.delete("b").unwrap();
+        tamper(&storage, |secrets| {
+            secrets.get_mut("b").unwrap()[0].encrypted_value[0] ^= 1;
+        });
+
+        let mut vault = open(&storage);
+        let report = vault.verify().unwrap();
+        assert_eq!(report.secrets, 2);
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!(
+            (report.issues[0].secret.as_str(), report.issues[0].kind),
+            ("b", IssueKind::Undecryptable)
+        );
+
+        let report = vault.repair().unwrap();
+        assert!(report.is_healthy(), "{:?}", report.issues);
+        assert_eq!(report.quarantined, 1);
+        assert!(open(&storage).deleted_keys().is_empty());
+    }
+
+    #[test]
+    fn test_repair_refuses_when_nothing_decrypts() {
+        let storage = MemoryStorage::new();
+        let mut vault = open(&storage);
+        vault.set("a", b"1").unwrap();
+        tamper(&storage, |secrets| {
+            secrets.get_mut("a").unwrap()[0].encrypted_value[0] ^= 1;
+        });
+
+        let mut vault = open(&storage);
+        assert!(matches!(
+            vault.repair(),
+            Err(VaultError::DecryptionFailed(_))
+        ));
+        assert_eq!(open(&storage).list_keys(), vec!["a".to_string()]);
+    }
+}
@@ -1,4 +1,4 @@
-use assert_cmd::Command;
+use assert_cmd::cargo::cargo_bin_cmd;
 use predicates::prelude::*;
 use std::fs;
 use tempfile::TempDir;
@@ -10,7 +10,7 @@ fn test_cli_flow() -> anyhow::Result<()> {
     let key_path = temp_dir.path().join("master.key");
     let new_key_path = temp_dir.path().join("new_master.key");
 
-    let mut cmd = Command::cargo_bin("vault")?;
+    let mut cmd = cargo_bin_cmd!("vault");
 
     // 1. Init
     cmd.arg("init")
@@ -25,7 +25,7 @@ fn test_cli_flow() -> anyhow::Result<()> {
     assert!(key_path.exists());
 
     // 2. Set Secret
-    let mut cmd = Command::cargo_bin("vault")?;
+    let mut cmd = cargo_bin_cmd!("vault");
     cmd.arg("set")
         .arg("my_secret")
         .arg("secret_value")
@@ -37,7 +37,7 @@ fn test_cli_flow() -> anyhow::Result<()> {
         .success();
 
     // 3. Get Secret
-    let mut cmd = Command::cargo_bin("vault")?;
+    let mut cmd = cargo_bin_cmd!("vault");
     cmd.arg("get")
         .arg("my_secret")
         .arg("--vault-path")
@@ -49,7 +49,7 @@ fn test_cli_flow() -> anyhow::Result<()> {
         .stdout(predicate::str::contains("secret_value"));
 
     // 4. Rotate Key
-    let mut cmd = Command::cargo_bin("vault")?;
+    let mut cmd = cargo_bin_cmd!("vault");
     cmd.arg("rotate")
         .arg("--vault-path")
         .arg(&vault_path)
@@ -63,7 +63,7 @@ fn test_cli_flow() -> anyhow::Result<()> {
     assert!(new_key_path.exists());
 
     // 5. Get Secret with New Key
-    let mut cmd = Command::cargo_bin("vault")?;
+    let mut cmd = cargo_bin_cmd!("vault");
     cmd.arg("get")
         .arg("my_secret")
         .arg("--vault-path")
@@ -76,3 +76,1185 @@ fn test_cli_flow() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_cli_init_with_cipher() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+    let key_path = temp_dir.path().join("master.key");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--cipher")
+        .arg("xchacha20-poly1305")
+        .assert()
+        .success();
+
+    let contents = fs::read_to_string(&vault_path)?;
+    assert!(contents.contains("cipher: xchacha20-poly1305"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("my_secret")
+        .arg("secret_value")
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .arg("--key-path")
+        .arg(&key_path)
+        .assert()
+        .success();
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("get")
+        .arg("my_secret")
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .arg("--key-path")
+        .arg(&key_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("secret_value"));
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_passphrase_vault() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.env("VAULT_PASSPHRASE", "correct horse battery staple")
+        .arg("init")
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .arg("--passphrase-env")
+        .arg("VAULT_PASSPHRASE")
+        .arg("--kdf-memory-kib")
+        .arg("64")
+        .arg("--kdf-iterations")
+        .arg("1")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Master Key").not());
+
+    let contents = fs::read_to_string(&vault_path)?;
+    assert!(contents.contains("algorithm: argon2id"));
+    assert!(contents.contains("memory_kib: 64"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.env("VAULT_PASSPHRASE", "correct horse battery staple")
+        .arg("set")
+        .arg("my_secret")
+        .arg("secret_value")
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .arg("--passphrase-env")
+        .arg("VAULT_PASSPHRASE")
+        .assert()
+        .success();
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.env("VAULT_PASSPHRASE", "correct horse battery staple")
+        .arg("get")
+        .arg("my_secret")
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .arg("--passphrase-env")
+        .arg("VAULT_PASSPHRASE")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("secret_value"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.env("VAULT_PASSPHRASE", "wrong")
+        .arg("get")
+        .arg("my_secret")
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .arg("--passphrase-env")
+        .arg("VAULT_PASSPHRASE")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Incorrect passphrase"));
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_keyring_rotation() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+    let old_key = temp_dir.path().join("old.key");
+    let new_key = temp_dir.path().join("new.key");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&old_key)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("my_secret")
+        .arg("secret_value")
+        .arg("--key-path")
+        .arg(&old_key)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+
+    // The keyring is whatever keys each run is given: the new key becomes
+    // active by being passed as --key-path.
+    fs::write(&new_key, "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=")?;
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("other_secret")
+        .arg("other_value")
+        .arg("--key-path")
+        .arg(&new_key)
+        .arg("--extra-key-path")
+        .arg(&old_key)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+
+    // Old entries still need the old key until they are rewrapped.
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("get")
+        .arg("my_secret")
+        .arg("--key-path")
+        .arg(&new_key)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .failure();
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("key")
+        .arg("usage")
+        .arg("--key-path")
+        .arg(&new_key)
+        .arg("--extra-key-path")
+        .arg(&old_key)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("(active): 1 entries"))
+        .stdout(predicate::str::contains(": 1 entries"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("key")
+        .arg("rewrap")
+        .arg("--key-path")
+        .arg(&new_key)
+        .arg("--extra-key-path")
+        .arg(&old_key)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Rewrapped 1 entries"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("get")
+        .arg("my_secret")
+        .arg("--key-path")
+        .arg(&new_key)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("secret_value"));
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_rotate_dry_run() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+    let key_path = temp_dir.path().join("master.key");
+    let new_key = temp_dir.path().join("new.key");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("my_secret")
+        .arg("secret_value")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    let before = fs::read(&vault_path)?;
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("rotate")
+        .arg("--dry-run")
+        .arg("--new-key-out")
+        .arg(&new_key)
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Would rewrap 1 data keys"));
+    assert_eq!(fs::read(&vault_path)?, before);
+    assert!(!new_key.exists());
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("rotate")
+        .arg("--new-key-out")
+        .arg(&new_key)
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("get")
+        .arg("my_secret")
+        .arg("--key-path")
+        .arg(&new_key)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("secret_value"));
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_parallel_sets() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+    let key_path = temp_dir.path().join("master.key");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+
+    let children: Vec<_> = (0..8)
+        .map(|i| {
+            let mut cmd = cargo_bin_cmd!("vault");
+            cmd.arg("set")
+                .arg(format!("secret{}", i))
+                .arg("value")
+                .arg("--key-path")
+                .arg(&key_path)
+                .arg("--vault-path")
+                .arg(&vault_path)
+                .arg("--retries")
+                .arg("20");
+            std::thread::spawn(move || cmd.assert().success())
+        })
+        .collect();
+    for child in children {
+        child.join().unwrap();
+    }
+
+    for i in 0..8 {
+        let mut cmd = cargo_bin_cmd!("vault");
+        cmd.arg("get")
+            .arg(format!("secret{}", i))
+            .arg("--key-path")
+            .arg(&key_path)
+            .arg("--vault-path")
+            .arg(&vault_path)
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("value"));
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_cli_migrate_to_sqlite() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+    let db_path = temp_dir.path().join("vault.db");
+    let key_path = temp_dir.path().join("master.key");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("my_secret")
+        .arg("secret_value")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("migrate")
+        .arg("--to")
+        .arg("sqlite")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    assert!(db_path.exists());
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("get")
+        .arg("my_secret")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&db_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("secret_value"));
+
+    // The copy is never written over.
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("migrate")
+        .arg("--to")
+        .arg("sqlite")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_binary_vault_converts_to_yaml() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.rmsv");
+    let yaml_path = temp_dir.path().join("vault.yaml");
+    let key_path = temp_dir.path().join("master.key");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--format")
+        .arg("binary")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("my_secret")
+        .arg("secret_value")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    assert!(fs::read(&vault_path)?.starts_with(b"RMSVCBOR"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("migrate")
+        .arg("--to")
+        .arg("yaml")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("get")
+        .arg("my_secret")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&yaml_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("secret_value"));
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_log_vault_compact() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.rmsl");
+    let key_path = temp_dir.path().join("master.key");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--format")
+        .arg("log")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    for value in ["first", "second", "third"] {
+        let mut cmd = cargo_bin_cmd!("vault");
+        cmd.arg("set")
+            .arg("my_secret")
+            .arg(value)
+            .arg("--key-path")
+            .arg(&key_path)
+            .arg("--vault-path")
+            .arg(&vault_path)
+            .assert()
+            .success();
+    }
+    assert!(fs::read(&vault_path)?.starts_with(b"RMSVLOG1"));
+    let before = fs::metadata(&vault_path)?.len();
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("compact")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Compacted"));
+    assert!(fs::metadata(&vault_path)?.len() < before);
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("get")
+        .arg("my_secret")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("third"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("compact")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Nothing to compact"));
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_upgrade() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+    let key_path = temp_dir.path().join("master.key");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+
+    // Drop the version field, as files from older releases lack it.
+    let yaml = fs::read_to_string(&vault_path)?;
+    assert!(yaml.contains("format_version: 3\n"));
+    fs::write(&vault_path, yaml.replace("format_version: 3\n", ""))?;
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("upgrade")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("from format version 0 to 3"));
+    assert!(fs::read_to_string(&vault_path)?.contains("format_version: 3\n"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("upgrade")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("already at format version 3"));
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_merge_driver() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let base = temp_dir.path().join("base.yaml");
+    let ours = temp_dir.path().join("ours.yaml");
+    let theirs = temp_dir.path().join("theirs.yaml");
+    let key_path = temp_dir.path().join("master.key");
+
+    let vault = |path: &std::path::Path, args: &[&str]| {
+        let mut cmd = cargo_bin_cmd!("vault");
+        cmd.args(args)
+            .arg("--key-path")
+            .arg(&key_path)
+            .arg("--vault-path")
+            .arg(path)
+            .assert()
+    };
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&base)
+        .assert()
+        .success();
+    vault(&base, &["set", "shared", "base"]).success();
+    fs::copy(&base, &ours)?;
+    fs::copy(&base, &theirs)?;
+    vault(&ours, &["set", "ours_only", "a"]).success();
+    vault(&theirs, &["set", "theirs_only", "b"]).success();
+
+    // New secrets on both sides merge without the key.
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("merge")
+        .arg(&base)
+        .arg(&ours)
+        .arg(&theirs)
+        .assert()
+        .success();
+    vault(&ours, &["get", "theirs_only"])
+        .success()
+        .stdout(predicate::str::contains("b"));
+    vault(&ours, &["get", "ours_only"])
+        .success()
+        .stdout(predicate::str::contains("a"));
+
+    // A version added on both sides must be renumbered, which needs the key.
+    fs::copy(&ours, &base)?;
+    fs::copy(&ours, &theirs)?;
+    vault(&ours, &["set", "shared", "ours"]).success();
+    vault(&theirs, &["set", "shared", "theirs"]).success();
+    let before = fs::read(&ours)?;
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("merge")
+        .arg(&base)
+        .arg(&ours)
+        .arg(&theirs)
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("shared v2"));
+    assert_eq!(fs::read(&ours)?, before);
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("merge")
+        .arg(&base)
+        .arg(&ours)
+        .arg(&theirs)
+        .arg("--key-path")
+        .arg(&key_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Renumbered their shared v2 to v3"));
+    vault(&ours, &["get", "shared"])
+        .success()
+        .stdout(predicate::str::contains("theirs"));
+    vault(&ours, &["list-versions", "shared"])
+        .success()
+        .stdout(predicate::str::contains("[1, 2, 3]"));
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_read_only() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+    let key_path = temp_dir.path().join("master.key");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("my_secret")
+        .arg("value")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("get")
+        .arg("my_secret")
+        .arg("--read-only")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("value"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("my_secret")
+        .arg("other")
+        .arg("--read-only")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("ReadOnly"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("get")
+        .arg("my_secret")
+        .arg("--read-only")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(temp_dir.path().join("missing.yaml"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("VaultNotFound"));
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_init_refuses_to_overwrite() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+    let key_path = temp_dir.path().join("master.key");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    let key = fs::read(&key_path)?;
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("VaultExists"));
+    assert_eq!(fs::read(&key_path)?, key);
+
+    // A key file left behind is not replaced either.
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(temp_dir.path().join("other.yaml"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("already exists"));
+    assert_eq!(fs::read(&key_path)?, key);
+
+    // Other commands do not start a vault at a mistyped path.
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("my_secret")
+        .arg("value")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(temp_dir.path().join("vualt.yaml"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("VaultNotFound"));
+    assert!(!temp_dir.path().join("vualt.yaml").exists());
+
+    // The key file is only replaced once the new vault exists.
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--force")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(temp_dir.path().join("missing").join("vault.yaml"))
+        .assert()
+        .failure();
+    assert_eq!(fs::read(&key_path)?, key);
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--force")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    assert_ne!(fs::read(&key_path)?, key);
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_fsck_and_repair() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+    let key_path = temp_dir.path().join("master.key");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    for key in ["first", "second"] {
+        let mut cmd = cargo_bin_cmd!("vault");
+        cmd.arg("set")
+            .arg(key)
+            .arg("value")
+            .arg("--key-path")
+            .arg(&key_path)
+            .arg("--vault-path")
+            .arg(&vault_path)
+            .assert()
+            .success();
+    }
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("fsck")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"issues\": []"));
+
+    // Damage the ciphertext of the first secret.
+    let yaml = fs::read_to_string(&vault_path)?;
+    let start = yaml.find("encrypted_value: ").unwrap() + "encrypted_value: ".len() + 30;
+    let flipped = if &yaml[start..start + 1] == "A" {
+        "B"
+    } else {
+        "A"
+    };
+    fs::write(
+        &vault_path,
+        format!("{}{}{}", &yaml[..start], flipped, &yaml[start + 1..]),
+    )?;
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("fsck")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("\"kind\": \"undecryptable\""));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("fsck")
+        .arg("--repair")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"quarantined\": 1"));
+    assert!(fs::read_to_string(temp_dir.path().join("vault.yaml.quarantine"))?.contains("first"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("get")
+        .arg("second")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("value"));
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_pinned_vault_detects_rollback() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+    let key_path = temp_dir.path().join("master.key");
+    let pin_path = temp_dir.path().join("vault.state");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .arg("--pin-path")
+        .arg(&pin_path)
+        .assert()
+        .success();
+    assert!(fs::read_to_string(&vault_path)?.contains("manifest:"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("api_key")
+        .arg("revoked")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .arg("--pin-path")
+        .arg(&pin_path)
+        .assert()
+        .success();
+    let old_copy = fs::read(&vault_path)?;
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("api_key")
+        .arg("current")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .arg("--pin-path")
+        .arg(&pin_path)
+        .assert()
+        .success();
+
+    fs::write(&vault_path, old_copy)?;
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("get")
+        .arg("api_key")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .arg("--pin-path")
+        .arg(&pin_path)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("RollbackDetected"));
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_sign_manifest() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+    let key_path = temp_dir.path().join("master.key");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+
+    // A vault without a manifest is refused where one is required.
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("api_key")
+        .arg("value")
+        .arg("--manifest")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("ManifestMismatch"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("sign-manifest")
+        .arg("--manifest")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    assert!(fs::read_to_string(&vault_path)?.contains("manifest:"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("api_key")
+        .arg("value")
+        .arg("--manifest")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_sealed_vault_hides_names() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.rmse");
+    let key_path = temp_dir.path().join("master.key");
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--format")
+        .arg("sealed")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("set")
+        .arg("payment_gateway_token")
+        .arg("value")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+
+    let bytes = fs::read(&vault_path)?;
+    assert!(!String::from_utf8_lossy(&bytes).contains("payment_gateway_token"));
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("list-versions")
+        .arg("payment_gateway_token")
+        .arg("--key-path")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1"));
+
+    let other_key_path = temp_dir.path().join("other.key");
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&other_key_path)
+        .arg("--vault-path")
+        .arg(temp_dir.path().join("other.yaml"))
+        .assert()
+        .success();
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("get")
+        .arg("payment_gateway_token")
+        .arg("--key-path")
+        .arg(&other_key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("UnknownKeyId"));
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_retention_and_prune() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+    let key_path = temp_dir.path().join("master.key");
+    let audit_path = temp_dir.path().join("audit.log");
+    let vault = |args: &[&str]| {
+        let mut cmd = cargo_bin_cmd!("vault");
+        cmd.args(args)
+            .arg("--key-path")
+            .arg(&key_path)
+            .arg("--vault-path")
+            .arg(&vault_path)
+            .arg("--audit-path")
+            .arg(&audit_path)
+            .assert()
+    };
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    for value in ["1", "2", "3", "4"] {
+        vault(&["set", "api_key", value]).success();
+    }
+
+    vault(&["retention"])
+        .success()
+        .stdout(predicate::str::contains("No retention policy"));
+    vault(&["retention", "--key", "api_key", "--keep-last", "2"]).success();
+    vault(&["retention", "--key", "api_key"])
+        .success()
+        .stdout(predicate::str::contains("keep the last 2 versions"));
+    vault(&["retention", "--max-age", "90x"]).failure();
+
+    vault(&["prune"])
+        .success()
+        .stdout(predicate::str::contains("Pruned 2 version(s)"));
+    vault(&["list-versions", "api_key"])
+        .success()
+        .stdout(predicate::str::contains("[3, 4]"));
+    vault(&["set", "api_key", "5"]).success();
+    vault(&["list-versions", "api_key"])
+        .success()
+        .stdout(predicate::str::contains("[4, 5]"));
+
+    let audit = fs::read_to_string(&audit_path)?;
+    for pruned in ["api_key@1", "api_key@2", "api_key@3"] {
+        assert!(audit.contains(&format!("\"Prune\",\"key\":\"{}\"", pruned)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cli_undelete_and_purge() -> anyhow::Result<()> {
+    let temp_dir = TempDir::new()?;
+    let vault_path = temp_dir.path().join("vault.yaml");
+    let key_path = temp_dir.path().join("master.key");
+    let audit_path = temp_dir.path().join("audit.log");
+    let vault = |args: &[&str]| {
+        let mut cmd = cargo_bin_cmd!("vault");
+        cmd.args(args)
+            .arg("--key-path")
+            .arg(&key_path)
+            .arg("--vault-path")
+            .arg(&vault_path)
+            .arg("--audit-path")
+            .arg(&audit_path)
+            .assert()
+    };
+
+    let mut cmd = cargo_bin_cmd!("vault");
+    cmd.arg("init")
+        .arg("--key-out")
+        .arg(&key_path)
+        .arg("--vault-path")
+        .arg(&vault_path)
+        .assert()
+        .success();
+    vault(&["set", "api_key", "secret"]).success();
+    vault(&["set", "db_password", "hunter2"]).success();
+
+    vault(&["delete", "api_key"])
+        .success()
+        .stdout(predicate::str::contains("vault undelete api_key"));
+    vault(&["get", "api_key"]).failure();
+    vault(&["list-versions", "api_key"])
+        .success()
+        .stdout(predicate::str::contains("No versions found"));
+    vault(&["deleted"])
+        .success()
+        .stdout(predicate::str::contains("api_key (deleted "));
+
+    vault(&["undelete", "api_key"])
+        .success()
+        .stdout(predicate::str::contains("restored"));
+    vault(&["get", "api_key"])
+        .success()
+        .stdout(predicate::str::contains("secret"));
+    vault(&["undelete", "api_key"]).failure();
+
+    // Purging only removes deleted secrets.
+    vault(&["purge", "api_key"]).failure();
+    vault(&["delete", "api_key"]).success();
+    vault(&["purge", "api_key"])
+        .success()
+        .stdout(predicate::str::contains("purged"));
+    vault(&["undelete", "api_key"]).failure();
+
+    vault(&["delete", "db_password"]).success();
+    vault(&["purge", "--expired"])
+        .success()
+        .stdout(predicate::str::contains("Purged 0 deleted secret(s)"));
+    vault(&["grace-period"])
+        .success()
+        .stdout(predicate::str::contains("kept for 30d"));
+    // The new period is the vault's, and its save purges what has expired.
+    vault(&["grace-period", "0s"])
+        .success()
+        .stdout(predicate::str::contains("now kept for 0s"));
+    vault(&["grace-period"])
+        .success()
+        .stdout(predicate::str::contains("kept for 0s"));
+    vault(&["deleted"])
+        .success()
+        .stdout(predicate::str::contains("No deleted secrets"));
+
+    let audit = fs::read_to_string(&audit_path)?;
+    assert!(audit.contains("\"Undelete\",\"key\":\"api_key\""));
+    assert!(audit.contains("\"Purge\",\"key\":\"api_key\""));
+    assert!(audit.contains("\"Purge\",\"key\":\"db_password\""));
+
+    Ok(())
+}